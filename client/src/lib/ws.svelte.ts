@@ -2,16 +2,41 @@ import { env } from '$env/dynamic/public';
 import { roland_state, send_local_settings } from "../routes/controller/controller.svelte";
 import { append_log, LogLevel } from "./logs.svelte";
 
-export let roland = $state({ ip: env.PUBLIC_ROLAND_IP, connection: "disconnected" });
+export let roland = $state({ ip: env.PUBLIC_ROLAND_IP, connection: "disconnected", link: "Connected" as LinkState, firmware: null as Firmware | null });
 
 type BuzzerCommand = {
     Buzzer: number;
 };
 
+type MelodyCommand = {
+    Melody: string;
+};
+
+export type Jingle = "startup" | "connected" | "disconnected" | "done" | "warning" | "error";
+
+type JingleCommand = {
+    Jingle: Jingle;
+};
+
 type LEDCommand = {
     LED: [number, number, number];
 };
 
+type Color = [number, number, number];
+
+export type LedEffect =
+    | { Solid: Color }
+    | { Blink: { color: Color; period: number } }
+    | { Breathe: { color: Color; period: number } }
+    | { Rainbow: { period: number; brightness: number } }
+    | { Fade: { color: Color; duration: number } };
+
+type LedEffectCommand = {
+    LedEffect: LedEffect;
+};
+
+type StopLedEffectCommand = "StopLedEffect";
+
 type ServoCommand = {
     Servo: number;
 };
@@ -26,21 +51,55 @@ type StateCommand = {
 
 export type ControlState = "ManualControl" | "FollowLine" | "KeepDistance";
 
-export type Command = BuzzerCommand | MotorCommand | ServoCommand | LEDCommand | StateCommand;
+type EmergencyStopCommand = "EmergencyStop";
+
+export type Command = BuzzerCommand | MelodyCommand | JingleCommand | MotorCommand | ServoCommand | LEDCommand | LedEffectCommand | StopLedEffectCommand | StateCommand | EmergencyStopCommand;
 
 type TextMessage = {
     Text: String,
 };
 
+export type UltraError = "NoEcho" | "Timeout" | "OutOfRange";
+
 type UltraSensorMessage = {
-    Ultra: number | null;
+    Ultra: {
+        distance: number | null;
+        raw: number | null;
+        error: UltraError | null;
+    };
 };
 
 type TrackSensorMessage = {
     Track: [boolean, boolean, boolean, boolean];
 };
 
-export type ServerMessage = TextMessage | UltraSensorMessage | TrackSensorMessage;
+export type LinkState = "Connected" | "Reconnecting" | "Lost";
+
+type LinkMessage = {
+    Link: LinkState;
+};
+
+export type Firmware = {
+    protocol: number;
+    capabilities: string[];
+};
+
+type FirmwareMessage = {
+    Firmware: Firmware | null;
+};
+
+export type FirmwareFault = {
+    kind: "Panic" | "HardFault";
+    message: string;
+    location: string | null;
+    uptime: number;
+};
+
+type FaultMessage = {
+    Fault: FirmwareFault;
+};
+
+export type ServerMessage = TextMessage | UltraSensorMessage | TrackSensorMessage | LinkMessage | FirmwareMessage | FaultMessage;
 
 let ws: WebSocket | null = null;
 
@@ -85,11 +144,23 @@ export const ws_handle_message = (msg: ServerMessage) => {
     if ("Text" in msg) {
         append_log(LogLevel.Info, `Received: ${msg.Text}`);
     } else if ("Ultra" in msg) {
-        if (msg.Ultra !== null) {
-            roland_state.ultra_sensor = msg.Ultra;
+        if (msg.Ultra.distance !== null) {
+            roland_state.ultra_sensor = Math.round(msg.Ultra.distance);
         }
     } else if ("Track" in msg) {
         roland_state.track_sensor = msg.Track;
+    } else if ("Link" in msg) {
+        roland.link = msg.Link;
+        append_log(msg.Link === "Connected" ? LogLevel.Info : LogLevel.Warn, `Pico link: ${msg.Link}`);
+    } else if ("Firmware" in msg) {
+        roland.firmware = msg.Firmware;
+        if (msg.Firmware !== null) {
+            append_log(LogLevel.Info, `Pico firmware: protocol v${msg.Firmware.protocol} [${msg.Firmware.capabilities.join(", ")}]`);
+        }
+    } else if ("Fault" in msg) {
+        const { kind, message, location, uptime } = msg.Fault;
+        const at = location !== null ? ` at ${location}` : "";
+        append_log(LogLevel.Error, `Pico firmware crashed (${kind}${at}, ${uptime.toFixed(1)}s after booting): ${message}`);
     } else {
         const _exhaustive: never = msg;
         append_log(LogLevel.Error, `Unknown message type: ${_exhaustive}`);
@@ -3,8 +3,12 @@ MEMORY {
      * The RP2350 has either external or internal flash.
      *
      * 2 MiB is a safe default here, although a Pico 2 has 4 MiB.
+     *
+     * The last 4K sector of it is reserved for the calibration, see
+     * `src/storage.rs`.
      */
-    FLASH : ORIGIN = 0x10000000, LENGTH = 2048K
+    FLASH : ORIGIN = 0x10000000, LENGTH = 2048K - 4K
+    CALIBRATION : ORIGIN = 0x10000000 + 2048K - 4K, LENGTH = 4K
     /*
      * RAM consists of 8 banks, SRAM0-SRAM7, with a striped mapping.
      * This is usually good for performance, as it distributes load on
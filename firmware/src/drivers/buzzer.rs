@@ -1,24 +1,128 @@
+use embassy_executor::Spawner;
 use embassy_rp::pwm::Pwm;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_deadline, Duration, Instant};
+use heapless::Vec;
+use roland_protocol::{MelodyChunk, Note, Rejection, MELODY_CAPACITY};
+use static_cell::StaticCell;
 
-use crate::drivers::pwm::PWM;
+use crate::drivers::{pwm::PWM, Shared, SharedCell};
+
+/// wakes the melody task when notes are queued
+static QUEUED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 pub struct Buzzer<'a> {
-    pub pwm: PWM<'a>,
+    pwm: PWM<'a>,
+    /// id of the melody being played, if there's one
+    melody: Option<u8>,
+    notes: Vec<Note, MELODY_CAPACITY>,
+    /// index of the next note to play
+    next: usize,
+    /// when the note being played ends, `None` once the queue ran out
+    note_end: Option<Instant>,
 }
 
 impl<'a> Buzzer<'a> {
     /// uses the A channel of the PWM
     pub fn new(pwm: Pwm<'a>) -> Self {
-        Self { pwm: PWM::new(pwm) }
+        Self {
+            pwm: PWM::new(pwm),
+            melody: None,
+            notes: Vec::new(),
+            next: 0,
+            note_end: None,
+        }
     }
 
-    /// a frequency of 0 turns the duty cycle to 0
+    /// a frequency of 0 turns the duty cycle to 0, this also stops the melody
     pub fn freq(&mut self, freq: u16) {
-        if freq > 0 {
-            self.pwm.set_duty_a(0xffff / 2);
+        self.melody = None;
+        self.notes.clear();
+        self.note_end = None;
+        self.tone(freq, u8::MAX);
+    }
+
+    /// add the notes of a (valid) chunk to the queue, or start a new melody with them
+    pub fn queue(&mut self, chunk: MelodyChunk) -> Result<(), Rejection> {
+        if self.melody != Some(chunk.id) {
+            if chunk.offset != 0 {
+                return Err(Rejection::OutOfRange);
+            }
+            self.freq(0);
+            self.melody = Some(chunk.id);
+            self.next = 0;
+        }
+
+        let offset = chunk.offset as usize;
+        let received = self.notes.len();
+        if offset > received {
+            // a chunk got lost in between
+            return Err(Rejection::OutOfRange);
+        }
+        // the part that was queued already is a retransmission
+        let new = chunk.notes().get(received - offset..).unwrap_or_default();
+        self.notes
+            .extend_from_slice(new)
+            .map_err(|_| Rejection::OutOfRange)?;
+
+        QUEUED.signal(());
+        Ok(())
+    }
+
+    /// start the next note if the current one is over, returns when to check again
+    fn step(&mut self, now: Instant) -> Option<Instant> {
+        self.melody?;
+        if let Some(end) = self.note_end {
+            if now < end {
+                return Some(end);
+            }
+        }
+
+        let Some(note) = self.notes.get(self.next).copied() else {
+            // wait for more notes
+            self.note_end = None;
+            self.tone(0, 0);
+            return None;
+        };
+        self.next += 1;
+        self.tone(note.freq, note.volume);
+        // back to back with the previous note, so late wakeups don't add up
+        let start = self.note_end.unwrap_or(now);
+        let end = start + Duration::from_millis(note.duration as u64);
+        self.note_end = Some(end);
+        Some(end)
+    }
+
+    fn tone(&mut self, freq: u16, volume: u8) {
+        if freq > 0 && volume > 0 {
             self.pwm.set_freq(freq);
+            // a square wave is the loudest
+            self.pwm
+                .set_duty_a((0xffff / 2 * volume as u32 / u8::MAX as u32) as u16);
         } else {
             self.pwm.set_duty_a(0);
         }
     }
 }
+
+impl Buzzer<'static> {
+    /// hand the buzzer over to a task playing its melodies
+    pub fn share(self, spawner: Spawner) -> Shared<Self> {
+        static BUZZER: StaticCell<SharedCell<Buzzer<'static>>> = StaticCell::new();
+        let buzzer = Shared::new(&BUZZER, self);
+        spawner.spawn(melody_task(buzzer)).unwrap();
+        buzzer
+    }
+}
+
+#[embassy_executor::task]
+async fn melody_task(buzzer: Shared<Buzzer<'static>>) {
+    loop {
+        match buzzer.with(|b| b.step(Instant::now())) {
+            Some(at) => {
+                let _ = with_deadline(at, QUEUED.wait()).await;
+            }
+            None => QUEUED.wait().await,
+        }
+    }
+}
@@ -1,10 +1,25 @@
+use embassy_executor::Spawner;
 use embassy_rp::{
-    gpio::{Input, Level, Output, Pin, Pull},
+    gpio::{Level, Output, Pin},
     pwm::Pwm,
     Peri,
 };
+use embassy_time::{Duration, Instant, Ticker};
+use roland_protocol::{MotorRamp, StopMode};
+use static_cell::StaticCell;
 
-use crate::drivers::pwm::PWM;
+use crate::{
+    drivers::{pwm::PWM, Shared, SharedCell},
+    fault,
+};
+
+/// how often the ramp moves the speed towards the target
+const TICK: Duration = Duration::from_millis(10);
+/// full speed, in either direction
+const MAX_SPEED: i32 = 0xffff;
+/// how long an emergency stop brakes for, enough to stop from full speed, after that the
+/// configured stop mode takes over again
+const BRAKE_TIME: Duration = Duration::from_millis(500);
 
 pub struct HBridge<'a> {
     l1: Output<'a>,
@@ -12,6 +27,16 @@ pub struct HBridge<'a> {
     r1: Output<'a>,
     r2: Output<'a>,
     pwm: PWM<'a>,
+    /// flip the direction of each side
+    reversed: (bool, bool),
+    ramp: MotorRamp,
+    stop_mode: StopMode,
+    /// speed the ramp is heading to
+    target: (i32, i32),
+    /// speed the outputs are set to
+    current: (i32, i32),
+    /// braking after an emergency stop, until then or the next speed change
+    braking: Option<Instant>,
 }
 
 impl<'a> HBridge<'a> {
@@ -22,34 +47,156 @@ impl<'a> HBridge<'a> {
         r2: Peri<'a, impl Pin>,
         pwm: Pwm<'a>,
         pwm_freq: u16,
+        reversed: (bool, bool),
     ) -> Self {
+        // a crash mustn't leave the motors running
+        for pin in [l1.pin(), l2.pin(), r1.pin(), r2.pin()] {
+            fault::hold_low(pin);
+        }
+
         let mut s = Self {
             pwm: PWM::new(pwm),
             l1: Output::new(l1, Level::Low),
             l2: Output::new(l2, Level::Low),
             r1: Output::new(r1, Level::Low),
             r2: Output::new(r2, Level::Low),
+            reversed,
+            ramp: MotorRamp::DEFAULT,
+            stop_mode: StopMode::default(),
+            target: (0, 0),
+            current: (0, 0),
+            braking: None,
         };
 
-        s.pwm.set_freq(pwm_freq);
+        s.calibrate(pwm_freq, reversed);
         s
     }
 
-    /// the input speed must be between -0xffff and 0xffff
+    pub fn calibrate(&mut self, pwm_freq: u16, reversed: (bool, bool)) {
+        self.pwm.set_freq(pwm_freq);
+        self.reversed = reversed;
+        self.apply();
+    }
+
+    pub fn set_ramp(&mut self, ramp: MotorRamp) {
+        self.ramp = ramp;
+    }
+
+    pub fn set_stop_mode(&mut self, stop_mode: StopMode) {
+        self.stop_mode = stop_mode;
+        self.apply();
+    }
+
+    /// set the speed to ramp to, it must be between -0xffff and 0xffff
     pub fn drive(&mut self, l: i32, r: i32) {
-        let l = l.clamp(-0xffff, 0xffff);
-        let r = r.clamp(-0xffff, 0xffff);
-
-        self.pwm.set_duty_b(l.unsigned_abs() as u16);
-        self.pwm.set_duty_a(r.unsigned_abs() as u16);
-
-        self.l1
-            .set_level(if l > 0 { Level::High } else { Level::Low });
-        self.l2
-            .set_level(if l < 0 { Level::High } else { Level::Low });
-        self.r1
-            .set_level(if r > 0 { Level::High } else { Level::Low });
-        self.r2
-            .set_level(if r < 0 { Level::High } else { Level::Low });
+        self.target = (
+            l.clamp(-MAX_SPEED, MAX_SPEED),
+            r.clamp(-MAX_SPEED, MAX_SPEED),
+        );
+        self.braking = None;
+        // the first step is taken right away, so an instant ramp has no latency
+        self.current = self.next();
+        self.apply();
+    }
+
+    /// stop and brake both sides right away, bypassing the ramp
+    /// the brake is released after [`BRAKE_TIME`], so the bridge doesn't keep shorting the motors
+    pub fn emergency_stop(&mut self) {
+        self.target = (0, 0);
+        self.current = (0, 0);
+        self.braking = Some(Instant::now() + BRAKE_TIME);
+        self.apply();
+    }
+
+    /// move the speed one tick closer to the target
+    fn step(&mut self) {
+        if self.braking.is_some_and(|until| Instant::now() >= until) {
+            self.braking = None;
+            self.apply();
+        }
+
+        let next = self.next();
+        if next != self.current {
+            self.current = next;
+            self.apply();
+        }
+    }
+
+    fn next(&self) -> (i32, i32) {
+        let accel = step_size(self.ramp.accel);
+        let decel = step_size(self.ramp.decel);
+        (
+            ramp(self.current.0, self.target.0, accel, decel),
+            ramp(self.current.1, self.target.1, accel, decel),
+        )
+    }
+
+    /// set the outputs to the current speed
+    fn apply(&mut self) {
+        let (l, r) = self.current;
+        let l = if self.reversed.0 { -l } else { l };
+        let r = if self.reversed.1 { -r } else { r };
+        let brake = self.braking.is_some() || self.stop_mode == StopMode::Brake;
+
+        let l = side(l, brake, &mut self.l1, &mut self.l2);
+        let r = side(r, brake, &mut self.r1, &mut self.r2);
+        self.pwm.set_duty_b(l);
+        self.pwm.set_duty_a(r);
+    }
+}
+
+/// set the inputs of one side of the bridge, returns its duty cycle
+fn side(speed: i32, brake: bool, in1: &mut Output, in2: &mut Output) -> u16 {
+    if speed == 0 && brake {
+        // both inputs high with the side enabled shorts the motor
+        in1.set_high();
+        in2.set_high();
+        return 0xffff;
+    }
+    in1.set_level(if speed > 0 { Level::High } else { Level::Low });
+    in2.set_level(if speed < 0 { Level::High } else { Level::Low });
+    speed.unsigned_abs() as u16
+}
+
+/// largest speed change per tick, for a ramp of `ms` from standing still to full speed
+fn step_size(ms: u16) -> i32 {
+    if ms == 0 {
+        // from full speed in one direction to full speed in the other
+        return 2 * MAX_SPEED;
+    }
+    (MAX_SPEED as u64 * TICK.as_millis() / ms as u64).max(1) as i32
+}
+
+/// one tick of moving `current` towards `target`, stopping at 0 if the direction changes
+fn ramp(current: i32, target: i32, accel: i32, decel: i32) -> i32 {
+    let toward = if current.signum() * target.signum() < 0 {
+        0
+    } else {
+        target
+    };
+    let max_step = if toward.abs() > current.abs() {
+        accel
+    } else {
+        decel
+    };
+    current + (toward - current).clamp(-max_step, max_step)
+}
+
+impl HBridge<'static> {
+    /// hand the H-bridge over to a task ramping its speed
+    pub fn share(self, spawner: Spawner) -> Shared<Self> {
+        static HBRIDGE: StaticCell<SharedCell<HBridge<'static>>> = StaticCell::new();
+        let hb = Shared::new(&HBRIDGE, self);
+        spawner.spawn(ramp_task(hb)).unwrap();
+        hb
+    }
+}
+
+#[embassy_executor::task]
+async fn ramp_task(hb: Shared<HBridge<'static>>) {
+    let mut ticker = Ticker::every(TICK);
+    loop {
+        ticker.next().await;
+        hb.with(HBridge::step);
     }
 }
@@ -1,3 +1,8 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use static_cell::StaticCell;
+
 pub mod buzzer;
 pub mod h_bridge;
 pub mod pwm;
@@ -5,3 +10,26 @@ pub mod rgb_led;
 pub mod servo;
 pub mod track_sensor;
 pub mod ultra_sensor;
+
+pub type SharedCell<T> = Mutex<CriticalSectionRawMutex, RefCell<T>>;
+
+/// a driver used by both the hardware task and a background task of its own
+pub struct Shared<T: 'static>(&'static SharedCell<T>);
+
+impl<T> Shared<T> {
+    pub fn new(cell: &'static StaticCell<SharedCell<T>>, driver: T) -> Self {
+        Self(cell.init(Mutex::new(RefCell::new(driver))))
+    }
+
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.0.lock(|driver| f(&mut driver.borrow_mut()))
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Shared<T> {}
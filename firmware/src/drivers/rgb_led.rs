@@ -1,35 +1,193 @@
+use embassy_executor::Spawner;
 use embassy_rp::pwm::Pwm;
+use embassy_time::{Duration, Instant, Ticker};
+use roland_protocol::LedEffect;
+use static_cell::StaticCell;
 
-use crate::drivers::pwm::PWM;
+use crate::drivers::{pwm::PWM, Shared, SharedCell};
 
-/// common cathode RGB LED
+/// how often a running effect updates the color
+const TICK: Duration = Duration::from_millis(10);
+
+/// duty cycle of every intensity, so the perceived brightness changes evenly
+/// `(i / 255) ^ 2.2 * 0xffff`
+#[rustfmt::skip]
+const GAMMA: [u16; 256] = [
+    0, 0, 2, 4, 7, 11, 17, 24, 32, 42, 53, 65,
+    79, 94, 111, 129, 148, 169, 192, 216, 242, 270, 299, 330,
+    362, 396, 432, 469, 508, 549, 591, 635, 681, 729, 779, 830,
+    883, 938, 995, 1053, 1113, 1175, 1239, 1305, 1373, 1443, 1514, 1587,
+    1663, 1740, 1819, 1900, 1983, 2068, 2155, 2243, 2334, 2427, 2521, 2618,
+    2717, 2817, 2920, 3024, 3131, 3240, 3350, 3463, 3578, 3694, 3813, 3934,
+    4057, 4182, 4309, 4438, 4570, 4703, 4838, 4976, 5115, 5257, 5401, 5547,
+    5695, 5845, 5998, 6152, 6309, 6468, 6629, 6792, 6957, 7124, 7294, 7466,
+    7640, 7816, 7994, 8175, 8358, 8543, 8730, 8919, 9111, 9305, 9501, 9699,
+    9900, 10102, 10307, 10515, 10724, 10936, 11150, 11366, 11585, 11806, 12029, 12254,
+    12482, 12712, 12944, 13179, 13416, 13655, 13896, 14140, 14386, 14635, 14885, 15138,
+    15394, 15652, 15912, 16174, 16439, 16706, 16975, 17247, 17521, 17798, 18077, 18358,
+    18642, 18928, 19216, 19507, 19800, 20095, 20393, 20694, 20996, 21301, 21609, 21919,
+    22231, 22546, 22863, 23182, 23504, 23829, 24156, 24485, 24817, 25151, 25487, 25826,
+    26168, 26512, 26858, 27207, 27558, 27912, 28268, 28627, 28988, 29351, 29717, 30086,
+    30457, 30830, 31206, 31585, 31966, 32349, 32735, 33124, 33514, 33908, 34304, 34702,
+    35103, 35507, 35913, 36321, 36732, 37146, 37562, 37981, 38402, 38825, 39252, 39680,
+    40112, 40546, 40982, 41421, 41862, 42306, 42753, 43202, 43654, 44108, 44565, 45025,
+    45487, 45951, 46418, 46888, 47360, 47835, 48313, 48793, 49275, 49761, 50249, 50739,
+    51232, 51728, 52226, 52727, 53230, 53736, 54245, 54756, 55270, 55787, 56306, 56828,
+    57352, 57879, 58409, 58941, 59476, 60014, 60554, 61097, 61642, 62190, 62741, 63295,
+    63851, 64410, 64971, 65535,
+];
+
+type Color = (u8, u8, u8);
+
+/// common cathode RGB LED, or common anode if it's `inverted`
 pub struct RGBLed<'a> {
     pub rg_pwm: PWM<'a>,
     pub b_pwm: PWM<'a>,
+    inverted: bool,
+    /// the color being shown
+    color: Color,
+    /// the running effect, when it started and the color it started from
+    effect: Option<(LedEffect, Instant, Color)>,
 }
 
 impl<'a> RGBLed<'a> {
     /// the A channel is used for Blue
-    pub fn new(rg_pwm: Pwm<'a>, b_pwm: Pwm<'a>, pwm_freq: u16) -> Self {
+    pub fn new(rg_pwm: Pwm<'a>, b_pwm: Pwm<'a>, pwm_freq: u16, inverted: bool) -> Self {
         let mut s = Self {
             rg_pwm: PWM::new(rg_pwm),
             b_pwm: PWM::new(b_pwm),
+            inverted,
+            color: (0, 0, 0),
+            effect: None,
         };
 
-        s.rg_pwm.set_freq(pwm_freq);
-        s.b_pwm.set_freq(pwm_freq);
-        s.set_color(0, 0, 0);
+        s.calibrate(pwm_freq, inverted);
         s
     }
 
-    /// set light intensity between 0 and 255
+    pub fn calibrate(&mut self, pwm_freq: u16, inverted: bool) {
+        self.rg_pwm.set_freq(pwm_freq);
+        self.b_pwm.set_freq(pwm_freq);
+        self.inverted = inverted;
+        self.show(self.color);
+    }
+
+    /// set light intensity between 0 and 255, this stops the running effect
     pub fn set_color(&mut self, r: u8, g: u8, b: u8) {
-        let r = r as u16 * ((0xffff / 0xff) as u16);
-        let g = g as u16 * ((0xffff / 0xff) as u16);
-        let b = b as u16 * ((0xffff / 0xff) as u16);
+        self.effect = None;
+        self.show((r, g, b));
+    }
+
+    /// start a (valid) effect
+    pub fn effect(&mut self, effect: LedEffect) {
+        match effect {
+            LedEffect::Solid((r, g, b)) => self.set_color(r, g, b),
+            effect => self.effect = Some((effect, Instant::now(), self.color)),
+        }
+    }
+
+    /// update the color of the running effect
+    fn step(&mut self, now: Instant) {
+        let Some((effect, start, from)) = self.effect else {
+            return;
+        };
+        let elapsed = now.saturating_duration_since(start).as_millis();
+        // how far into the current period it is, between 0 and 255
+        let phase = |period: u16| (elapsed % period as u64 * 256 / period as u64) as u8;
+
+        let color = match effect {
+            LedEffect::Solid(color) => color,
+            LedEffect::Blink { color, period } => {
+                if phase(period) < 128 {
+                    color
+                } else {
+                    (0, 0, 0)
+                }
+            }
+            LedEffect::Breathe { color, period } => {
+                let p = phase(period);
+                let level = if p < 128 { p * 2 } else { (255 - p) * 2 };
+                scale(color, level)
+            }
+            LedEffect::Rainbow { period, brightness } => hue(phase(period), brightness),
+            LedEffect::Fade { color, duration } => {
+                if elapsed >= duration as u64 {
+                    self.effect = None;
+                    color
+                } else {
+                    let t = (elapsed * 256 / duration as u64) as u8;
+                    mix(from, color, t)
+                }
+            }
+        };
+        if color != self.color {
+            self.show(color);
+        }
+    }
+
+    fn show(&mut self, color: Color) {
+        self.color = color;
+        let duty = |v: u8| {
+            let d = GAMMA[v as usize];
+            if self.inverted {
+                0xffff - d
+            } else {
+                d
+            }
+        };
+        let (r, g, b) = (duty(color.0), duty(color.1), duty(color.2));
 
         self.rg_pwm.set_duty_a(r);
         self.rg_pwm.set_duty_b(g);
         self.b_pwm.set_duty_a(b);
     }
 }
+
+/// `color` at `level` brightness (0 to 255)
+fn scale(color: Color, level: u8) -> Color {
+    let s = |c: u8| (c as u16 * level as u16 / 255) as u8;
+    (s(color.0), s(color.1), s(color.2))
+}
+
+/// `t` of the way from `from` to `to` (0 to 255)
+fn mix(from: Color, to: Color, t: u8) -> Color {
+    let m = |a: u8, b: u8| (a as i32 + (b as i32 - a as i32) * t as i32 / 256) as u8;
+    (m(from.0, to.0), m(from.1, to.1), m(from.2, to.2))
+}
+
+/// fully saturated color of hue `h` (0 to 255 around the color wheel)
+fn hue(h: u8, brightness: u8) -> Color {
+    // six sections, with `f` going from 0 to 255 in each
+    let section = h as u16 * 6 / 256;
+    let f = (h as u16 * 6 % 256) as u8;
+    let (v, up, down) = (255, f, 255 - f);
+    let color = match section {
+        0 => (v, up, 0),
+        1 => (down, v, 0),
+        2 => (0, v, up),
+        3 => (0, down, v),
+        4 => (up, 0, v),
+        _ => (v, 0, down),
+    };
+    scale(color, brightness)
+}
+
+impl RGBLed<'static> {
+    /// hand the LED over to a task running its effects
+    pub fn share(self, spawner: Spawner) -> Shared<Self> {
+        static LED: StaticCell<SharedCell<RGBLed<'static>>> = StaticCell::new();
+        let led = Shared::new(&LED, self);
+        spawner.spawn(effect_task(led)).unwrap();
+        led
+    }
+}
+
+#[embassy_executor::task]
+async fn effect_task(led: Shared<RGBLed<'static>>) {
+    let mut ticker = Ticker::every(TICK);
+    loop {
+        ticker.next().await;
+        let now = Instant::now();
+        led.with(|l| l.step(now));
+    }
+}
@@ -1,28 +1,79 @@
+use embassy_executor::Spawner;
 use embassy_rp::pwm::Pwm;
+use embassy_time::{Duration, Instant, Ticker};
+use roland_protocol::{SerialData, ServoMotion, ServoMove, ServoSweep};
+use static_cell::StaticCell;
 
-use crate::drivers::pwm::PWM;
+use crate::{
+    drivers::{pwm::PWM, Shared, SharedCell},
+    serial::send_data,
+};
+
+/// how often the position of a moving servo is updated, one PWM period
+const TICK: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy)]
+enum Motion {
+    Idle,
+    Move {
+        from: i16,
+        to: i16,
+        start: Instant,
+        duration: Duration,
+    },
+    Sweep {
+        sweep: ServoSweep,
+        start: Instant,
+    },
+}
 
 pub struct Servo<'a> {
     pwm: PWM<'a>,
     min: u16,
     mid: u16,
     max: u16,
+    reversed: bool,
+    /// in hundredths of a degree
+    angle: i16,
+    motion: Motion,
+    /// pulses are being sent
+    attached: bool,
 }
 
 impl<'a> Servo<'a> {
     /// uses the A channel of the PWM
-    pub fn new(pwm: Pwm<'a>, min: u16, mid: u16, max: u16) -> Self {
+    /// `min`, `mid` and `max` are the duty cycles at -90°, 0° and 90°, unless it's `reversed`
+    pub fn new(pwm: Pwm<'a>, min: u16, mid: u16, max: u16, reversed: bool) -> Self {
         let mut pwm = PWM::new(pwm);
         pwm.set_freq(50);
 
-        let mut s = Self { pwm, min, mid, max };
-        s.deg(0);
+        let mut s = Self {
+            pwm,
+            min,
+            mid,
+            max,
+            reversed,
+            angle: 0,
+            motion: Motion::Idle,
+            attached: true,
+        };
+        s.set(0);
         s
     }
 
+    /// change the range, a detached servo stays detached
+    pub fn calibrate(&mut self, min: u16, mid: u16, max: u16, reversed: bool) {
+        self.min = min;
+        self.mid = mid;
+        self.max = max;
+        self.reversed = reversed;
+        if self.attached {
+            self.set(self.angle);
+        }
+    }
+
     fn duty(&mut self, d: u16) {
-        // flip direction
-        let d = 0xffff - d;
+        let d = if self.reversed { 0xffff - d } else { d };
         let d = d as u32;
 
         let duty = if d < 0xffff / 2 {
@@ -37,6 +88,106 @@ impl<'a> Servo<'a> {
 
     /// set rotation between -90 and 90 degrees
     pub fn deg(&mut self, d: i8) {
-        self.duty(((d as i16 + 90) as u32 * 0xffff / 180) as u16);
+        self.motion = Motion::Idle;
+        self.set(d as i16 * 100);
+    }
+
+    /// start moving, `m` has to be valid
+    pub fn start_move(&mut self, m: ServoMove) {
+        let distance = (m.angle - self.angle).unsigned_abs() as u64;
+        let duration = match m.motion {
+            ServoMotion::Instant => Duration::MIN,
+            ServoMotion::Duration(ms) => Duration::from_millis(ms as u64),
+            ServoMotion::Speed(speed) => Duration::from_millis(distance * 10 / speed as u64),
+        };
+        self.motion = Motion::Move {
+            from: self.angle,
+            to: m.angle,
+            start: Instant::now(),
+            duration,
+        };
+        if duration == Duration::MIN {
+            // the next step reports it as done
+            self.set(m.angle);
+        }
+    }
+
+    /// start sweeping at `sweep.from`, it has to be valid
+    pub fn sweep(&mut self, sweep: ServoSweep) {
+        self.motion = Motion::Sweep {
+            sweep,
+            start: Instant::now(),
+        };
+        self.set(sweep.from);
+    }
+
+    pub fn detach(&mut self) {
+        self.motion = Motion::Idle;
+        self.attached = false;
+        self.pwm.set_duty_a(0);
+    }
+
+    /// update the position of a moving servo, returns the angle it stopped at if a move just
+    /// finished
+    fn step(&mut self, now: Instant) -> Option<i16> {
+        match self.motion {
+            Motion::Idle => None,
+            Motion::Move {
+                from,
+                to,
+                start,
+                duration,
+            } => {
+                let elapsed = now.saturating_duration_since(start);
+                if elapsed >= duration {
+                    self.motion = Motion::Idle;
+                    self.set(to);
+                    return Some(to);
+                }
+                let travelled =
+                    (to - from) as i64 * elapsed.as_micros() as i64 / duration.as_micros() as i64;
+                self.set(from + travelled as i16);
+                None
+            }
+            Motion::Sweep { sweep, start } => {
+                let period = sweep.period as u64 * 1000;
+                let t = now.saturating_duration_since(start).as_micros() % period;
+                // there in the first half of the period, back in the second
+                let half = period / 2;
+                let phase = if t < half { t } else { period - t };
+                let travelled = (sweep.to - sweep.from) as i64 * phase as i64 / half as i64;
+                self.set(sweep.from + travelled as i16);
+                None
+            }
+        }
+    }
+
+    /// move to `angle` (0.01°) right away
+    fn set(&mut self, angle: i16) {
+        self.angle = angle;
+        self.attached = true;
+        self.duty(((angle as i32 + 9000) as u32 * 0xffff / 18000) as u16);
+    }
+}
+
+impl Servo<'static> {
+    /// hand the servo over to a task carrying out its moves
+    pub fn share(self, spawner: Spawner) -> Shared<Self> {
+        static SERVO: StaticCell<SharedCell<Servo<'static>>> = StaticCell::new();
+        let servo = Shared::new(&SERVO, self);
+        spawner.spawn(motion_task(servo)).unwrap();
+        servo
+    }
+}
+
+#[embassy_executor::task]
+async fn motion_task(servo: Shared<Servo<'static>>) {
+    let mut ticker = Ticker::every(TICK);
+    loop {
+        ticker.next().await;
+        let now = Instant::now();
+        if let Some(angle) = servo.with(|s| s.step(now)) {
+            send_data(SerialData::ServoDone((now.as_micros(), angle)));
+        }
     }
 }
@@ -1,31 +1,101 @@
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU16, AtomicU8, Ordering},
+};
+
 use embassy_executor::Spawner;
 use embassy_rp::{
-    gpio::{Input, Level, Pin, Pull},
+    gpio::{Input, Pin, Pull},
     Peri,
 };
-use serde::Serialize;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Duration, Instant};
+use roland_protocol::{
+    Capabilities, SerialData, StreamMode, TrackConfig, TrackSensorID, TrackSnapshot,
+};
 
-use crate::serial::{SerialData, DATA};
+use crate::serial::{host_supports, send_sensor_data};
 
-#[derive(Serialize, Debug, Clone)]
-pub enum TrackSensorID {
-    L1,
-    L2,
-    R1,
-    R2,
-}
+/// the debounced level of every sensor, bit `n` is the sensor with index `n`
+static STATE: AtomicU8 = AtomicU8::new(0);
+/// how long each sensor has to hold a new level (µs)
+static DEBOUNCE: [AtomicU16; 4] = [const { AtomicU16::new(TrackConfig::DEFAULT.debounce[0]) }; 4];
+static SNAPSHOT_PERIOD: AtomicU16 = AtomicU16::new(TrackConfig::DEFAULT.snapshot_period);
+/// wakes the snapshot task to send one right away
+static SNAPSHOT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static STREAM: Mutex<CriticalSectionRawMutex, Cell<StreamMode>> =
+    Mutex::new(Cell::new(StreamMode::Continuous));
 
 pub struct TrackSensor {}
 
 #[embassy_executor::task(pool_size = 4)]
 async fn track_sensor_task(mut pin: Input<'static>, id: TrackSensorID) {
+    let mut level = pin.is_high();
+    set_level(id, level);
+
     loop {
         pin.wait_for_any_edge().await;
-        DATA.send(SerialData::TrackSensor((
-            id.clone(),
-            pin.get_level().into(),
-        )))
-        .await;
+        let t = Instant::now().as_micros();
+
+        // noise makes the level flip back and forth, it only counts once it holds
+        let debounce = Duration::from_micros(DEBOUNCE[id as usize].load(Ordering::Relaxed) as u64);
+        while with_timeout(debounce, pin.wait_for_any_edge())
+            .await
+            .is_ok()
+        {}
+
+        if pin.is_high() == level {
+            continue;
+        }
+        level = !level;
+        set_level(id, level);
+        // the state is kept up to date even if it isn't sent, for the snapshots
+        if let StreamMode::Continuous | StreamMode::OnChange = stream_mode() {
+            send_sensor_data(SerialData::TrackSensor((t, id, level)));
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn snapshot_task() {
+    loop {
+        let period = match stream_mode() {
+            StreamMode::Off => 0,
+            StreamMode::Interval(ms) => ms,
+            StreamMode::Continuous | StreamMode::OnChange => {
+                SNAPSHOT_PERIOD.load(Ordering::Relaxed)
+            }
+        };
+        match period {
+            0 => SNAPSHOT.wait().await,
+            ms => {
+                let _ = with_timeout(Duration::from_millis(ms as u64), SNAPSHOT.wait()).await;
+            }
+        }
+
+        // the mode might have changed while waiting
+        if stream_mode() != StreamMode::Off && host_supports(Capabilities::TRACK_SNAPSHOTS) {
+            send_sensor_data(SerialData::Track(TrackSnapshot {
+                time: Instant::now().as_micros(),
+                state: STATE.load(Ordering::Relaxed),
+            }));
+        }
+    }
+}
+
+fn stream_mode() -> StreamMode {
+    STREAM.lock(Cell::get)
+}
+
+fn set_level(id: TrackSensorID, high: bool) {
+    let bit = 1 << id as u8;
+    if high {
+        STATE.fetch_or(bit, Ordering::Relaxed);
+    } else {
+        STATE.fetch_and(!bit, Ordering::Relaxed);
     }
 }
 
@@ -62,5 +132,28 @@ impl TrackSensor {
                 TrackSensorID::R2,
             ))
             .unwrap();
+        spawner.spawn(snapshot_task()).unwrap();
+    }
+
+    /// use a (valid) config, and send a snapshot right away
+    pub fn configure(config: TrackConfig) {
+        for (debounce, us) in DEBOUNCE.iter().zip(config.debounce) {
+            debounce.store(us, Ordering::Relaxed);
+        }
+        SNAPSHOT_PERIOD.store(config.snapshot_period, Ordering::Relaxed);
+        Self::snapshot();
+    }
+
+    /// change what's sent, and send a snapshot right away unless it's `Off`
+    ///
+    /// with [`StreamMode::Interval`], only snapshots are sent
+    pub fn stream(mode: StreamMode) {
+        STREAM.lock(|s| s.set(mode));
+        Self::snapshot();
+    }
+
+    /// send a snapshot of every sensor's level, if the host understands it
+    pub fn snapshot() {
+        SNAPSHOT.signal(());
     }
 }
@@ -3,53 +3,91 @@ use embassy_rp::{
     gpio::{Input, Level, Output, Pin, Pull},
     Peri,
 };
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::{with_timeout, Duration, Instant, Timer};
-use heapless::Deque;
+use heapless::{Deque, Vec};
 
-use crate::serial::{SerialData, DATA};
+use roland_protocol::{
+    Capabilities, SerialData, StreamMode, UltraConfig, UltraError, UltraFilter, UltraReading,
+};
+
+use crate::serial::{host_supports, send_sensor_data};
+
+// for these, refer to the ultra sensor datasheet
+/// how long the sensor may take to start the echo pulse after the trigger
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(20);
+/// the sensor ends the echo pulse after about 38ms if nothing reflected the sound
+const NO_ECHO_PULSE: Duration = Duration::from_millis(30);
+
+/// consecutive failed measurements after which the moving average is dropped
+const EMA_MAX_MISSES: u8 = 3;
+
+/// the config for the sensor task to pick up
+static CONFIG: Signal<CriticalSectionRawMutex, UltraConfig> = Signal::new();
+/// the stream mode for the sensor task to pick up
+static STREAM: Signal<CriticalSectionRawMutex, StreamMode> = Signal::new();
 
 pub struct UltraSensor {
     trig: Output<'static>,
     echo: Input<'static>,
-    data: Deque<u16, 4>,
 }
 
 #[embassy_executor::task]
 async fn ultra_sensor_task(mut ultra: UltraSensor) {
-    // for these, refer to the ultra sensor datasheet
-    const MIN_DIST: u16 = 2;
-    const MAX_DIST: u16 = 400;
+    let mut config = UltraConfig::DEFAULT;
+    let mut filter = Filter::new(config.filter);
+    let mut stream = StreamMode::Continuous;
+    // the filtered distance and time of the last reading sent, `None` if there's none since the
+    // stream mode changed
+    let mut last_sent: Option<(Option<u16>, Instant)> = None;
 
     loop {
-        let mut done = false;
-        let _ = with_timeout(Duration::from_millis(60), async {
-            ultra.trig.set_high();
-            Timer::after_micros(10).await;
-            ultra.trig.set_low();
-
-            ultra.echo.wait_for_high().await;
-
-            let rise = Instant::now();
-            ultra.echo.wait_for_low().await;
-            let fall = Instant::now();
+        if let Some(c) = CONFIG.try_take() {
+            config = c;
+            filter = Filter::new(c.filter);
+        }
+        if let Some(s) = STREAM.try_take() {
+            stream = s;
+            last_sent = None;
+        }
+        if stream == StreamMode::Off {
+            // nobody's listening, so there's no point in measuring until that changes
+            stream = STREAM.wait().await;
+            // the old measurements are stale by then
+            filter = Filter::new(config.filter);
+            last_sent = None;
+            continue;
+        }
 
-            let dist = ((fall - rise).as_micros() * 343 / 10000 / 2) as u16;
+        // the readings are timestamped with the moment the measurement was triggered
+        let start = Instant::now();
+        let raw = ultra.measure().await;
+        let filtered = filter.push(raw.ok());
 
-            if (MIN_DIST..=MAX_DIST).contains(&dist) {
-                ultra.push_data(dist);
-                DATA.send(SerialData::UltraSensor(Some(ultra.get_dist().unwrap())))
-                    .await;
-            } else {
-                DATA.send(SerialData::UltraSensor(None)).await;
+        let send = match (stream, last_sent) {
+            (_, None) | (StreamMode::Continuous, _) => true,
+            (StreamMode::OnChange, Some((last, _))) => filtered != last,
+            (StreamMode::Interval(ms), Some((_, at))) => {
+                start >= at + Duration::from_millis(ms as u64)
             }
-            done = true;
-
-            Timer::after_millis(60).await;
-        })
-        .await;
-        if !done {
-            DATA.send(SerialData::UltraSensor(None)).await;
+            (StreamMode::Off, _) => false,
+        };
+        if send {
+            last_sent = Some((filtered, start));
+            let t = start.as_micros();
+            send_sensor_data(if host_supports(Capabilities::ULTRA_READINGS) {
+                SerialData::Ultra(UltraReading {
+                    time: t,
+                    raw,
+                    filtered,
+                })
+            } else {
+                // to the nearest cm
+                SerialData::UltraSensor((t, filtered.map(|mm| (mm + 5) / 10)))
+            });
         }
+
+        Timer::at(start + Duration::from_millis(config.period as u64)).await;
     }
 }
 
@@ -63,24 +101,111 @@ impl UltraSensor {
         let s = Self {
             trig: Output::new(trig_pin, Level::Low),
             echo: Input::new(echo_pin, Pull::None),
-            data: Deque::new(),
         };
 
         spawner.spawn(ultra_sensor_task(s)).unwrap();
     }
 
-    fn push_data(&mut self, d: u16) {
-        if self.data.len() == self.data.capacity() {
-            self.data.pop_front();
+    /// use a (valid) config from the next measurement on
+    pub fn configure(config: UltraConfig) {
+        CONFIG.signal(config);
+    }
+
+    /// change what's sent from the next measurement on, `Off` stops measuring
+    pub fn stream(mode: StreamMode) {
+        STREAM.signal(mode);
+    }
+
+    /// distance in mm
+    async fn measure(&mut self) -> Result<u16, UltraError> {
+        self.trig.set_high();
+        Timer::after_micros(10).await;
+        self.trig.set_low();
+
+        with_timeout(RESPONSE_TIMEOUT, self.echo.wait_for_high())
+            .await
+            .map_err(|_| UltraError::Timeout)?;
+        let rise = Instant::now();
+        with_timeout(NO_ECHO_PULSE, self.echo.wait_for_low())
+            .await
+            .map_err(|_| UltraError::NoEcho)?;
+        let fall = Instant::now();
+
+        // sound travels 0.343 mm/µs, there and back
+        let mm = (fall - rise).as_micros() * 343 / 2000;
+        match u16::try_from(mm) {
+            Ok(mm) if UltraConfig::RANGE.contains(&mm) => Ok(mm),
+            _ => Err(UltraError::OutOfRange),
         }
-        let _ = self.data.push_back(d);
     }
+}
 
-    fn get_dist(&self) -> Option<u16> {
-        if self.data.is_empty() {
-            None
-        } else {
-            Some(self.data.iter().sum::<u16>() / self.data.len() as u16)
+struct Filter {
+    kind: UltraFilter,
+    /// the last measurements, for the median
+    window: Deque<Option<u16>, { UltraConfig::MAX_WINDOW as usize }>,
+    /// the moving average, in 1/256 mm
+    ema: Option<u32>,
+    /// failed measurements since the last successful one
+    misses: u8,
+}
+
+impl Filter {
+    fn new(kind: UltraFilter) -> Self {
+        Self {
+            kind,
+            window: Deque::new(),
+            ema: None,
+            misses: 0,
+        }
+    }
+
+    /// add a measurement, `None` if it failed, and get the filtered distance
+    fn push(&mut self, sample: Option<u16>) -> Option<u16> {
+        match self.kind {
+            UltraFilter::None => sample,
+            UltraFilter::Median(n) => {
+                if self.window.len() >= n as usize {
+                    self.window.pop_front();
+                }
+                let _ = self.window.push_back(sample);
+
+                let mut valid: Vec<u16, { UltraConfig::MAX_WINDOW as usize }> =
+                    self.window.iter().flatten().copied().collect();
+                // mostly failures, there's nothing reliable to report
+                if valid.is_empty() || valid.len() * 2 < self.window.len() {
+                    return None;
+                }
+                valid.sort_unstable();
+                let mid = valid.len() / 2;
+                if valid.len().is_multiple_of(2) {
+                    Some(((valid[mid - 1] as u32 + valid[mid] as u32) / 2) as u16)
+                } else {
+                    Some(valid[mid])
+                }
+            }
+            UltraFilter::Ema(weight) => {
+                match sample {
+                    Some(mm) => {
+                        let mm = mm as u32 * 256;
+                        self.misses = 0;
+                        self.ema = Some(match self.ema {
+                            Some(ema) => {
+                                (ema as i64 + (mm as i64 - ema as i64) * weight as i64 / 100) as u32
+                            }
+                            None => mm,
+                        });
+                    }
+                    None => {
+                        self.misses = self.misses.saturating_add(1);
+                        if self.misses >= EMA_MAX_MISSES {
+                            self.ema = None;
+                        }
+                    }
+                }
+                // a few misses in a row still report the average
+                self.ema.map(|ema| ((ema + 128) / 256) as u16)
+            }
         }
     }
 }
@@ -0,0 +1,133 @@
+//! panic and hard fault handling
+//!
+//! either one drives the outputs registered with [`hold_low`] low, zeroes the duty cycle of every
+//! PWM slice and keeps what happened in a RAM section that isn't cleared on boot, then resets the
+//! chip. the next boot picks the fault up with [`take`], so it can be reported to the host
+
+use core::{
+    fmt::Write as _,
+    mem::MaybeUninit,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+use embassy_rp::pac;
+use embassy_time::Instant;
+use roland_protocol::{Fault, FaultKind, FaultLocation, ShortString};
+
+/// marks [`RECORD`] as written by [`record`], anything else is left over from a power cycle
+const MAGIC: u32 = 0xdead_c0de;
+/// the RP2350 has 12 PWM slices
+const PWM_SLICES: usize = 12;
+
+/// GPIO pins to drive low, bit `n` is `PIN_n`
+static HOLD_LOW: AtomicU32 = AtomicU32::new(0);
+/// set once a fault is being handled, so one inside the handler doesn't recurse
+static FAULTED: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+struct Record {
+    magic: u32,
+    fault: Fault,
+}
+
+#[unsafe(link_section = ".uninit.fault")]
+static mut RECORD: MaybeUninit<Record> = MaybeUninit::uninit();
+
+/// drive `pin` (a GPIO output of bank 0) low on a panic or hard fault
+pub fn hold_low(pin: u8) {
+    HOLD_LOW.fetch_or(1 << pin, Ordering::Relaxed);
+}
+
+/// the fault that caused the last reset, if there was one
+///
+/// it's only returned once, the next boot won't see it again
+pub fn take() -> Option<Fault> {
+    // SAFETY: the fault is only read if the magic says a fault handler wrote it in full, and
+    // those never return
+    unsafe {
+        let record = (&raw mut RECORD).cast::<Record>();
+        let magic = &raw mut (*record).magic;
+        if magic.read_volatile() != MAGIC {
+            return None;
+        }
+        magic.write_volatile(0);
+        Some((&raw const (*record).fault).read_volatile())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enter();
+
+    let mut message = ShortString::empty();
+    let _ = write!(message, "{}", info.message());
+    let location = info.location().map(|l| FaultLocation {
+        file: ShortString::new(l.file()),
+        line: l.line(),
+        column: l.column(),
+    });
+    record(FaultKind::Panic, message, location)
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    enter();
+
+    let mut message = ShortString::empty();
+    let _ = write!(
+        message,
+        "pc {:#010x}, lr {:#010x}, xpsr {:#010x}",
+        frame.pc(),
+        frame.lr(),
+        frame.xpsr()
+    );
+    record(FaultKind::HardFault, message, None)
+}
+
+/// stop everything else from running and bring the outputs to a safe state, a fault inside a
+/// fault handler resets right away
+fn enter() {
+    cortex_m::interrupt::disable();
+    safe_state();
+    if FAULTED.swap(true, Ordering::Relaxed) {
+        SCB::sys_reset();
+    }
+}
+
+/// writes the registers directly, the drivers might be what's broken
+fn safe_state() {
+    let pins = HOLD_LOW.load(Ordering::Relaxed);
+    pac::SIO.gpio_out(0).value_clr().write_value(pins);
+    pac::SIO.gpio_oe(0).value_set().write_value(pins);
+
+    for slice in 0..PWM_SLICES {
+        pac::PWM.ch(slice).cc().write(|w| {
+            w.set_a(0);
+            w.set_b(0);
+        });
+    }
+}
+
+fn record(
+    kind: FaultKind,
+    message: ShortString<{ Fault::MESSAGE_LEN }>,
+    location: Option<FaultLocation>,
+) -> ! {
+    let fault = Fault {
+        kind,
+        uptime: Instant::now().as_micros(),
+        message,
+        location,
+    };
+    // SAFETY: interrupts are disabled and nothing else touches the record after booting
+    unsafe {
+        (&raw mut RECORD).write_volatile(MaybeUninit::new(Record {
+            magic: MAGIC,
+            fault,
+        }));
+    }
+    SCB::sys_reset()
+}
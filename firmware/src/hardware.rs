@@ -1,43 +1,146 @@
 use embassy_executor::Spawner;
 use embassy_rp::{
     pwm::{self, Pwm},
-    Peripherals,
+    rom_data, Peripherals,
+};
+use embassy_time::{with_deadline, Duration, Instant, Timer};
+use log::{debug, info, warn};
+use roland_protocol::{
+    ActuatorState, Calibration, Capabilities, Command, Fault, Hello, LedEffect, Query, RebootMode,
+    Rejection, Response, Sensor, SerialCMD, SerialData, DEFAULT_WATCHDOG_TIMEOUT,
+    MIN_WATCHDOG_TIMEOUT,
 };
 
 use crate::{
     drivers::{
         buzzer::Buzzer, h_bridge::HBridge, rgb_led::RGBLed, servo::Servo,
-        track_sensor::TrackSensor, ultra_sensor::UltraSensor,
+        track_sensor::TrackSensor, ultra_sensor::UltraSensor, Shared,
     },
-    // log::logger_task,
-    serial::{serial_init, SerialCMD, CMD},
+    fault,
+    serial::{host_supports, send_data, serial_init, set_host_capabilities, stats, CMD},
+    storage::Storage,
+    version,
 };
 
 /// manages all incoming hardware commands
+/// if the host goes quiet for longer than the watchdog timeout, the hardware is brought to a safe
+/// state, so a crashed host can't leave the motors running
 #[embassy_executor::task]
 async fn hardware_task(mut hw: Hardware) {
+    let mut timeout = Some(Duration::from_millis(DEFAULT_WATCHDOG_TIMEOUT as u64));
+    // only armed while a host is talking to us, so an idle pico doesn't keep tripping
+    let mut armed = false;
+    let mut last_cmd = Instant::now();
+
     loop {
-        match CMD.receive().await {
-            SerialCMD::Buzzer(freq) => hw.buzzer.freq(freq),
-            SerialCMD::LED((r, g, b)) => hw.led.set_color(r, g, b),
-            SerialCMD::Servo(deg) => hw.servo.deg(deg),
-            SerialCMD::HBridge((l_speed, r_speed)) => hw.hb.drive(l_speed, r_speed),
-            SerialCMD::Reset => {
-                hw.buzzer.freq(0);
-                hw.led.set_color(0, 0, 0);
-                hw.servo.deg(0);
-                hw.hb.drive(0, 0);
+        let received = match timeout {
+            Some(timeout) if armed => with_deadline(last_cmd + timeout, CMD.receive()).await.ok(),
+            _ => Some(CMD.receive().await),
+        };
+
+        let Some(Command { seq, cmd }) = received else {
+            warn!("watchdog tripped, stopping everything");
+            hw.safe_state();
+            armed = false;
+            send_data(SerialData::WatchdogTripped);
+            continue;
+        };
+
+        last_cmd = Instant::now();
+        // the host is going away on purpose after a reset
+        armed = !matches!(cmd, SerialCMD::Reset);
+        let mut reboot = None;
+
+        let ret = match cmd {
+            SerialCMD::Watchdog(0) => {
+                timeout = None;
+                Ok(())
+            }
+            SerialCMD::Watchdog(ms) if ms < MIN_WATCHDOG_TIMEOUT => {
+                debug!("watchdog timeout {}ms is too short", ms);
+                Err(Rejection::OutOfRange)
+            }
+            SerialCMD::Watchdog(ms) => {
+                debug!("watchdog timeout set to {}ms", ms);
+                timeout = Some(Duration::from_millis(ms as u64));
+                Ok(())
+            }
+            SerialCMD::Sync(id) => {
+                send_data(SerialData::Sync((id, Instant::now().as_micros())));
+                Ok(())
+            }
+            // the host decides whether it can talk to us
+            SerialCMD::Hello(hello) => {
+                set_host_capabilities(hello.capabilities);
+                send_data(SerialData::Hello(Hello::new(Capabilities::all())));
+                // the host doesn't know the state of the sensors that haven't changed since
+                TrackSensor::snapshot();
+                if host_supports(Capabilities::FAULTS) {
+                    if let Some(fault) = hw.fault.take() {
+                        send_data(SerialData::Fault(fault));
+                    }
+                }
+                Ok(())
+            }
+            SerialCMD::Query((id, query)) => {
+                let response = match query {
+                    Query::Ping => Response::Pong,
+                    Query::GetVersion => Response::Version(version()),
+                    Query::GetActuatorState => Response::ActuatorState(hw.state.clone()),
+                    Query::GetCalibration => Response::Calibration(hw.calibration),
+                    Query::GetStats => Response::Stats(stats()),
+                };
+                send_data(SerialData::Response((id, response)));
+                Ok(())
+            }
+            SerialCMD::SetCalibration(calibration) => {
+                calibration.validate().map(|()| hw.calibrate(calibration))
             }
+            SerialCMD::SaveCalibration => {
+                let ret = hw.storage.save_calibration(&hw.calibration);
+                match ret {
+                    Ok(()) => info!("calibration saved"),
+                    Err(_) => warn!("couldn't save the calibration"),
+                }
+                ret
+            }
+            // only after the ack went out
+            SerialCMD::Reboot(mode) => {
+                reboot = Some(mode);
+                Ok(())
+            }
+            cmd => hw.apply(cmd),
+        };
+
+        if let Some(seq) = seq {
+            send_data(match ret {
+                Ok(()) => SerialData::Ack(seq),
+                Err(reason) => SerialData::Nack((seq, reason)),
+            });
+        }
+
+        if let Some(mode) = reboot {
+            info!("rebooting into {:?}", mode);
+            hw.safe_state();
+            // give the ack and the log a chance to reach the host
+            Timer::after_millis(100).await;
+            self::reboot(mode);
         }
     }
 }
 
 /// wrapper for all external peripherals
 pub struct Hardware {
-    buzzer: Buzzer<'static>,
-    led: RGBLed<'static>,
-    servo: Servo<'static>,
-    hb: HBridge<'static>,
+    buzzer: Shared<Buzzer<'static>>,
+    led: Shared<RGBLed<'static>>,
+    servo: Shared<Servo<'static>>,
+    hb: Shared<HBridge<'static>>,
+    /// what the actuators were last set to
+    state: ActuatorState,
+    calibration: Calibration,
+    storage: Storage,
+    /// the crash before this boot, until a host that understands it connects
+    fault: Option<Fault>,
 }
 
 impl Hardware {
@@ -45,26 +148,47 @@ impl Hardware {
     pub async fn init(p: Peripherals, spawner: Spawner) {
         spawner.spawn(serial_init(p.USB, spawner)).unwrap();
 
-        // spawner.spawn(logger_task(p.USB)).unwrap();
+        let fault = fault::take();
+        if let Some(fault) = &fault {
+            warn!("rebooted because the firmware {}", fault);
+        }
+
+        let mut storage = Storage::new(p.FLASH);
+        let calibration = match storage.load_calibration() {
+            Some(calibration) => {
+                info!("loaded {:?}", calibration);
+                calibration
+            }
+            None => {
+                info!("no calibration saved, using the defaults");
+                Calibration::DEFAULT
+            }
+        };
+        let c = &calibration;
 
         let buzzer = Buzzer::new(Pwm::new_output_a(
             p.PWM_SLICE0,
             p.PIN_0,
             pwm::Config::default(),
         ));
+        let buzzer = buzzer.share(spawner);
 
         let led = RGBLed::new(
             Pwm::new_output_ab(p.PWM_SLICE1, p.PIN_18, p.PIN_19, pwm::Config::default()),
             Pwm::new_output_a(p.PWM_SLICE2, p.PIN_20, pwm::Config::default()),
-            2000,
+            c.led_freq,
+            c.led_inverted,
         );
+        let led = led.share(spawner);
 
         let servo = Servo::new(
             Pwm::new_output_a(p.PWM_SLICE6, p.PIN_28, pwm::Config::default()),
-            2100,
-            4800,
-            8300,
+            c.servo_min,
+            c.servo_mid,
+            c.servo_max,
+            c.servo_reversed,
         );
+        let servo = servo.share(spawner);
 
         let hb = HBridge::new(
             p.PIN_13,
@@ -72,8 +196,10 @@ impl Hardware {
             p.PIN_11,
             p.PIN_10,
             Pwm::new_output_ab(p.PWM_SLICE7, p.PIN_14, p.PIN_15, pwm::Config::default()),
-            2000,
+            c.motor_freq,
+            (c.motor_left_reversed, c.motor_right_reversed),
         );
+        let hb = hb.share(spawner);
 
         UltraSensor::init(p.PIN_21, p.PIN_22, spawner);
 
@@ -84,8 +210,156 @@ impl Hardware {
             led,
             servo,
             hb,
+            state: ActuatorState::default(),
+            calibration,
+            storage,
+            fault,
         };
 
         spawner.spawn(hardware_task(hw)).unwrap();
     }
+
+    /// validate and execute a single command
+    fn apply(&mut self, cmd: SerialCMD) -> Result<(), Rejection> {
+        match cmd {
+            SerialCMD::Buzzer(freq) => {
+                self.buzzer.with(|b| b.freq(freq));
+                self.state.buzzer = freq;
+            }
+            SerialCMD::LED((r, g, b)) => {
+                self.led.with(|l| l.set_color(r, g, b));
+                self.state.led = (r, g, b);
+            }
+            SerialCMD::Servo(deg) => {
+                check_servo(deg)?;
+                self.servo.with(|s| s.deg(deg));
+                self.state.servo = deg as i16 * 100;
+            }
+            SerialCMD::HBridge((l_speed, r_speed)) => {
+                self.hb.with(|hb| hb.drive(l_speed, r_speed));
+                // the driver clamps the same way
+                self.state.motor = (
+                    l_speed.clamp(-0xffff, 0xffff),
+                    r_speed.clamp(-0xffff, 0xffff),
+                );
+            }
+            SerialCMD::Reset => {
+                self.safe_state();
+                self.led.with(|l| l.set_color(0, 0, 0));
+                self.state.led = (0, 0, 0);
+            }
+            SerialCMD::Heartbeat => {}
+            // handled by the hardware task
+            SerialCMD::Watchdog(_)
+            | SerialCMD::Sync(_)
+            | SerialCMD::Query(_)
+            | SerialCMD::Hello(_)
+            | SerialCMD::SetCalibration(_)
+            | SerialCMD::SaveCalibration
+            | SerialCMD::Reboot(_) => {}
+            SerialCMD::ServoMove(m) => {
+                m.validate()?;
+                self.servo.with(|s| s.start_move(m));
+                self.state.servo = m.angle;
+            }
+            SerialCMD::ServoSweep(sweep) => {
+                sweep.validate()?;
+                self.servo.with(|s| s.sweep(sweep));
+            }
+            SerialCMD::ServoDetach => self.servo.with(Servo::detach),
+            SerialCMD::LedEffect(effect) => {
+                effect.validate()?;
+                self.led.with(|l| l.effect(effect));
+                // where it ends up, if it stops anywhere
+                if let LedEffect::Solid(color) | LedEffect::Fade { color, .. } = effect {
+                    self.state.led = color;
+                }
+            }
+            SerialCMD::UltraConfig(config) => {
+                config.validate()?;
+                UltraSensor::configure(config);
+            }
+            SerialCMD::TrackConfig(config) => {
+                config.validate()?;
+                TrackSensor::configure(config);
+            }
+            SerialCMD::Stream((sensor, mode)) => {
+                mode.validate()?;
+                match sensor {
+                    Sensor::Ultra => UltraSensor::stream(mode),
+                    Sensor::Track => TrackSensor::stream(mode),
+                }
+            }
+            SerialCMD::Melody(chunk) => {
+                chunk.validate()?;
+                self.buzzer.with(|b| b.queue(chunk))?;
+                self.state.buzzer = 0;
+            }
+            SerialCMD::MotorRamp(ramp) => self.hb.with(|hb| hb.set_ramp(ramp)),
+            SerialCMD::StopMode(mode) => self.hb.with(|hb| hb.set_stop_mode(mode)),
+            SerialCMD::EmergencyStop => {
+                self.hb.with(HBridge::emergency_stop);
+                self.state.motor = (0, 0);
+            }
+            SerialCMD::Batch(batch) => {
+                // check everything up front, so a refused batch changes nothing
+                batch.validate()?;
+                for cmd in batch.commands() {
+                    self.apply(cmd)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// switch to a (validated) calibration, keeping the actuators where they are
+    fn calibrate(&mut self, c: Calibration) {
+        self.servo
+            .with(|s| s.calibrate(c.servo_min, c.servo_mid, c.servo_max, c.servo_reversed));
+        self.led.with(|l| l.calibrate(c.led_freq, c.led_inverted));
+        self.hb.with(|hb| {
+            hb.calibrate(
+                c.motor_freq,
+                (c.motor_left_reversed, c.motor_right_reversed),
+            )
+        });
+        self.calibration = c;
+        debug!("calibrated to {:?}", c);
+    }
+
+    /// stop everything that moves or makes noise
+    fn safe_state(&mut self) {
+        self.buzzer.with(|b| b.freq(0));
+        self.servo.with(|s| s.deg(0));
+        // without ramping down, nobody might be around to wait for it. the brake is released
+        // shortly after, the motors don't stay shorted while idle
+        self.hb.with(HBridge::emergency_stop);
+        self.state.buzzer = 0;
+        self.state.servo = 0;
+        self.state.motor = (0, 0);
+    }
+}
+
+/// reboot through the boot ROM, see section 5.5.10.1 of the RP2350 datasheet
+fn reboot(mode: RebootMode) -> ! {
+    const REBOOT_TYPE_NORMAL: u32 = 0x0000;
+    const REBOOT_TYPE_BOOTSEL: u32 = 0x0002;
+    const NO_RETURN_ON_SUCCESS: u32 = 0x0100;
+
+    let flags = match mode {
+        RebootMode::Application => REBOOT_TYPE_NORMAL,
+        RebootMode::Bootloader => REBOOT_TYPE_BOOTSEL,
+    };
+    // for BOOTSEL, p0 and p1 select the interfaces and the activity LED, 0 keeps the defaults
+    rom_data::reboot(flags | NO_RETURN_ON_SUCCESS, 10, 0, 0);
+    panic!("the boot ROM refused to reboot into {:?}", mode)
+}
+
+fn check_servo(deg: i8) -> Result<(), Rejection> {
+    if (-90..=90).contains(&deg) {
+        Ok(())
+    } else {
+        debug!("servo angle {} is out of range", deg);
+        Err(Rejection::OutOfRange)
+    }
 }
@@ -1,48 +1,76 @@
-use embassy_executor::Spawner;
-use embassy_rp::peripherals::{DMA_CH0, PIN_16, PIN_17, UART0};
-use embassy_rp::uart::{Async, Config, InterruptHandler, UartTx};
-use embassy_rp::{bind_interrupts, Peri};
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::Channel;
-use embassy_time::Timer;
+use core::fmt::Write as _;
+
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pipe::Pipe;
+use embassy_usb::class::cdc_acm::Sender;
 use heapless::String;
-use {defmt_rtt as _, panic_probe as _};
+use log::{LevelFilter, Metadata, Record};
 
-bind_interrupts!(struct Irqs {
-    UART0_IRQ => InterruptHandler<UART0>;
-});
+use crate::serial::write_transfer;
 
-/// channel for debug logs
-pub static LOG: Channel<ThreadModeRawMutex, String<64>, 64> = Channel::new();
+/// longest log line, anything longer is cut off
+const MAX_LINE: usize = 128;
 
-#[embassy_executor::task]
-async fn log_tx_task(mut tx: UartTx<'static, Async>) {
-    loop {
-        tx.write(b"Balls\n").await.unwrap();
-        Timer::after_secs(1).await;
+/// log lines waiting to be sent to the host
+static LOG: Pipe<CriticalSectionRawMutex, 1024> = Pipe::new();
+
+static LOGGER: UsbLogger = UsbLogger;
+
+/// formats `log` records into [`LOG`], one `<LEVEL> <target>: <message>` line per record
+/// records that don't fit are dropped, so logging never blocks
+struct UsbLogger;
+
+impl log::Log for UsbLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
     }
-}
 
-macro_rules! log {
-    ($($arg:tt)*) => {
-        use core::fmt::Write;
+    fn log(&self, record: &Record) {
+        let mut line: String<MAX_LINE> = String::new();
+        let _ = write!(
+            line,
+            "{} {}: {}",
+            record.level(),
+            record.target(),
+            record.args()
+        );
 
-        let mut buf: String<64> = String::new();
-        let _ = write!(&mut buf, $($arg)*);
-        LOG.send(buf).await;
+        if LOG.free_capacity() < line.len() + 1 {
+            return;
+        }
+        for mut buf in [line.as_bytes(), b"\n"] {
+            // the pipe is a ring buffer, so this can take two writes
+            while let Ok(n @ 1..) = LOG.try_write(buf) {
+                buf = &buf[n..];
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// install the logger, records before this are lost
+pub fn init() {
+    let level = if cfg!(debug_assertions) {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
     };
+    let _ = log::set_logger(&LOGGER).map(|()| log::set_max_level(level));
 }
 
+/// sends the log lines to the host over the second serial port
+/// they're only buffered while nobody is listening
 #[embassy_executor::task]
-pub async fn logger_task(
-    uart: Peri<'static, UART0>,
-    tx_pin: Peri<'static, PIN_16>,
-    // RX is currently not utilized
-    _rx_pin: Peri<'static, PIN_17>,
-    dma: Peri<'static, DMA_CH0>,
-    spawner: Spawner,
-) {
-    let uart_tx = UartTx::new(uart, tx_pin, dma, Config::default());
-    spawner.spawn(log_tx_task(uart_tx)).unwrap();
-    // log!("Nig{}", "hts");
+pub async fn logger_task(mut tx: Sender<'static, Driver<'static, USB>>) {
+    let mut buf = [0u8; 64];
+
+    loop {
+        tx.wait_connection().await;
+        let n = LOG.read(&mut buf).await;
+        // lost lines are better than a stuck logger
+        let _ = write_transfer(&mut tx, &buf[..n]).await;
+    }
 }
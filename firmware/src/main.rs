@@ -1,15 +1,18 @@
 #![no_std]
 #![no_main]
 
+use defmt_rtt as _;
 use embassy_executor::Spawner;
-use {defmt_rtt as _, panic_probe as _};
+use roland_protocol::{Version, PROTOCOL_VERSION};
 
 use crate::hardware::Hardware;
 
 mod drivers;
+mod fault;
 mod hardware;
 mod log;
 mod serial;
+mod storage;
 
 // firmware metadata
 #[unsafe(link_section = ".bi_entries")]
@@ -21,7 +24,21 @@ pub static PICOTOOL_ENTRIES: [embassy_rp::binary_info::EntryAddr; 4] = [
     embassy_rp::binary_info::rp_program_build_attribute!(),
 ];
 
+/// the same build information as [`PICOTOOL_ENTRIES`], in a form the host can query
+pub fn version() -> Version {
+    let num = |s: &str| s.parse().unwrap_or(0);
+    Version {
+        protocol: PROTOCOL_VERSION,
+        major: num(env!("CARGO_PKG_VERSION_MAJOR")),
+        minor: num(env!("CARGO_PKG_VERSION_MINOR")),
+        patch: num(env!("CARGO_PKG_VERSION_PATCH")),
+        debug: cfg!(debug_assertions),
+    }
+}
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
+    log::init();
+    ::log::info!("Roland firmware {} starting", version());
     Hardware::init(embassy_rp::init(Default::default()), spawner).await;
 }
@@ -1,50 +1,141 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
+
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_rp::otp;
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::{Driver, InterruptHandler};
 use embassy_rp::{bind_interrupts, Peri};
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::Channel;
+use embassy_sync::channel::{Channel, TrySendError};
+use embassy_time::Instant;
 use embassy_usb::class::cdc_acm::{CdcAcmClass, Receiver, Sender, State};
+use embassy_usb::driver::EndpointError;
 use embassy_usb::UsbDevice;
-use postcard::{from_bytes, to_slice};
-use serde::{Deserialize, Serialize};
+use heapless::String;
+use roland_protocol::{
+    frame::{self, FrameDecoder, MAX_FRAME_SIZE},
+    Capabilities, Command, SerialCMD, SerialData, Stats, USB_PID, USB_PRODUCT, USB_VID,
+};
 use static_cell::StaticCell;
 
-use crate::drivers::track_sensor::TrackSensorID;
+use crate::log::logger_task;
 
 bind_interrupts!(pub struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
 });
 
-/// pico -> pi
-#[derive(Serialize, Debug)]
-pub enum SerialData {
-    /// measured distance in cm
-    UltraSensor(Option<u16>),
-    /// sensor id and value
-    TrackSensor((TrackSensorID, bool)),
+/// max size of a single USB packet
+const MAX_PACKET_SIZE: u16 = 64;
+
+/// number of incoming frames dropped because they couldn't be decoded
+pub static FRAMING_ERRORS: AtomicU32 = AtomicU32::new(0);
+// the rest of the counters in [`Stats`]
+static DROPPED_DATA: AtomicU32 = AtomicU32::new(0);
+static DROPPED_COMMANDS: AtomicU32 = AtomicU32::new(0);
+static DATA_HIGH_WATER: AtomicU32 = AtomicU32::new(0);
+static CMD_HIGH_WATER: AtomicU32 = AtomicU32::new(0);
+
+/// capabilities of the host, from its last hello, so it isn't sent data it can't decode
+static HOST_CAPABILITIES: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_host_capabilities(capabilities: Capabilities) {
+    HOST_CAPABILITIES.store(capabilities.0, Ordering::Relaxed);
 }
 
-/// pi -> pico
-#[derive(Deserialize, Debug)]
-pub enum SerialCMD {
-    /// frequency (Hz)
-    Buzzer(u16),
-    /// RGB color (0 to 255)
-    LED((u8, u8, u8)),
-    /// rotation in degrees (0 is the midpoint, -90 to 90)
-    Servo(i8),
-    /// duty cycle (sign is direction)
-    HBridge((i32, i32)),
-    /// Reset the hardware peripherals to a neutral (known) state
-    Reset,
+pub fn host_supports(capability: Capabilities) -> bool {
+    Capabilities(HOST_CAPABILITIES.load(Ordering::Relaxed)).contains(capability)
 }
 
 /// channel for incoming messages
-pub static CMD: Channel<ThreadModeRawMutex, SerialCMD, 64> = Channel::new();
+pub static CMD: Channel<ThreadModeRawMutex, Command, 64> = Channel::new();
+
+/// channel for outgoing messages, written by [`send_data`], except for sensor data, which goes
+/// through [`send_sensor_data`]
+static DATA: Channel<ThreadModeRawMutex, SerialData, 64> = Channel::new();
+
+/// channel for outgoing sensor data, it's only sent while `DATA` is empty
+static SENSOR_DATA: Channel<ThreadModeRawMutex, SerialData, 32> = Channel::new();
+
+/// queue a message for the host without waiting for it to read it, so nothing stalls while USB is
+/// down
+///
+/// if the queue is full, the message is dropped, the host retransmits the commands it needs an
+/// answer to
+pub fn send_data(data: SerialData) {
+    if DATA.try_send(data).is_err() {
+        DROPPED_DATA.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// queue sensor data without waiting for the host to read it
+///
+/// if the queue is full, the oldest data is dropped, a sensor task shouldn't stall and newer
+/// measurements are more useful anyway
+pub fn send_sensor_data(data: SerialData) {
+    if let Err(TrySendError::Full(data)) = SENSOR_DATA.try_send(data) {
+        let _ = SENSOR_DATA.try_receive();
+        DROPPED_DATA.fetch_add(1, Ordering::Relaxed);
+        let _ = SENSOR_DATA.try_send(data);
+    }
+}
+
+/// commands that move or sound something
+fn is_actuator(cmd: &SerialCMD) -> bool {
+    matches!(
+        cmd,
+        SerialCMD::Buzzer(_)
+            | SerialCMD::Melody(_)
+            | SerialCMD::LED(_)
+            | SerialCMD::LedEffect(_)
+            | SerialCMD::Servo(_)
+            | SerialCMD::ServoMove(_)
+            | SerialCMD::ServoSweep(_)
+            | SerialCMD::ServoDetach
+            | SerialCMD::HBridge(_)
+            | SerialCMD::Batch(_)
+    )
+}
+
+/// hand a command over to the hardware task without waiting, so the stops behind a full queue
+/// still get through
+fn queue_command(cmd: Command) {
+    if let SerialCMD::Reset | SerialCMD::EmergencyStop = cmd.cmd {
+        // the queued actuator commands were sent before the stop, they mustn't undo it
+        // they aren't answered, the host gives up on them once it sends the stop. everything else
+        // stays queued in order, not all of it is retransmitted
+        for _ in 0..CMD.len() {
+            let Ok(queued) = CMD.try_receive() else {
+                break;
+            };
+            if is_actuator(&queued.cmd) {
+                DROPPED_COMMANDS.fetch_add(1, Ordering::Relaxed);
+            } else {
+                // there's room, it was just taken out
+                let _ = CMD.try_send(queued);
+            }
+        }
+    }
+    // the host retransmits the reliable ones, newer commands supersede the rest anyway
+    if CMD.try_send(cmd).is_err() {
+        DROPPED_COMMANDS.fetch_add(1, Ordering::Relaxed);
+    }
+    CMD_HIGH_WATER.fetch_max(CMD.len() as u32, Ordering::Relaxed);
+}
 
-/// channel for outgoing messages
-pub static DATA: Channel<ThreadModeRawMutex, SerialData, 64> = Channel::new();
+/// the counters of the serial link
+pub fn stats() -> Stats {
+    let load = |counter: &AtomicU32| counter.load(Ordering::Relaxed);
+    Stats {
+        uptime: Instant::now().as_micros(),
+        dropped_data: load(&DROPPED_DATA),
+        dropped_commands: load(&DROPPED_COMMANDS),
+        decode_errors: load(&FRAMING_ERRORS),
+        data_high_water: load(&DATA_HIGH_WATER) as u16,
+        cmd_high_water: load(&CMD_HIGH_WATER) as u16,
+    }
+}
 
 #[embassy_executor::task]
 async fn usb_task(mut usb: UsbDevice<'static, Driver<'static, USB>>) -> ! {
@@ -53,12 +144,27 @@ async fn usb_task(mut usb: UsbDevice<'static, Driver<'static, USB>>) -> ! {
 
 #[embassy_executor::task]
 async fn usb_read_task(mut rx: Receiver<'static, Driver<'static, USB>>) {
-    let mut buf = [0u8; 64];
+    let mut buf = [0u8; MAX_PACKET_SIZE as usize];
+    let mut decoder = FrameDecoder::<MAX_FRAME_SIZE>::new();
 
     loop {
-        if let Ok(n) = rx.read_packet(&mut buf).await {
-            if let Ok(cmd) = from_bytes::<SerialCMD>(&buf[..n]) {
-                CMD.send(cmd).await;
+        let n = match rx.read_packet(&mut buf).await {
+            Ok(n) => n,
+            Err(_) => {
+                // the host went away, anything half received is garbage by now
+                rx.wait_connection().await;
+                decoder.reset();
+                continue;
+            }
+        };
+
+        for &b in &buf[..n] {
+            match decoder.push::<Command>(b) {
+                Some(Ok(cmd)) => queue_command(cmd),
+                Some(Err(_)) => {
+                    FRAMING_ERRORS.store(decoder.errors(), Ordering::Relaxed);
+                }
+                None => {}
             }
         }
     }
@@ -66,22 +172,62 @@ async fn usb_read_task(mut rx: Receiver<'static, Driver<'static, USB>>) {
 
 #[embassy_executor::task]
 async fn usb_write_task(mut tx: Sender<'static, Driver<'static, USB>>) {
-    let mut buf = [0u8; 64];
+    let mut buf = [0u8; MAX_FRAME_SIZE];
 
     loop {
-        let data = DATA.receive().await;
-        let _ = tx.write_packet(to_slice(&data, &mut buf).unwrap()).await;
+        let queued = DATA.len() + SENSOR_DATA.len();
+        DATA_HIGH_WATER.fetch_max(queued as u32, Ordering::Relaxed);
+
+        // acks and answers go before sensor data
+        let data = match select(DATA.receive(), SENSOR_DATA.receive()).await {
+            Either::First(data) | Either::Second(data) => data,
+        };
+        let Ok(n) = frame::encode(&data, &mut buf) else {
+            continue;
+        };
+
+        if write_transfer(&mut tx, &buf[..n]).await.is_err() {
+            // drop the frame and wait for the host to come back
+            tx.wait_connection().await;
+        }
     }
 }
 
+/// write `data` as a single USB transfer
+pub async fn write_transfer(
+    tx: &mut Sender<'static, Driver<'static, USB>>,
+    data: &[u8],
+) -> Result<(), EndpointError> {
+    // a frame can be larger than a single USB packet
+    for chunk in data.chunks(MAX_PACKET_SIZE as usize) {
+        tx.write_packet(chunk).await?;
+    }
+    // a full last packet doesn't terminate the transfer, so it needs to be followed by an empty one
+    if data.len() % MAX_PACKET_SIZE as usize == 0 {
+        tx.write_packet(&[]).await?;
+    }
+    Ok(())
+}
+
+/// the unique chip id in hex, so the host can tell multiple picos apart
+fn serial_number() -> &'static str {
+    static SERIAL: StaticCell<String<16>> = StaticCell::new();
+    let serial = SERIAL.init(String::new());
+    if let Ok(id) = otp::get_chipid() {
+        let _ = write!(serial, "{:016X}", id);
+    }
+    serial.as_str()
+}
+
 #[embassy_executor::task]
 pub async fn serial_init(peri_usb: Peri<'static, USB>, spawner: Spawner) {
     let driver = Driver::new(peri_usb, Irqs);
 
     let config = {
-        let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+        let mut config = embassy_usb::Config::new(USB_VID, USB_PID);
         config.manufacturer = Some("Kareszklub");
-        config.product = Some("Roland uC firmware");
+        config.product = Some(USB_PRODUCT);
+        config.serial_number = Some(serial_number());
         config.max_power = 100;
         config.max_packet_size_0 = 64;
         config
@@ -103,10 +249,17 @@ pub async fn serial_init(peri_usb: Peri<'static, USB>, spawner: Spawner) {
         builder
     };
 
+    // the order matters, these get the interface numbers the host looks for
+    // (`USB_CMD_INTERFACE` and `USB_LOG_INTERFACE`)
     let mut class = {
         static STATE: StaticCell<State> = StaticCell::new();
         let state = STATE.init(State::new());
-        CdcAcmClass::new(&mut builder, state, 64)
+        CdcAcmClass::new(&mut builder, state, MAX_PACKET_SIZE)
+    };
+    let log_class = {
+        static STATE: StaticCell<State> = StaticCell::new();
+        let state = STATE.init(State::new());
+        CdcAcmClass::new(&mut builder, state, MAX_PACKET_SIZE)
     };
 
     let usb = builder.build();
@@ -114,6 +267,10 @@ pub async fn serial_init(peri_usb: Peri<'static, USB>, spawner: Spawner) {
     // run the USB task
     spawner.spawn(usb_task(usb)).unwrap();
 
+    // nothing is ever read from the log port
+    let (log_tx, _) = log_class.split();
+    spawner.spawn(logger_task(log_tx)).unwrap();
+
     class.wait_connection().await;
     let (tx, rx) = class.split();
 
@@ -0,0 +1,60 @@
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_rp::Peri;
+use roland_protocol::{
+    frame::{self, FrameDecoder, MAX_FRAME_SIZE},
+    Calibration, Rejection,
+};
+
+/// size of the flash, has to match `memory.x`
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// the calibration lives in the last sector, which `memory.x` keeps out of the program's way
+const CALIBRATION_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+/// marks a sector that was written by us, an erased one reads as all ones
+const MAGIC: [u8; 4] = *b"RCAL";
+
+/// settings that survive a reboot
+pub struct Storage {
+    flash: Flash<'static, FLASH, Blocking, FLASH_SIZE>,
+}
+
+impl Storage {
+    pub fn new(flash: Peri<'static, FLASH>) -> Self {
+        Self {
+            flash: Flash::new_blocking(flash),
+        }
+    }
+
+    /// the saved calibration, if there's a valid one
+    pub fn load_calibration(&mut self) -> Option<Calibration> {
+        let mut buf = [0u8; MAGIC.len() + MAX_FRAME_SIZE];
+        self.flash
+            .blocking_read(CALIBRATION_OFFSET, &mut buf)
+            .ok()?;
+
+        // stored the same way it's sent, so it's protected by the same checksum
+        let mut decoder = FrameDecoder::<MAX_FRAME_SIZE>::new();
+        let calibration = buf
+            .strip_prefix(&MAGIC)?
+            .iter()
+            .find_map(|&b| decoder.push::<Calibration>(b))?
+            .ok()?;
+
+        calibration.validate().ok().map(|()| calibration)
+    }
+
+    /// NOTE: this blocks for a few tens of milliseconds while the sector is erased
+    pub fn save_calibration(&mut self, calibration: &Calibration) -> Result<(), Rejection> {
+        let mut buf = [0u8; MAGIC.len() + MAX_FRAME_SIZE];
+        buf[..MAGIC.len()].copy_from_slice(&MAGIC);
+        let n = frame::encode(calibration, &mut buf[MAGIC.len()..])
+            .map_err(|_| Rejection::StorageFailed)?;
+
+        self.flash
+            .blocking_erase(CALIBRATION_OFFSET, CALIBRATION_OFFSET + ERASE_SIZE as u32)
+            .map_err(|_| Rejection::StorageFailed)?;
+        self.flash
+            .blocking_write(CALIBRATION_OFFSET, &buf[..MAGIC.len() + n])
+            .map_err(|_| Rejection::StorageFailed)
+    }
+}
@@ -0,0 +1,143 @@
+//! COBS framing for the serial link
+//!
+//! every message is serialized with postcard, followed by a CRC-16 of the serialized bytes, COBS
+//! encoded and terminated with a `0` byte, so the receiving side can find message boundaries in an
+//! arbitrarily chunked byte stream and detect corrupted frames
+
+use crc::{CRC_16_IBM_SDLC, Crc};
+use serde::{Serialize, de::DeserializeOwned};
+
+/// largest serialized message (before COBS encoding) that can be sent or received
+pub const MAX_PAYLOAD_SIZE: usize = 256;
+
+const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+const CRC_SIZE: usize = 2;
+
+/// largest frame on the wire, including the CRC, the COBS overhead and the terminating `0`
+pub const MAX_FRAME_SIZE: usize = cobs::max_encoding_length(MAX_PAYLOAD_SIZE + CRC_SIZE) + 1;
+
+/// frame delimiter
+pub const SENTINEL: u8 = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// the message didn't fit in the buffer
+    Overflow,
+    /// the frame contained invalid COBS data
+    Cobs,
+    /// the checksum didn't match the contents of the frame
+    Crc,
+    /// the frame decoded fine, but couldn't be (de)serialized as the expected type
+    Postcard(postcard::Error),
+}
+
+impl core::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FrameError::Overflow => write!(f, "frame too large"),
+            FrameError::Cobs => write!(f, "invalid COBS encoding"),
+            FrameError::Crc => write!(f, "checksum mismatch"),
+            FrameError::Postcard(e) => write!(f, "postcard: {}", e),
+        }
+    }
+}
+
+/// serializes and frames `msg` into `out`, returning the number of bytes written (including the
+/// terminating `0`)
+pub fn encode<T: Serialize>(msg: &T, out: &mut [u8]) -> Result<usize, FrameError> {
+    let mut payload = [0u8; MAX_PAYLOAD_SIZE + CRC_SIZE];
+    let len = postcard::to_slice(msg, &mut payload[..MAX_PAYLOAD_SIZE])
+        .map_err(FrameError::Postcard)?
+        .len();
+
+    let crc = CRC.checksum(&payload[..len]);
+    payload[len..len + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+    let n = cobs::try_encode(&payload[..len + CRC_SIZE], out).map_err(|_| FrameError::Overflow)?;
+    *out.get_mut(n).ok_or(FrameError::Overflow)? = SENTINEL;
+
+    Ok(n + 1)
+}
+
+/// streaming frame decoder
+///
+/// bytes can be pushed in as they arrive, partial frames are buffered until their delimiter shows
+/// up. after any error, everything up to the next delimiter is discarded, so the decoder always
+/// resynchronizes on the next intact frame
+pub struct FrameDecoder<const N: usize = MAX_FRAME_SIZE> {
+    buf: [u8; N],
+    len: usize,
+    /// set when the current frame overflowed the buffer, cleared at the next delimiter
+    discard: bool,
+    errors: u32,
+}
+
+impl<const N: usize> Default for FrameDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            discard: false,
+            errors: 0,
+        }
+    }
+
+    /// push a single byte, returns a message once a complete frame was received
+    ///
+    /// empty frames (consecutive delimiters) are silently skipped
+    pub fn push<T: DeserializeOwned>(&mut self, byte: u8) -> Option<Result<T, FrameError>> {
+        if byte != SENTINEL {
+            if self.discard {
+                return None;
+            }
+            if self.len == N {
+                self.discard = true;
+                self.len = 0;
+                self.errors = self.errors.wrapping_add(1);
+                return Some(Err(FrameError::Overflow));
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.discard) || len == 0 {
+            return None;
+        }
+
+        let ret = match cobs::decode_in_place(&mut self.buf[..len]) {
+            Ok(n) if n > CRC_SIZE => {
+                let (payload, crc) = self.buf[..n].split_at(n - CRC_SIZE);
+                if CRC.checksum(payload).to_le_bytes() == crc {
+                    postcard::from_bytes(payload).map_err(FrameError::Postcard)
+                } else {
+                    Err(FrameError::Crc)
+                }
+            }
+            Ok(_) => Err(FrameError::Crc),
+            Err(_) => Err(FrameError::Cobs),
+        };
+        if ret.is_err() {
+            self.errors = self.errors.wrapping_add(1);
+        }
+        Some(ret)
+    }
+
+    /// number of frames dropped because of framing or decoding errors
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// drop any partially received frame
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.discard = false;
+    }
+}
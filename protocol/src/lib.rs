@@ -0,0 +1,1054 @@
+//! Serial protocol shared between the Roland host (`roland`) and the pico firmware (`roland-uc`)
+//!
+//! every type sent over the wire lives here, so both sides always agree on the postcard encoding
+//!
+//! the protocol only grows by appending enum variants, and every connection starts with a
+//! [`Hello`] exchange in which both sides check that they're compatible and agree on the
+//! [`Capabilities`] they can use
+
+#![no_std]
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+pub mod frame;
+
+/// version of the wire format
+/// bump this whenever a type in this crate changes in a way that affects its encoding (this
+/// includes adding, removing or reordering enum variants)
+pub const PROTOCOL_VERSION: u16 = 19;
+/// oldest protocol version this one can talk to
+///
+/// commands added since then must only be sent if the other side announced the matching
+/// capability, and bumping this is only needed when an existing type changes its encoding
+pub const MIN_PROTOCOL_VERSION: u16 = 19;
+
+/// USB vendor ID the firmware enumerates with
+pub const USB_VID: u16 = 0xc0de;
+/// USB product ID the firmware enumerates with
+pub const USB_PID: u16 = 0xcafe;
+/// USB product string the firmware enumerates with
+pub const USB_PRODUCT: &str = "Roland uC firmware";
+/// USB interface number of the serial port carrying commands and data
+pub const USB_CMD_INTERFACE: u8 = 0;
+/// USB interface number of the serial port carrying the firmware's log, as plain text
+/// `<LEVEL> <target>: <message>` lines
+pub const USB_LOG_INTERFACE: u8 = 2;
+
+/// watchdog timeout the firmware boots with (ms), see [`SerialCMD::Watchdog`]
+pub const DEFAULT_WATCHDOG_TIMEOUT: u16 = 500;
+/// shortest watchdog timeout the firmware accepts (ms)
+pub const MIN_WATCHDOG_TIMEOUT: u16 = 50;
+/// servo angle limits, in hundredths of a degree
+pub const SERVO_MIN_ANGLE: i16 = -9000;
+pub const SERVO_MAX_ANGLE: i16 = 9000;
+/// most notes a melody can have, see [`SerialCMD::Melody`]
+pub const MELODY_CAPACITY: usize = 256;
+
+/// pico time (µs since it booted)
+pub type Timestamp = u64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackSensorID {
+    L1,
+    L2,
+    R1,
+    R2,
+}
+
+impl TrackSensorID {
+    /// every sensor, in the order of their indices
+    pub const ALL: [Self; 4] = [Self::L1, Self::L2, Self::R1, Self::R2];
+}
+
+/// pico -> pi
+/// data packet coming from the pico, currently it's only used for sensor data
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SerialData {
+    /// capture time and measured distance in cm
+    UltraSensor((Timestamp, Option<u16>)),
+    /// capture time, sensor id and value
+    TrackSensor((Timestamp, TrackSensorID, bool)),
+    /// the command with this sequence number was applied
+    Ack(u16),
+    /// the command with this sequence number was refused, nothing was changed
+    Nack((u16, Rejection)),
+    /// no command arrived within the watchdog timeout, the motors were stopped and the servo and
+    /// buzzer were set to neutral
+    WatchdogTripped,
+    /// answer to [`SerialCMD::Sync`], with the same id and the time it was handled
+    Sync((u16, Timestamp)),
+    /// answer to [`SerialCMD::Query`], with the same id
+    Response((u16, Response)),
+    /// answer to [`SerialCMD::Hello`]
+    ///
+    /// the index and encoding of this variant must never change
+    Hello(Hello),
+    /// a [`SerialCMD::ServoMove`] finished, the time and the angle it reached (0.01°)
+    ///
+    /// moves replaced by another servo command before finishing aren't reported
+    ServoDone((Timestamp, i16)),
+    /// a measurement of the ultrasonic sensor, sent instead of [`SerialData::UltraSensor`] if the
+    /// host announced [`Capabilities::ULTRA_READINGS`]
+    Ultra(UltraReading),
+    /// the level of every track sensor, sent when the host connects, when it changes the
+    /// [`TrackConfig`] and periodically after that
+    ///
+    /// [`SerialData::TrackSensor`] is still sent on every change
+    Track(TrackSnapshot),
+    /// the firmware crashed before this boot, sent once after the next [`SerialCMD::Hello`] of a
+    /// host that announced [`Capabilities::FAULTS`]
+    Fault(Fault),
+}
+
+/// what brought the firmware down
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    Panic,
+    HardFault,
+}
+
+/// a crash of the firmware, kept in RAM over the reboot that follows it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// how long the firmware had been running (µs)
+    pub uptime: Timestamp,
+    /// the panic message, or the registers of a hard fault, cut off if it's too long
+    pub message: ShortString<{ Fault::MESSAGE_LEN }>,
+    /// where the panic happened
+    pub location: Option<FaultLocation>,
+}
+
+impl Fault {
+    pub const MESSAGE_LEN: usize = 128;
+}
+
+impl core::fmt::Display for Fault {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.kind {
+            FaultKind::Panic => write!(f, "panicked")?,
+            FaultKind::HardFault => write!(f, "hit a hard fault")?,
+        }
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        let ms = self.uptime / 1000;
+        write!(
+            f,
+            ": {} ({}.{:03}s after booting)",
+            self.message,
+            ms / 1000,
+            ms % 1000
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultLocation {
+    pub file: ShortString<{ FaultLocation::FILE_LEN }>,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl FaultLocation {
+    pub const FILE_LEN: usize = 64;
+}
+
+impl core::fmt::Display for FaultLocation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// a string of at most `N` (< 256) bytes that doesn't need an allocator, sent like a `&str`
+///
+/// anything that doesn't fit is cut off at a char boundary
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ShortString<const N: usize> {
+    len: u8,
+    buf: [u8; N],
+}
+
+impl<const N: usize> ShortString<N> {
+    pub const fn empty() -> Self {
+        const { assert!(N <= u8::MAX as usize) };
+        Self {
+            len: 0,
+            buf: [0; N],
+        }
+    }
+
+    pub fn new(s: &str) -> Self {
+        let mut string = Self::empty();
+        let _ = core::fmt::Write::write_str(&mut string, s);
+        string
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.buf
+            .get(..self.len as usize)
+            .and_then(|b| core::str::from_utf8(b).ok())
+            .unwrap_or_default()
+    }
+}
+
+impl<const N: usize> Default for ShortString<N> {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl<const N: usize> core::fmt::Write for ShortString<N> {
+    /// appends as much of `s` as fits, and fails if that isn't all of it
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let start = self.len as usize;
+        let mut end = s.len().min(N - start);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf[start..start + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end as u8;
+        if end == s.len() {
+            Ok(())
+        } else {
+            Err(core::fmt::Error)
+        }
+    }
+}
+
+impl<const N: usize> core::fmt::Display for ShortString<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for ShortString<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> Serialize for ShortString<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for ShortString<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor<const N: usize>;
+
+        impl<const N: usize> de::Visitor<'_> for Visitor<N> {
+            type Value = ShortString<N>;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                write!(f, "a string of at most {} bytes", N)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                if v.len() > N {
+                    return Err(E::invalid_length(v.len(), &self));
+                }
+                Ok(ShortString::new(v))
+            }
+        }
+
+        deserializer.deserialize_str(Visitor::<N>)
+    }
+}
+
+/// the debounced level of every track sensor at once
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackSnapshot {
+    pub time: Timestamp,
+    /// bit `n` is the sensor with index `n` in [`TrackSensorID::ALL`]
+    pub state: u8,
+}
+
+impl TrackSnapshot {
+    pub fn get(&self, id: TrackSensorID) -> bool {
+        self.state & (1 << id as u8) != 0
+    }
+}
+
+/// distances are in mm
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UltraReading {
+    /// when the measurement was triggered
+    pub time: Timestamp,
+    /// this measurement on its own, or why there's none
+    pub raw: Result<u16, UltraError>,
+    /// the output of the filter, `None` if recent measurements mostly failed
+    pub filtered: Option<u16>,
+}
+
+/// why the ultrasonic sensor couldn't measure a distance
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UltraError {
+    /// the pulse went out, but nothing reflected it
+    NoEcho,
+    /// the sensor didn't answer the trigger
+    Timeout,
+    /// the echo came from outside of [`UltraConfig::RANGE`]
+    OutOfRange,
+}
+
+impl core::fmt::Display for UltraError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            UltraError::NoEcho => write!(f, "no echo"),
+            UltraError::Timeout => write!(f, "sensor timed out"),
+            UltraError::OutOfRange => write!(f, "out of range"),
+        }
+    }
+}
+
+/// reason for refusing a command
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// one of the parameters is outside of its documented range
+    OutOfRange,
+    /// this firmware doesn't know how to handle the command
+    Unsupported,
+    /// writing the flash failed
+    StorageFailed,
+}
+
+impl core::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Rejection::OutOfRange => write!(f, "parameter out of range"),
+            Rejection::Unsupported => write!(f, "unsupported command"),
+            Rejection::StorageFailed => write!(f, "flash storage failed"),
+        }
+    }
+}
+
+/// pi -> pico
+/// every [`SerialCMD`] is sent wrapped in this
+///
+/// the encoding of this must never change, otherwise [`SerialCMD::Hello`] can't be understood
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Command {
+    /// if set, the pico answers with [`SerialData::Ack`] or [`SerialData::Nack`] after handling
+    /// the command
+    ///
+    /// commands are idempotent, so a retransmitted command is harmless even if the original was
+    /// applied and only its ack got lost. that doesn't keep them in order though, the host stops
+    /// retransmitting a command once a newer one for the same actuator went out, otherwise the
+    /// retransmission would undo it
+    pub seq: Option<u16>,
+    pub cmd: SerialCMD,
+}
+
+/// command packet for direct control of devices managed by the pico
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SerialCMD {
+    /// frequency (Hz)
+    Buzzer(u16),
+    /// RGB color (0 to 255)
+    LED((u8, u8, u8)),
+    /// rotation in degrees (0 is the midpoint, -90 to 90)
+    Servo(i8),
+    /// duty cycle (sign is direction)
+    HBridge((i32, i32)),
+    /// Reset the hardware peripherals to a neutral (known) state
+    ///
+    /// the host closes the serial connection after sending this
+    Reset,
+    /// does nothing, only keeps the watchdog from tripping
+    Heartbeat,
+    /// set the watchdog timeout (ms, at least [`MIN_WATCHDOG_TIMEOUT`]), 0 disables it
+    ///
+    /// the watchdog is armed by the first command after boot or a trip, and trips if no command
+    /// arrives within the timeout after that
+    Watchdog(u16),
+    /// ask for the pico's current time, used to map its timestamps to host time
+    Sync(u16),
+    /// ask the pico something, it answers with [`SerialData::Response`] carrying the same id
+    Query((u16, Query)),
+    /// the first command of every connection, the pico answers with [`SerialData::Hello`]
+    ///
+    /// the index and encoding of this variant must never change
+    Hello(Hello),
+    /// set several actuators at once, they're all applied together or not at all
+    /// requires [`Capabilities::BATCH`]
+    Batch(Batch),
+    /// start using this calibration, it's lost on reboot unless it's saved
+    /// requires [`Capabilities::CALIBRATION`]
+    SetCalibration(Calibration),
+    /// store the calibration in use in flash, so the pico boots with it
+    /// requires [`Capabilities::CALIBRATION`]
+    SaveCalibration,
+    /// stop everything and reboot, after acknowledging the command
+    ///
+    /// the serial connection drops, the host has to reconnect (or flash the firmware) afterwards
+    /// requires [`Capabilities::REBOOT`]
+    Reboot(RebootMode),
+    /// how fast the motors may change speed, [`SerialCMD::HBridge`] only sets where they're headed
+    /// requires [`Capabilities::MOTOR_CONTROL`]
+    MotorRamp(MotorRamp),
+    /// what the motors do at zero speed
+    /// requires [`Capabilities::MOTOR_CONTROL`]
+    StopMode(StopMode),
+    /// stop the motors right away, braking and skipping the ramp
+    ///
+    /// they stay braked for half a second, or until the next [`SerialCMD::HBridge`], after that
+    /// the [`SerialCMD::StopMode`] applies again
+    /// requires [`Capabilities::MOTOR_CONTROL`]
+    EmergencyStop,
+    /// move the servo smoothly, [`SerialData::ServoDone`] is sent once it gets there
+    /// requires [`Capabilities::SERVO_MOTION`]
+    ServoMove(ServoMove),
+    /// swing the servo back and forth until the next servo command
+    /// requires [`Capabilities::SERVO_MOTION`]
+    ServoSweep(ServoSweep),
+    /// stop sending pulses to the servo, so it stops holding its position (and jittering)
+    ///
+    /// the next servo command attaches it again
+    /// requires [`Capabilities::SERVO_MOTION`]
+    ServoDetach,
+    /// queue notes for the buzzer to play one after the other, [`SerialCMD::Buzzer`] stops it
+    /// requires [`Capabilities::MELODY`]
+    Melody(MelodyChunk),
+    /// run an effect on the RGB LED until the next LED command
+    /// requires [`Capabilities::LED_EFFECTS`]
+    LedEffect(LedEffect),
+    /// how often the ultrasonic sensor measures and how its measurements are filtered
+    /// requires [`Capabilities::ULTRA_READINGS`]
+    UltraConfig(UltraConfig),
+    /// debouncing of the track sensors and how often their snapshot is sent
+    /// requires [`Capabilities::TRACK_SNAPSHOTS`]
+    TrackConfig(TrackConfig),
+    /// what a sensor sends, streams the host isn't using can be turned off
+    ///
+    /// both sensors boot with [`StreamMode::Continuous`]
+    /// requires [`Capabilities::STREAMS`]
+    Stream((Sensor, StreamMode)),
+}
+
+/// angles are in hundredths of a degree, between [`SERVO_MIN_ANGLE`] and [`SERVO_MAX_ANGLE`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServoMove {
+    pub angle: i16,
+    pub motion: ServoMotion,
+}
+
+/// how a [`ServoMove`] gets to its angle
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServoMotion {
+    /// as fast as the servo can
+    Instant,
+    /// taking this long (ms), whatever the distance
+    Duration(u16),
+    /// at this speed (degrees per second, more than 0)
+    Speed(u16),
+}
+
+/// angles are in hundredths of a degree, `period` is the time of a full swing there and back (ms,
+/// more than 0)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServoSweep {
+    pub from: i16,
+    pub to: i16,
+    pub period: u16,
+}
+
+impl ServoMove {
+    pub fn validate(&self) -> Result<(), Rejection> {
+        let motion_valid = !matches!(self.motion, ServoMotion::Speed(0));
+        if valid_angle(self.angle) && motion_valid {
+            Ok(())
+        } else {
+            Err(Rejection::OutOfRange)
+        }
+    }
+
+    /// the angle rounded to whole degrees
+    pub fn degrees(&self) -> i8 {
+        ((self.angle + 50 * self.angle.signum()) / 100) as i8
+    }
+}
+
+impl ServoSweep {
+    pub fn validate(&self) -> Result<(), Rejection> {
+        if valid_angle(self.from) && valid_angle(self.to) && self.period > 0 {
+            Ok(())
+        } else {
+            Err(Rejection::OutOfRange)
+        }
+    }
+}
+
+/// a note played by the buzzer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Note {
+    /// frequency (Hz), 0 is a rest
+    pub freq: u16,
+    /// ms
+    pub duration: u16,
+    /// 0 is silent, 255 is the loudest
+    pub volume: u8,
+}
+
+/// the notes of a melody from `offset`, it gets split up like this to fit in a frame
+///
+/// a chunk with a new `id` replaces the melody being played and has to start at offset 0, the
+/// ones after it are appended, and chunks that were queued already are ignored, so a retransmitted
+/// chunk isn't played twice
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MelodyChunk {
+    pub id: u8,
+    /// position of the first note in the melody
+    pub offset: u16,
+    /// how many of `notes` are used
+    pub len: u8,
+    pub notes: [Note; MelodyChunk::LEN],
+}
+
+impl MelodyChunk {
+    /// most notes in a chunk
+    pub const LEN: usize = 16;
+
+    /// a chunk of the melody `id`, `notes` can't be longer than [`MelodyChunk::LEN`]
+    pub fn new(id: u8, offset: u16, notes: &[Note]) -> Self {
+        let mut chunk = Self {
+            id,
+            offset,
+            len: notes.len() as u8,
+            notes: [Note::default(); Self::LEN],
+        };
+        chunk.notes[..notes.len()].copy_from_slice(notes);
+        chunk
+    }
+
+    pub fn notes(&self) -> &[Note] {
+        &self.notes[..(self.len as usize).min(Self::LEN)]
+    }
+
+    pub fn validate(&self) -> Result<(), Rejection> {
+        let fits = self.len as usize <= Self::LEN
+            && self.offset as usize + self.len as usize <= MELODY_CAPACITY;
+        let notes_valid = self
+            .notes()
+            .iter()
+            .all(|n| n.freq == 0 || Calibration::PWM_FREQ_RANGE.contains(&n.freq));
+
+        if fits && notes_valid {
+            Ok(())
+        } else {
+            Err(Rejection::OutOfRange)
+        }
+    }
+}
+
+/// what the RGB LED shows, colors are RGB (0 to 255) and times are in ms
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedEffect {
+    Solid((u8, u8, u8)),
+    /// on for the first half of every period, off for the second
+    Blink {
+        color: (u8, u8, u8),
+        period: u16,
+    },
+    /// brightening then dimming, once every period
+    Breathe {
+        color: (u8, u8, u8),
+        period: u16,
+    },
+    /// through every hue, once every period
+    Rainbow {
+        period: u16,
+        brightness: u8,
+    },
+    /// from the color being shown to `color`, then staying there
+    Fade {
+        color: (u8, u8, u8),
+        duration: u16,
+    },
+}
+
+impl LedEffect {
+    pub fn validate(&self) -> Result<(), Rejection> {
+        match *self {
+            LedEffect::Blink { period, .. }
+            | LedEffect::Breathe { period, .. }
+            | LedEffect::Rainbow { period, .. }
+                if period == 0 =>
+            {
+                Err(Rejection::OutOfRange)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UltraConfig {
+    pub filter: UltraFilter,
+    /// time between measurements (ms), at least [`UltraConfig::MIN_PERIOD`]
+    pub period: u16,
+}
+
+/// how the filtered distance of an [`UltraReading`] is calculated
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UltraFilter {
+    /// the raw distance
+    None,
+    /// median of this many of the last measurements (1 to [`UltraConfig::MAX_WINDOW`]), failed
+    /// ones included
+    Median(u8),
+    /// exponential moving average, with this weight (1 to 100%) for a new measurement
+    Ema(u8),
+}
+
+impl UltraConfig {
+    pub const DEFAULT: Self = Self {
+        filter: UltraFilter::Median(5),
+        period: 80,
+    };
+    /// the echo of the previous measurement has to die down before the next one
+    pub const MIN_PERIOD: u16 = 60;
+    /// longest median filter
+    pub const MAX_WINDOW: u8 = 9;
+    /// distances the sensor can measure (mm)
+    pub const RANGE: core::ops::RangeInclusive<u16> = 20..=4000;
+
+    pub fn validate(&self) -> Result<(), Rejection> {
+        let filter_valid = match self.filter {
+            UltraFilter::None => true,
+            UltraFilter::Median(n) => (1..=Self::MAX_WINDOW).contains(&n),
+            UltraFilter::Ema(weight) => (1..=100).contains(&weight),
+        };
+
+        if filter_valid && self.period >= Self::MIN_PERIOD {
+            Ok(())
+        } else {
+            Err(Rejection::OutOfRange)
+        }
+    }
+}
+
+impl Default for UltraConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackConfig {
+    /// how long a sensor has to stay at a new level before the change is reported (µs), in the
+    /// order of [`TrackSensorID::ALL`], 0 reports every edge
+    pub debounce: [u16; 4],
+    /// time between [`SerialData::Track`] snapshots (ms), at least
+    /// [`TrackConfig::MIN_SNAPSHOT_PERIOD`], 0 only sends them when the host connects or changes
+    /// the config
+    pub snapshot_period: u16,
+}
+
+impl TrackConfig {
+    pub const DEFAULT: Self = Self {
+        debounce: [1000; 4],
+        snapshot_period: 250,
+    };
+    pub const MIN_SNAPSHOT_PERIOD: u16 = 20;
+
+    pub fn validate(&self) -> Result<(), Rejection> {
+        if self.snapshot_period == 0 || self.snapshot_period >= Self::MIN_SNAPSHOT_PERIOD {
+            Ok(())
+        } else {
+            Err(Rejection::OutOfRange)
+        }
+    }
+}
+
+impl Default for TrackConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// a sensor that streams its measurements to the host
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sensor {
+    Ultra,
+    Track,
+}
+
+/// when a sensor sends its measurements
+///
+/// the track sensors only measure changes, so for them [`StreamMode::Continuous`] and
+/// [`StreamMode::OnChange`] are the same, and [`StreamMode::Interval`] sends snapshots instead
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamMode {
+    /// nothing, and the sensor stops measuring if it can
+    Off,
+    /// every measurement
+    Continuous,
+    /// measurements whose (filtered) value differs from the last one sent
+    OnChange,
+    /// the latest measurement, at most this often (ms, more than 0)
+    Interval(u16),
+}
+
+impl StreamMode {
+    pub fn validate(&self) -> Result<(), Rejection> {
+        match self {
+            StreamMode::Interval(0) => Err(Rejection::OutOfRange),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn valid_angle(angle: i16) -> bool {
+    (SERVO_MIN_ANGLE..=SERVO_MAX_ANGLE).contains(&angle)
+}
+
+/// what the pico should boot into, see [`SerialCMD::Reboot`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootMode {
+    /// the firmware in flash, same as power cycling
+    Application,
+    /// the USB bootloader, same as holding BOOTSEL while plugging it in
+    ///
+    /// it shows up as a mass storage device that accepts UF2 images
+    Bootloader,
+}
+
+/// time (ms) it takes the motors to go from standing still to full speed (`accel`) and back
+/// (`decel`), 0 changes speed instantly
+///
+/// reversing decelerates to 0 first, then accelerates in the other direction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotorRamp {
+    pub accel: u16,
+    pub decel: u16,
+}
+
+impl MotorRamp {
+    /// what the firmware boots with
+    pub const DEFAULT: Self = Self {
+        accel: 200,
+        decel: 100,
+    };
+}
+
+impl Default for MotorRamp {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// how the H-bridge holds a motor at zero speed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StopMode {
+    /// both inputs low, the motor spins down freely
+    #[default]
+    Coast,
+    /// both inputs high, the motor is shorted and stops quickly
+    Brake,
+}
+
+/// new values for any subset of the actuators, `None` leaves that one alone
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Batch {
+    pub buzzer: Option<u16>,
+    pub led: Option<(u8, u8, u8)>,
+    /// hundredths of a degree, like [`ServoMove`]
+    pub servo: Option<i16>,
+    pub motor: Option<(i32, i32)>,
+}
+
+impl Batch {
+    pub fn validate(&self) -> Result<(), Rejection> {
+        match self.servo {
+            Some(angle) if !valid_angle(angle) => Err(Rejection::OutOfRange),
+            _ => Ok(()),
+        }
+    }
+
+    /// the same changes as separate commands
+    pub fn commands(self) -> impl Iterator<Item = SerialCMD> {
+        [
+            self.buzzer.map(SerialCMD::Buzzer),
+            self.led.map(SerialCMD::LED),
+            self.servo.map(|angle| {
+                SerialCMD::ServoMove(ServoMove {
+                    angle,
+                    motion: ServoMotion::Instant,
+                })
+            }),
+            self.motor.map(SerialCMD::HBridge),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// the protocol version and capabilities of one side of the link
+///
+/// the encoding of this must never change
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol: u16,
+    /// oldest protocol version this side can talk to
+    pub min_protocol: u16,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    /// the hello of this version of the crate
+    pub const fn new(capabilities: Capabilities) -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            min_protocol: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// can the two sides talk to each other
+    pub fn compatible(&self, other: &Hello) -> bool {
+        self.min_protocol.max(other.min_protocol) <= self.protocol.min(other.protocol)
+    }
+}
+
+/// optional protocol features, as a set of bit flags
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// [`Command::seq`] is answered with [`SerialData::Ack`] or [`SerialData::Nack`]
+    pub const RELIABLE: Self = Self(1 << 0);
+    /// [`SerialCMD::Watchdog`] and [`SerialCMD::Heartbeat`]
+    pub const WATCHDOG: Self = Self(1 << 1);
+    /// [`SerialCMD::Sync`]
+    pub const CLOCK_SYNC: Self = Self(1 << 2);
+    /// [`SerialCMD::Query`]
+    pub const QUERIES: Self = Self(1 << 3);
+    /// [`SerialCMD::Batch`]
+    pub const BATCH: Self = Self(1 << 4);
+    /// [`SerialCMD::SetCalibration`], [`SerialCMD::SaveCalibration`] and
+    /// [`Query::GetCalibration`]
+    pub const CALIBRATION: Self = Self(1 << 5);
+    /// [`SerialCMD::Reboot`]
+    pub const REBOOT: Self = Self(1 << 6);
+    /// [`SerialCMD::MotorRamp`], [`SerialCMD::StopMode`] and [`SerialCMD::EmergencyStop`]
+    pub const MOTOR_CONTROL: Self = Self(1 << 7);
+    /// [`SerialCMD::ServoMove`], [`SerialCMD::ServoSweep`], [`SerialCMD::ServoDetach`] and
+    /// [`SerialData::ServoDone`]
+    pub const SERVO_MOTION: Self = Self(1 << 8);
+    /// [`SerialCMD::Melody`]
+    pub const MELODY: Self = Self(1 << 9);
+    /// [`SerialCMD::LedEffect`]
+    pub const LED_EFFECTS: Self = Self(1 << 10);
+    /// [`SerialCMD::UltraConfig`] and [`SerialData::Ultra`]
+    pub const ULTRA_READINGS: Self = Self(1 << 11);
+    /// [`SerialCMD::TrackConfig`] and [`SerialData::Track`]
+    pub const TRACK_SNAPSHOTS: Self = Self(1 << 12);
+    /// [`SerialCMD::Stream`]
+    pub const STREAMS: Self = Self(1 << 13);
+    /// [`Query::GetStats`]
+    pub const STATS: Self = Self(1 << 14);
+    /// [`SerialData::Fault`]
+    pub const FAULTS: Self = Self(1 << 15);
+
+    /// name of every capability
+    pub const NAMES: &[(Self, &str)] = &[
+        (Self::RELIABLE, "reliable"),
+        (Self::WATCHDOG, "watchdog"),
+        (Self::CLOCK_SYNC, "clock-sync"),
+        (Self::QUERIES, "queries"),
+        (Self::BATCH, "batch"),
+        (Self::CALIBRATION, "calibration"),
+        (Self::REBOOT, "reboot"),
+        (Self::MOTOR_CONTROL, "motor-control"),
+        (Self::SERVO_MOTION, "servo-motion"),
+        (Self::MELODY, "melody"),
+        (Self::LED_EFFECTS, "led-effects"),
+        (Self::ULTRA_READINGS, "ultra-readings"),
+        (Self::TRACK_SNAPSHOTS, "track-snapshots"),
+        (Self::STREAMS, "streams"),
+        (Self::STATS, "stats"),
+        (Self::FAULTS, "faults"),
+    ];
+
+    /// every capability this version of the crate knows about
+    pub const fn all() -> Self {
+        let mut all = 0;
+        let mut i = 0;
+        while i < Self::NAMES.len() {
+            all |= Self::NAMES[i].0.0;
+            i += 1;
+        }
+        Self(all)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// the capabilities both sides have
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES
+            .iter()
+            .filter(move |(c, _)| self.contains(*c))
+            .map(|(_, name)| *name)
+    }
+}
+
+/// questions the host can ask the pico
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    /// answered with [`Response::Pong`], to check that the pico is alive and measure latency
+    Ping,
+    GetVersion,
+    GetActuatorState,
+    GetCalibration,
+    /// requires [`Capabilities::STATS`]
+    GetStats,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Response {
+    Pong,
+    Version(Version),
+    ActuatorState(ActuatorState),
+    Calibration(Calibration),
+    Stats(Stats),
+}
+
+/// build information of the firmware
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// [`PROTOCOL_VERSION`] the firmware was built with
+    pub protocol: u16,
+    /// crate version
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+    /// built with debug assertions
+    pub debug: bool,
+}
+
+impl core::fmt::Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "v{}.{}.{} ({}, protocol {})",
+            self.major,
+            self.minor,
+            self.patch,
+            if self.debug { "debug" } else { "release" },
+            self.protocol
+        )
+    }
+}
+
+/// counters of the firmware's serial link, since it booted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub uptime: Timestamp,
+    /// messages dropped because the host didn't read them fast enough, the oldest sensor data is
+    /// dropped first
+    pub dropped_data: u32,
+    /// commands dropped because too many were waiting, or because a stop overtook them
+    pub dropped_commands: u32,
+    /// incoming frames that couldn't be decoded
+    pub decode_errors: u32,
+    /// most outgoing frames waiting to be sent at once
+    pub data_high_water: u16,
+    /// most commands waiting to be handled at once
+    pub cmd_high_water: u16,
+}
+
+impl core::fmt::Display for Stats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let s = self.uptime / 1_000_000;
+        write!(
+            f,
+            "up {}h{:02}m{:02}s, dropped {} sensor frames and {} commands, {} decode errors, \
+             at most {} frames waiting to be sent and {} commands to be handled",
+            s / 3600,
+            s / 60 % 60,
+            s % 60,
+            self.dropped_data,
+            self.dropped_commands,
+            self.decode_errors,
+            self.data_high_water,
+            self.cmd_high_water
+        )
+    }
+}
+
+/// the last applied value of every actuator
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ActuatorState {
+    /// frequency (Hz)
+    pub buzzer: u16,
+    /// RGB color
+    pub led: (u8, u8, u8),
+    /// rotation in hundredths of a degree
+    pub servo: i16,
+    /// duty cycles
+    pub motor: (i32, i32),
+}
+
+/// everything that differs between robots, so the same firmware can drive all of them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Calibration {
+    /// servo pulse at -90°, 0° and 90°, as a fraction of the 50Hz PWM period (0 to 0xffff)
+    pub servo_min: u16,
+    pub servo_mid: u16,
+    pub servo_max: u16,
+    /// swap the ends of the servo's range
+    pub servo_reversed: bool,
+    /// RGB LED PWM frequency (Hz)
+    pub led_freq: u16,
+    /// the LED is common anode, so its channels are active low
+    pub led_inverted: bool,
+    /// H-bridge PWM frequency (Hz)
+    pub motor_freq: u16,
+    pub motor_left_reversed: bool,
+    pub motor_right_reversed: bool,
+}
+
+impl Calibration {
+    /// what the firmware uses if nothing was saved
+    pub const DEFAULT: Self = Self {
+        servo_min: 2100,
+        servo_mid: 4800,
+        servo_max: 8300,
+        servo_reversed: true,
+        led_freq: 2000,
+        led_inverted: false,
+        motor_freq: 2000,
+        motor_left_reversed: false,
+        motor_right_reversed: false,
+    };
+
+    /// PWM frequencies the hardware can produce (Hz)
+    pub const PWM_FREQ_RANGE: core::ops::RangeInclusive<u16> = 10..=50_000;
+
+    pub fn validate(&self) -> Result<(), Rejection> {
+        let servo_ordered = self.servo_min < self.servo_mid && self.servo_mid < self.servo_max;
+        let freqs_valid = [self.led_freq, self.motor_freq]
+            .iter()
+            .all(|f| Self::PWM_FREQ_RANGE.contains(f));
+
+        if servo_ordered && freqs_valid {
+            Ok(())
+        } else {
+            Err(Rejection::OutOfRange)
+        }
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
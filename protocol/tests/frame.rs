@@ -0,0 +1,117 @@
+use roland_protocol::{
+    SerialCMD, SerialData, TrackSensorID,
+    frame::{FrameDecoder, FrameError, MAX_FRAME_SIZE, encode},
+};
+
+fn framed<T: serde::Serialize>(msg: &T) -> Vec<u8> {
+    let mut buf = [0u8; MAX_FRAME_SIZE];
+    let n = encode(msg, &mut buf).unwrap();
+    buf[..n].to_vec()
+}
+
+fn decode_all<T: serde::de::DeserializeOwned>(
+    dec: &mut FrameDecoder,
+    bytes: &[u8],
+) -> Vec<Result<T, FrameError>> {
+    bytes.iter().filter_map(|&b| dec.push(b)).collect()
+}
+
+#[test]
+fn frame_has_single_trailing_delimiter() {
+    let frame = framed(&SerialCMD::HBridge((0, 0)));
+    assert_eq!(frame.last(), Some(&0));
+    assert!(!frame[..frame.len() - 1].contains(&0));
+}
+
+#[test]
+fn coalesced_frames() {
+    let msgs = [
+        SerialData::UltraSensor((10, Some(12))),
+        SerialData::TrackSensor((20, TrackSensorID::L2, true)),
+        SerialData::UltraSensor((30, None)),
+    ];
+    let bytes: Vec<u8> = msgs.iter().flat_map(framed).collect();
+
+    let mut dec = FrameDecoder::new();
+    let out: Vec<_> = decode_all::<SerialData>(&mut dec, &bytes)
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+
+    assert_eq!(out, msgs);
+    assert_eq!(dec.errors(), 0);
+}
+
+#[test]
+fn split_frames() {
+    let msg = SerialCMD::LED((1, 2, 3));
+    let bytes = framed(&msg);
+
+    let mut dec = FrameDecoder::new();
+    for chunk in bytes[..bytes.len() - 1].chunks(1) {
+        assert!(decode_all::<SerialCMD>(&mut dec, chunk).is_empty());
+    }
+    assert_eq!(
+        decode_all::<SerialCMD>(&mut dec, &bytes[bytes.len() - 1..]),
+        [Ok(msg)]
+    );
+}
+
+#[test]
+fn resync_after_garbage() {
+    let msg = SerialCMD::Servo(-45);
+
+    // a truncated frame from before the host connected, followed by an intact one
+    let mut bytes = framed(&SerialCMD::HBridge((1234, -1234)));
+    bytes.drain(..3);
+    bytes.extend(framed(&msg));
+
+    let mut dec = FrameDecoder::new();
+    let out = decode_all::<SerialCMD>(&mut dec, &bytes);
+
+    assert_eq!(out.len(), 2);
+    assert!(out[0].is_err());
+    assert_eq!(out[1], Ok(msg));
+    assert_eq!(dec.errors(), 1);
+}
+
+#[test]
+fn resync_after_overflow() {
+    let msg = SerialCMD::Buzzer(440);
+
+    let mut bytes = vec![0x55; 100];
+    bytes.push(0);
+    bytes.extend(framed(&msg));
+
+    let mut dec = FrameDecoder::<16>::new();
+    let out: Vec<_> = bytes
+        .iter()
+        .filter_map(|&b| dec.push::<SerialCMD>(b))
+        .collect();
+
+    assert_eq!(out, [Err(FrameError::Overflow), Ok(msg)]);
+    assert_eq!(dec.errors(), 1);
+}
+
+#[test]
+fn empty_frames_are_skipped() {
+    let mut dec = FrameDecoder::new();
+    assert!(decode_all::<SerialCMD>(&mut dec, &[0, 0, 0]).is_empty());
+    assert_eq!(dec.errors(), 0);
+}
+
+#[test]
+fn corrupted_frame_fails_crc() {
+    let msg = SerialCMD::HBridge((0x1234, 0x5678));
+    let mut bytes = framed(&msg);
+    bytes[2] ^= 0x01;
+    assert_ne!(bytes[2], 0);
+    bytes.extend(framed(&msg));
+
+    let mut dec = FrameDecoder::new();
+    assert_eq!(
+        decode_all::<SerialCMD>(&mut dec, &bytes),
+        [Err(FrameError::Crc), Ok(msg)]
+    );
+    assert_eq!(dec.errors(), 1);
+}
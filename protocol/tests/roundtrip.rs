@@ -0,0 +1,657 @@
+use postcard::{from_bytes, to_stdvec};
+use roland_protocol::{
+    ActuatorState, Batch, Calibration, Capabilities, Command, Fault, FaultKind, FaultLocation,
+    Hello, LedEffect, MELODY_CAPACITY, MIN_PROTOCOL_VERSION, MelodyChunk, MotorRamp, Note,
+    PROTOCOL_VERSION, Query, RebootMode, Rejection, Response, SERVO_MAX_ANGLE, SERVO_MIN_ANGLE,
+    Sensor, SerialCMD, SerialData, ServoMotion, ServoMove, ServoSweep, ShortString, Stats,
+    StopMode, StreamMode, TrackConfig, TrackSensorID, TrackSnapshot, UltraConfig, UltraError,
+    UltraFilter, UltraReading, Version, frame,
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+fn roundtrip<T>(value: T)
+where
+    T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+{
+    let bytes = to_stdvec(&value).unwrap();
+    assert_eq!(from_bytes::<T>(&bytes).unwrap(), value);
+}
+
+#[test]
+fn cmd_roundtrip() {
+    roundtrip(SerialCMD::Buzzer(440));
+    roundtrip(SerialCMD::LED((255, 128, 0)));
+    roundtrip(SerialCMD::Servo(-90));
+    roundtrip(SerialCMD::Servo(90));
+    roundtrip(SerialCMD::HBridge((-0xffff, 0xffff)));
+    roundtrip(SerialCMD::Reset);
+    roundtrip(SerialCMD::Heartbeat);
+    roundtrip(SerialCMD::Watchdog(500));
+    roundtrip(SerialCMD::Sync(3));
+    for q in [
+        Query::Ping,
+        Query::GetVersion,
+        Query::GetActuatorState,
+        Query::GetCalibration,
+        Query::GetStats,
+    ] {
+        roundtrip(SerialCMD::Query((u16::MAX, q)));
+    }
+    roundtrip(SerialCMD::Hello(Hello::new(Capabilities::all())));
+    roundtrip(SerialCMD::Batch(Batch::default()));
+    roundtrip(SerialCMD::Batch(Batch {
+        buzzer: Some(u16::MAX),
+        led: Some((255, 0, 128)),
+        servo: Some(SERVO_MIN_ANGLE),
+        motor: Some((-0xffff, 0xffff)),
+    }));
+    roundtrip(SerialCMD::Batch(Batch {
+        servo: Some(-4550),
+        ..Default::default()
+    }));
+    roundtrip(SerialCMD::SetCalibration(Calibration::DEFAULT));
+    roundtrip(SerialCMD::SaveCalibration);
+    roundtrip(SerialCMD::Reboot(RebootMode::Application));
+    roundtrip(SerialCMD::Reboot(RebootMode::Bootloader));
+    roundtrip(SerialCMD::MotorRamp(MotorRamp::DEFAULT));
+    roundtrip(SerialCMD::MotorRamp(MotorRamp {
+        accel: 0,
+        decel: u16::MAX,
+    }));
+    roundtrip(SerialCMD::StopMode(StopMode::Coast));
+    roundtrip(SerialCMD::StopMode(StopMode::Brake));
+    roundtrip(SerialCMD::EmergencyStop);
+    for motion in [
+        ServoMotion::Instant,
+        ServoMotion::Duration(u16::MAX),
+        ServoMotion::Speed(60),
+    ] {
+        roundtrip(SerialCMD::ServoMove(ServoMove {
+            angle: SERVO_MIN_ANGLE,
+            motion,
+        }));
+    }
+    roundtrip(SerialCMD::ServoSweep(ServoSweep {
+        from: SERVO_MIN_ANGLE,
+        to: SERVO_MAX_ANGLE,
+        period: 2000,
+    }));
+    roundtrip(SerialCMD::ServoDetach);
+    roundtrip(SerialCMD::Melody(MelodyChunk::new(
+        7,
+        16,
+        &[
+            Note {
+                freq: 440,
+                duration: 250,
+                volume: 255,
+            },
+            Note {
+                freq: 0,
+                duration: 125,
+                volume: 0,
+            },
+        ],
+    )));
+    for effect in [
+        LedEffect::Solid((1, 2, 3)),
+        LedEffect::Blink {
+            color: (255, 0, 0),
+            period: 500,
+        },
+        LedEffect::Breathe {
+            color: (0, 0, 255),
+            period: 4000,
+        },
+        LedEffect::Rainbow {
+            period: 3000,
+            brightness: 128,
+        },
+        LedEffect::Fade {
+            color: (0, 255, 0),
+            duration: 0,
+        },
+    ] {
+        roundtrip(SerialCMD::LedEffect(effect));
+    }
+    for filter in [
+        UltraFilter::None,
+        UltraFilter::Median(UltraConfig::MAX_WINDOW),
+        UltraFilter::Ema(30),
+    ] {
+        roundtrip(SerialCMD::UltraConfig(UltraConfig {
+            filter,
+            period: u16::MAX,
+        }));
+    }
+    roundtrip(SerialCMD::TrackConfig(TrackConfig::DEFAULT));
+    roundtrip(SerialCMD::TrackConfig(TrackConfig {
+        debounce: [0, 1, 500, u16::MAX],
+        snapshot_period: 0,
+    }));
+    for mode in [
+        StreamMode::Off,
+        StreamMode::Continuous,
+        StreamMode::OnChange,
+        StreamMode::Interval(u16::MAX),
+    ] {
+        roundtrip(SerialCMD::Stream((Sensor::Ultra, mode)));
+        roundtrip(SerialCMD::Stream((Sensor::Track, mode)));
+    }
+}
+
+#[test]
+fn command_roundtrip() {
+    roundtrip(Command {
+        seq: None,
+        cmd: SerialCMD::Servo(10),
+    });
+    roundtrip(Command {
+        seq: Some(u16::MAX),
+        cmd: SerialCMD::HBridge((1, -1)),
+    });
+}
+
+#[test]
+fn data_roundtrip() {
+    roundtrip(SerialData::UltraSensor((0, Some(400))));
+    roundtrip(SerialData::UltraSensor((u64::MAX, None)));
+    for id in [
+        TrackSensorID::L1,
+        TrackSensorID::L2,
+        TrackSensorID::R1,
+        TrackSensorID::R2,
+    ] {
+        roundtrip(SerialData::TrackSensor((1_000_000, id, true)));
+        roundtrip(SerialData::TrackSensor((1_000_001, id, false)));
+    }
+    roundtrip(SerialData::Ack(7));
+    roundtrip(SerialData::Nack((8, Rejection::OutOfRange)));
+    roundtrip(SerialData::Nack((9, Rejection::Unsupported)));
+    roundtrip(SerialData::Nack((10, Rejection::StorageFailed)));
+    roundtrip(SerialData::WatchdogTripped);
+    roundtrip(SerialData::Sync((3, 123_456_789)));
+    roundtrip(SerialData::Response((1, Response::Pong)));
+    roundtrip(SerialData::Response((
+        2,
+        Response::Version(Version {
+            protocol: PROTOCOL_VERSION,
+            major: 0,
+            minor: 1,
+            patch: 0,
+            debug: true,
+        }),
+    )));
+    roundtrip(SerialData::Response((
+        3,
+        Response::ActuatorState(ActuatorState {
+            buzzer: 440,
+            led: (1, 2, 3),
+            servo: -4550,
+            motor: (-0xffff, 0xffff),
+        }),
+    )));
+    roundtrip(SerialData::Response((
+        4,
+        Response::Calibration(Calibration {
+            servo_min: 0,
+            servo_max: u16::MAX,
+            led_inverted: true,
+            ..Calibration::DEFAULT
+        }),
+    )));
+    roundtrip(SerialData::Response((
+        5,
+        Response::Stats(Stats {
+            uptime: u64::MAX,
+            dropped_data: u32::MAX,
+            decode_errors: 1,
+            cmd_high_water: 64,
+            ..Stats::default()
+        }),
+    )));
+    roundtrip(SerialData::Hello(Hello::new(Capabilities::default())));
+    roundtrip(SerialData::ServoDone((u64::MAX, SERVO_MIN_ANGLE)));
+    for (raw, filtered) in [
+        (Ok(4000), Some(20)),
+        (Err(UltraError::NoEcho), Some(1234)),
+        (Err(UltraError::OutOfRange), None),
+    ] {
+        roundtrip(SerialData::Ultra(UltraReading {
+            time: u64::MAX,
+            raw,
+            filtered,
+        }));
+    }
+    roundtrip(SerialData::Track(TrackSnapshot {
+        time: u64::MAX,
+        state: 0b1010,
+    }));
+    roundtrip(SerialData::Fault(Fault {
+        kind: FaultKind::Panic,
+        uptime: u64::MAX,
+        message: ShortString::new("attempt to add with overflow"),
+        location: Some(FaultLocation {
+            file: ShortString::new("src/drivers/h_bridge.rs"),
+            line: 123,
+            column: 45,
+        }),
+    }));
+    roundtrip(SerialData::Fault(Fault {
+        kind: FaultKind::HardFault,
+        uptime: 0,
+        message: ShortString::new("pc 0x10001234"),
+        location: None,
+    }));
+}
+
+/// postcard encodes enum variants by index, so reordering variants breaks firmware that was built
+/// before the change, these pin the discriminants to catch that
+#[test]
+fn variant_indices() {
+    assert_eq!(to_stdvec(&SerialCMD::Buzzer(0)).unwrap()[0], 0);
+    assert_eq!(to_stdvec(&SerialCMD::LED((0, 0, 0))).unwrap()[0], 1);
+    assert_eq!(to_stdvec(&SerialCMD::Servo(0)).unwrap()[0], 2);
+    assert_eq!(to_stdvec(&SerialCMD::HBridge((0, 0))).unwrap()[0], 3);
+    assert_eq!(to_stdvec(&SerialCMD::Reset).unwrap(), [4]);
+    assert_eq!(to_stdvec(&SerialCMD::Heartbeat).unwrap(), [5]);
+    assert_eq!(to_stdvec(&SerialCMD::Watchdog(1)).unwrap(), [6, 1]);
+    assert_eq!(to_stdvec(&SerialCMD::Sync(1)).unwrap(), [7, 1]);
+    assert_eq!(
+        to_stdvec(&SerialCMD::Query((1, Query::GetActuatorState))).unwrap(),
+        [8, 1, 2]
+    );
+    assert_eq!(
+        to_stdvec(&SerialCMD::Batch(Batch::default())).unwrap(),
+        [10, 0, 0, 0, 0]
+    );
+    assert_eq!(to_stdvec(&SerialCMD::SaveCalibration).unwrap(), [12]);
+    assert_eq!(
+        to_stdvec(&SerialCMD::Reboot(RebootMode::Bootloader)).unwrap(),
+        [13, 1]
+    );
+    assert_eq!(
+        to_stdvec(&SerialCMD::StopMode(StopMode::Brake)).unwrap(),
+        [15, 1]
+    );
+    assert_eq!(to_stdvec(&SerialCMD::EmergencyStop).unwrap(), [16]);
+    assert_eq!(to_stdvec(&SerialCMD::ServoDetach).unwrap(), [19]);
+    assert_eq!(
+        to_stdvec(&SerialCMD::Melody(MelodyChunk::new(0, 0, &[]))).unwrap()[0],
+        20
+    );
+    assert_eq!(
+        to_stdvec(&SerialCMD::LedEffect(LedEffect::Solid((0, 0, 0)))).unwrap(),
+        [21, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        to_stdvec(&SerialCMD::UltraConfig(UltraConfig {
+            filter: UltraFilter::None,
+            period: 60
+        }))
+        .unwrap(),
+        [22, 0, 60]
+    );
+    assert_eq!(
+        to_stdvec(&SerialData::Ultra(UltraReading {
+            time: 1,
+            raw: Err(UltraError::Timeout),
+            filtered: None
+        }))
+        .unwrap(),
+        [9, 1, 1, 1, 0]
+    );
+    assert_eq!(
+        to_stdvec(&SerialCMD::TrackConfig(TrackConfig {
+            debounce: [0; 4],
+            snapshot_period: 0
+        }))
+        .unwrap(),
+        [23, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        to_stdvec(&SerialCMD::Stream((Sensor::Track, StreamMode::Interval(1)))).unwrap(),
+        [24, 1, 3, 1]
+    );
+    assert_eq!(
+        to_stdvec(&SerialCMD::Query((1, Query::GetStats))).unwrap(),
+        [8, 1, 4]
+    );
+    assert_eq!(
+        to_stdvec(&SerialData::Response((
+            1,
+            Response::Stats(Stats::default())
+        )))
+        .unwrap(),
+        [6, 1, 4, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        to_stdvec(&SerialData::Track(TrackSnapshot { time: 1, state: 2 })).unwrap(),
+        [10, 1, 2]
+    );
+    assert_eq!(
+        to_stdvec(&SerialData::Fault(Fault {
+            kind: FaultKind::HardFault,
+            uptime: 1,
+            message: ShortString::new("x"),
+            location: None,
+        }))
+        .unwrap(),
+        [11, 1, 1, 1, b'x', 0]
+    );
+
+    assert_eq!(
+        to_stdvec(&SerialData::UltraSensor((1, None))).unwrap(),
+        [0, 1, 0]
+    );
+    assert_eq!(
+        to_stdvec(&SerialData::TrackSensor((1, TrackSensorID::R2, true))).unwrap(),
+        [1, 1, 3, 1]
+    );
+    assert_eq!(to_stdvec(&SerialData::Ack(1)).unwrap(), [2, 1]);
+    assert_eq!(
+        to_stdvec(&SerialData::Nack((1, Rejection::Unsupported))).unwrap(),
+        [3, 1, 1]
+    );
+    assert_eq!(to_stdvec(&SerialData::WatchdogTripped).unwrap(), [4]);
+    assert_eq!(to_stdvec(&SerialData::Sync((1, 2))).unwrap(), [5, 1, 2]);
+    assert_eq!(
+        to_stdvec(&SerialData::Response((1, Response::Pong))).unwrap(),
+        [6, 1, 0]
+    );
+    assert_eq!(
+        to_stdvec(&SerialData::ServoDone((1, 1))).unwrap(),
+        [8, 1, 2]
+    );
+}
+
+/// the hello exchange has to be understood by every version, so its encoding is pinned completely
+#[test]
+fn hello_encoding() {
+    let hello = Hello {
+        protocol: 1,
+        min_protocol: 2,
+        capabilities: Capabilities(3),
+    };
+    assert_eq!(
+        to_stdvec(&Command {
+            seq: None,
+            cmd: SerialCMD::Hello(hello)
+        })
+        .unwrap(),
+        [0, 9, 1, 2, 3]
+    );
+    assert_eq!(to_stdvec(&SerialData::Hello(hello)).unwrap(), [7, 1, 2, 3]);
+}
+
+#[test]
+fn compatibility() {
+    let hello = |protocol, min_protocol| Hello {
+        protocol,
+        min_protocol,
+        capabilities: Capabilities::default(),
+    };
+    assert!(hello(6, 6).compatible(&hello(6, 6)));
+    assert!(hello(8, 6).compatible(&hello(6, 6)));
+    assert!(hello(6, 6).compatible(&hello(8, 6)));
+    assert!(!hello(8, 7).compatible(&hello(6, 6)));
+    assert!(!hello(6, 6).compatible(&hello(8, 7)));
+    assert!(
+        Hello::new(Capabilities::all()).compatible(&hello(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION))
+    );
+}
+
+#[test]
+fn capabilities() {
+    let all = Capabilities::all();
+    for (c, _) in Capabilities::NAMES {
+        assert!(all.contains(*c));
+        assert_eq!(c.0.count_ones(), 1);
+    }
+    assert_eq!(all.names().count(), Capabilities::NAMES.len());
+
+    let some = Capabilities(Capabilities::RELIABLE.0 | Capabilities::QUERIES.0);
+    assert_eq!(
+        some.intersection(Capabilities::RELIABLE),
+        Capabilities::RELIABLE
+    );
+    assert!(!some.contains(Capabilities::WATCHDOG));
+    assert_eq!(some.names().collect::<Vec<_>>(), ["reliable", "queries"]);
+}
+
+#[test]
+fn batch_commands() {
+    assert_eq!(Batch::default().commands().count(), 0);
+
+    let batch = Batch {
+        led: Some((1, 2, 3)),
+        motor: Some((4, 5)),
+        ..Default::default()
+    };
+    assert_eq!(
+        batch.commands().collect::<Vec<_>>(),
+        [SerialCMD::LED((1, 2, 3)), SerialCMD::HBridge((4, 5))]
+    );
+
+    // the servo keeps its hundredths of a degree
+    let batch = Batch {
+        servo: Some(1234),
+        ..Default::default()
+    };
+    assert_eq!(batch.validate(), Ok(()));
+    assert_eq!(
+        batch.commands().collect::<Vec<_>>(),
+        [SerialCMD::ServoMove(ServoMove {
+            angle: 1234,
+            motion: ServoMotion::Instant,
+        })]
+    );
+    let batch = Batch {
+        servo: Some(SERVO_MAX_ANGLE + 1),
+        ..Default::default()
+    };
+    assert_eq!(batch.validate(), Err(Rejection::OutOfRange));
+}
+
+#[test]
+fn calibration_validation() {
+    assert_eq!(Calibration::DEFAULT.validate(), Ok(()));
+
+    let invalid = [
+        Calibration {
+            servo_mid: Calibration::DEFAULT.servo_max,
+            ..Calibration::DEFAULT
+        },
+        Calibration {
+            servo_min: Calibration::DEFAULT.servo_max + 1,
+            ..Calibration::DEFAULT
+        },
+        Calibration {
+            led_freq: 0,
+            ..Calibration::DEFAULT
+        },
+        Calibration {
+            motor_freq: u16::MAX,
+            ..Calibration::DEFAULT
+        },
+    ];
+    for c in invalid {
+        assert_eq!(c.validate(), Err(Rejection::OutOfRange), "{:?}", c);
+    }
+}
+
+#[test]
+fn servo_validation() {
+    let to = |angle, motion| ServoMove { angle, motion };
+    assert_eq!(
+        to(SERVO_MAX_ANGLE, ServoMotion::Speed(1)).validate(),
+        Ok(())
+    );
+    assert_eq!(
+        to(SERVO_MAX_ANGLE + 1, ServoMotion::Instant).validate(),
+        Err(Rejection::OutOfRange)
+    );
+    assert_eq!(
+        to(0, ServoMotion::Speed(0)).validate(),
+        Err(Rejection::OutOfRange)
+    );
+    assert_eq!(to(-4550, ServoMotion::Instant).degrees(), -46);
+    assert_eq!(to(4549, ServoMotion::Instant).degrees(), 45);
+
+    let sweep = |from, to, period| ServoSweep { from, to, period };
+    assert_eq!(sweep(-4500, 4500, 1000).validate(), Ok(()));
+    assert_eq!(sweep(-4500, 4500, 0).validate(), Err(Rejection::OutOfRange));
+    assert_eq!(
+        sweep(SERVO_MIN_ANGLE - 1, 0, 1000).validate(),
+        Err(Rejection::OutOfRange)
+    );
+}
+
+#[test]
+fn melody_chunks() {
+    let loudest = Note {
+        freq: u16::MAX,
+        duration: u16::MAX,
+        volume: u8::MAX,
+    };
+    let chunk = MelodyChunk::new(u8::MAX, u16::MAX, &[loudest; MelodyChunk::LEN]);
+    assert_eq!(chunk.notes().len(), MelodyChunk::LEN);
+
+    // the largest chunk still fits in a frame
+    let cmd = Command {
+        seq: Some(u16::MAX),
+        cmd: SerialCMD::Melody(chunk),
+    };
+    let mut buf = [0u8; frame::MAX_FRAME_SIZE];
+    assert!(frame::encode(&cmd, &mut buf).is_ok());
+
+    let note = |freq| Note {
+        freq,
+        duration: 100,
+        volume: 128,
+    };
+    assert_eq!(
+        MelodyChunk::new(0, 0, &[note(0), note(440)]).validate(),
+        Ok(())
+    );
+    assert_eq!(
+        MelodyChunk::new(0, 0, &[note(5)]).validate(),
+        Err(Rejection::OutOfRange)
+    );
+    let last = (MELODY_CAPACITY - 1) as u16;
+    assert_eq!(MelodyChunk::new(0, last, &[note(440)]).validate(), Ok(()));
+    assert_eq!(
+        MelodyChunk::new(0, last, &[note(440); 2]).validate(),
+        Err(Rejection::OutOfRange)
+    );
+}
+
+#[test]
+fn led_effect_validation() {
+    let color = (255, 255, 255);
+    assert_eq!(LedEffect::Solid(color).validate(), Ok(()));
+    assert_eq!(LedEffect::Fade { color, duration: 0 }.validate(), Ok(()));
+    assert_eq!(LedEffect::Blink { color, period: 1 }.validate(), Ok(()));
+    assert_eq!(
+        LedEffect::Breathe { color, period: 0 }.validate(),
+        Err(Rejection::OutOfRange)
+    );
+    assert_eq!(
+        LedEffect::Rainbow {
+            period: 0,
+            brightness: 255
+        }
+        .validate(),
+        Err(Rejection::OutOfRange)
+    );
+}
+
+#[test]
+fn ultra_config_validation() {
+    let config = |filter, period| UltraConfig { filter, period };
+    assert_eq!(UltraConfig::DEFAULT.validate(), Ok(()));
+    assert_eq!(config(UltraFilter::None, 60).validate(), Ok(()));
+    assert_eq!(
+        config(UltraFilter::None, UltraConfig::MIN_PERIOD - 1).validate(),
+        Err(Rejection::OutOfRange)
+    );
+    assert_eq!(
+        config(UltraFilter::Median(0), 100).validate(),
+        Err(Rejection::OutOfRange)
+    );
+    assert_eq!(
+        config(UltraFilter::Median(UltraConfig::MAX_WINDOW + 1), 100).validate(),
+        Err(Rejection::OutOfRange)
+    );
+    assert_eq!(config(UltraFilter::Ema(100), 100).validate(), Ok(()));
+    assert_eq!(
+        config(UltraFilter::Ema(101), 100).validate(),
+        Err(Rejection::OutOfRange)
+    );
+}
+
+#[test]
+fn track_snapshots() {
+    let snapshot = TrackSnapshot {
+        time: 0,
+        state: 0b1001,
+    };
+    let levels = TrackSensorID::ALL.map(|id| snapshot.get(id));
+    assert_eq!(levels, [true, false, false, true]);
+    for (i, id) in TrackSensorID::ALL.into_iter().enumerate() {
+        assert_eq!(id as usize, i);
+    }
+}
+
+#[test]
+fn track_config_validation() {
+    let config = |snapshot_period| TrackConfig {
+        debounce: [u16::MAX; 4],
+        snapshot_period,
+    };
+    assert_eq!(TrackConfig::DEFAULT.validate(), Ok(()));
+    assert_eq!(config(0).validate(), Ok(()));
+    assert_eq!(config(TrackConfig::MIN_SNAPSHOT_PERIOD).validate(), Ok(()));
+    assert_eq!(
+        config(TrackConfig::MIN_SNAPSHOT_PERIOD - 1).validate(),
+        Err(Rejection::OutOfRange)
+    );
+}
+
+#[test]
+fn stream_mode_validation() {
+    assert_eq!(StreamMode::Off.validate(), Ok(()));
+    assert_eq!(StreamMode::OnChange.validate(), Ok(()));
+    assert_eq!(StreamMode::Interval(1).validate(), Ok(()));
+    assert_eq!(
+        StreamMode::Interval(0).validate(),
+        Err(Rejection::OutOfRange)
+    );
+}
+
+#[test]
+fn short_strings() {
+    let s = ShortString::<4>::new("abcdef");
+    assert_eq!(s.as_str(), "abcd");
+    // never cut in the middle of a char
+    assert_eq!(ShortString::<4>::new("abcé").as_str(), "abc");
+    assert_eq!(ShortString::<4>::default().as_str(), "");
+
+    // encoded like a &str
+    assert_eq!(to_stdvec(&s).unwrap(), to_stdvec("abcd").unwrap());
+    assert!(from_bytes::<ShortString<3>>(&to_stdvec("abcd").unwrap()).is_err());
+
+    // the longest fault still fits into a frame
+    let fault = SerialData::Fault(Fault {
+        kind: FaultKind::Panic,
+        uptime: u64::MAX,
+        message: ShortString::new(&"m".repeat(Fault::MESSAGE_LEN)),
+        location: Some(FaultLocation {
+            file: ShortString::new(&"f".repeat(FaultLocation::FILE_LEN)),
+            line: u32::MAX,
+            column: u32::MAX,
+        }),
+    });
+    let mut buf = [0; frame::MAX_FRAME_SIZE];
+    assert!(frame::encode(&fault, &mut buf).is_ok());
+}
@@ -0,0 +1,49 @@
+use roland_protocol::{Batch, SerialCMD};
+
+use crate::backend::{Backend, centidegrees};
+
+/// collects changes to several actuators, and sends them as a single command that the pico
+/// applies all at once
+/// see [`Backend::batch`]
+#[must_use = "nothing is sent until `send` is called"]
+pub struct BatchBuilder<'a, B: Backend> {
+    backend: &'a B,
+    batch: Batch,
+}
+
+impl<'a, B: Backend> BatchBuilder<'a, B> {
+    pub fn new(backend: &'a B) -> Self {
+        Self {
+            backend,
+            batch: Batch::default(),
+        }
+    }
+
+    /// see [`Backend::set_buzzer`]
+    pub fn buzzer(mut self, freq: u16) -> Self {
+        self.batch.buzzer = Some(freq);
+        self
+    }
+
+    /// see [`Backend::set_led`]
+    pub fn led(mut self, r: u8, g: u8, b: u8) -> Self {
+        self.batch.led = Some((r, g, b));
+        self
+    }
+
+    /// see [`Backend::set_servo`]
+    pub fn servo(mut self, deg: f32) -> Self {
+        self.batch.servo = Some(centidegrees(deg));
+        self
+    }
+
+    /// see [`Backend::set_motor`]
+    pub fn motor(mut self, left: i32, right: i32) -> Self {
+        self.batch.motor = Some((left, right));
+        self
+    }
+
+    pub async fn send(self) -> anyhow::Result<()> {
+        self.backend.command(SerialCMD::Batch(self.batch)).await
+    }
+}
@@ -0,0 +1,129 @@
+use std::{collections::VecDeque, time::Duration};
+
+use roland_protocol::Timestamp;
+use tokio::time::Instant;
+
+/// number of recent sync samples the estimate is based on
+const WINDOW: usize = 8;
+
+/// maps pico timestamps to host time
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    /// a host instant and the pico time at that instant
+    host: Instant,
+    pico: Timestamp,
+}
+
+impl Clock {
+    pub fn to_host(self, t: Timestamp) -> Instant {
+        if t >= self.pico {
+            self.host + Duration::from_micros(t - self.pico)
+        } else {
+            let before = Duration::from_micros(self.pico - t);
+            self.host.checked_sub(before).unwrap_or(self.host)
+        }
+    }
+}
+
+struct Sample {
+    round_trip: Duration,
+    clock: Clock,
+}
+
+/// estimates a [`Clock`] from sync round trips
+///
+/// the pico's answer is assumed to be captured halfway through the round trip, which is the most
+/// accurate for the shortest round trips, so the best of the last few samples is used
+#[derive(Default)]
+pub struct ClockSync {
+    samples: VecDeque<Sample>,
+}
+
+impl ClockSync {
+    /// add a sync answer with pico time `pico`, to a request sent at `sent` and answered at
+    /// `received`, returns the new best estimate
+    pub fn sample(&mut self, sent: Instant, received: Instant, pico: Timestamp) -> Clock {
+        let round_trip = received - sent;
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            round_trip,
+            clock: Clock {
+                host: sent + round_trip / 2,
+                pico,
+            },
+        });
+
+        self.samples
+            .iter()
+            .min_by_key(|s| s.round_trip)
+            .map(|s| s.clock)
+            .unwrap()
+    }
+
+    /// forget every sample, eg. because the pico might have rebooted
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn to_host() {
+        let base = Instant::now();
+        let clock = ClockSync::default().sample(base, base + ms(10), 1_000_000);
+        // the answer is assumed to be from halfway through the round trip
+        assert_eq!(clock.to_host(1_000_000), base + ms(5));
+        assert_eq!(clock.to_host(1_002_000), base + ms(7));
+        // before the reference point
+        assert_eq!(clock.to_host(999_000), base + ms(4));
+        assert_eq!(clock.to_host(995_000), base);
+    }
+
+    #[test]
+    fn shortest_round_trip_wins() {
+        let base = Instant::now();
+        let mut sync = ClockSync::default();
+        sync.sample(base, base + ms(10), 1_000);
+        let best = sync.sample(base + ms(100), base + ms(102), 100_000);
+        let clock = sync.sample(base + ms(200), base + ms(206), 200_000);
+        assert_eq!((clock.host, clock.pico), (best.host, best.pico));
+        assert_eq!(clock.to_host(100_000), base + ms(101));
+    }
+
+    #[test]
+    fn old_samples_are_evicted() {
+        let base = Instant::now();
+        let mut sync = ClockSync::default();
+        let best = sync.sample(base, base + ms(1), 0);
+        for i in 1..WINDOW as u64 {
+            let sent = base + ms(100 * i);
+            let clock = sync.sample(sent, sent + ms(5), 100_000 * i);
+            assert_eq!((clock.host, clock.pico), (best.host, best.pico));
+        }
+
+        // the best sample just dropped out of the window, the oldest of the rest is next
+        let sent = base + ms(100 * WINDOW as u64);
+        let clock = sync.sample(sent, sent + ms(5), 100_000 * WINDOW as u64);
+        assert_eq!(clock.pico, 100_000);
+        assert_eq!(clock.to_host(100_000), base + ms(100) + ms(5) / 2);
+    }
+
+    #[test]
+    fn reset() {
+        let base = Instant::now();
+        let mut sync = ClockSync::default();
+        sync.sample(base, base + ms(1), 0);
+        sync.reset();
+        let clock = sync.sample(base + ms(100), base + ms(110), 100_000);
+        assert_eq!(clock.pico, 100_000);
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use log::{debug, warn};
+use roland_protocol::{USB_CMD_INTERFACE, USB_LOG_INTERFACE, USB_PID, USB_PRODUCT, USB_VID};
+use tokio::fs;
+
+/// Raspberry Pi's USB vendor ID, used by the boot ROM
+const BOOTLOADER_VID: u16 = 0x2e8a;
+/// USB product ID of the RP2350's USB bootloader
+const BOOTLOADER_PID: u16 = 0x000f;
+
+/// how the pico's serial port should be chosen
+#[derive(Debug, Clone, Default)]
+pub enum DeviceSelector {
+    /// the only connected device with the firmware's VID/PID
+    #[default]
+    Auto,
+    /// this exact device path, without any verification
+    Path(String),
+    /// the device with this USB serial number
+    Serial(String),
+}
+
+/// a serial port and the USB metadata of the device behind it (if it's a USB device at all)
+#[derive(Debug, Clone, Default)]
+pub struct PortInfo {
+    pub path: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    /// number of the USB interface behind the port, a device can have several ports
+    pub interface: Option<u8>,
+    /// sysfs directory of the USB device, the same for every port of the device
+    pub usb_device: Option<PathBuf>,
+}
+
+impl PortInfo {
+    /// does this look like the command port of a pico running our firmware
+    pub fn is_pico(&self) -> bool {
+        self.vid == Some(USB_VID)
+            && self.pid == Some(USB_PID)
+            && self.product.as_deref().is_none_or(|p| p == USB_PRODUCT)
+            && self.interface.is_none_or(|i| i == USB_CMD_INTERFACE)
+    }
+}
+
+impl fmt::Display for PortInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let (Some(vid), Some(pid)) = (self.vid, self.pid) {
+            write!(f, " [{:04x}:{:04x}]", vid, pid)?;
+        }
+        if let Some(product) = &self.product {
+            write!(f, " \"{}\"", product)?;
+        }
+        if let Some(serial) = &self.serial {
+            write!(f, " (serial {})", serial)?;
+        }
+        Ok(())
+    }
+}
+
+/// read a single line sysfs attribute
+async fn read_attr(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name))
+        .await
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// list all USB serial ports with their metadata, based on sysfs
+///
+/// `/sys/class/tty/<name>/device` links to the USB interface, whose parent directory is the USB
+/// device holding the descriptor attributes
+pub async fn list_ports() -> anyhow::Result<Vec<PortInfo>> {
+    let mut ports = Vec::new();
+    let mut entries = fs::read_dir("/sys/class/tty").await?;
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if !name.starts_with("ttyACM") && !name.starts_with("ttyUSB") {
+            continue;
+        }
+
+        let mut info = PortInfo {
+            path: format!("/dev/{}", name),
+            ..Default::default()
+        };
+
+        if let Ok(interface) = fs::canonicalize(entry.path().join("device")).await
+            && let Some(usb_dev) = interface.parent()
+        {
+            info.interface = read_attr(&interface, "bInterfaceNumber")
+                .await
+                .and_then(|i| u8::from_str_radix(&i, 16).ok());
+            info.usb_device = Some(usb_dev.to_path_buf());
+            info.vid = read_attr(usb_dev, "idVendor")
+                .await
+                .and_then(|v| u16::from_str_radix(&v, 16).ok());
+            info.pid = read_attr(usb_dev, "idProduct")
+                .await
+                .and_then(|v| u16::from_str_radix(&v, 16).ok());
+            info.manufacturer = read_attr(usb_dev, "manufacturer").await;
+            info.product = read_attr(usb_dev, "product").await;
+            info.serial = read_attr(usb_dev, "serial").await;
+        }
+
+        debug!("Found serial port {}", info);
+        ports.push(info);
+    }
+
+    ports.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(ports)
+}
+
+/// find the path of the pico's serial port
+pub async fn find_pico(selector: &DeviceSelector) -> anyhow::Result<String> {
+    let ports = list_ports().await?;
+
+    match selector {
+        DeviceSelector::Path(path) => {
+            match ports.iter().find(|p| &p.path == path) {
+                Some(p) if !p.is_pico() => warn!("{} doesn't look like a Roland pico", p),
+                None => warn!("{} is not a known USB serial port", path),
+                _ => {}
+            }
+            Ok(path.clone())
+        }
+        DeviceSelector::Serial(serial) => ports
+            .iter()
+            .find(|p| p.serial.as_ref() == Some(serial))
+            .map(|p| p.path.clone())
+            .ok_or_else(|| not_found(&format!("with serial number {}", serial), &ports)),
+        DeviceSelector::Auto => {
+            let picos: Vec<_> = ports.iter().filter(|p| p.is_pico()).collect();
+            match picos.as_slice() {
+                [pico] => Ok(pico.path.clone()),
+                [] => Err(not_found(
+                    &format!("[{:04x}:{:04x}]", USB_VID, USB_PID),
+                    &ports,
+                )),
+                _ => Err(anyhow!(
+                    "Multiple Roland picos found, select one by path or serial number:{}",
+                    list(picos.into_iter())
+                )),
+            }
+        }
+    }
+}
+
+/// find the log port of the pico whose command port is at `cmd_path`
+/// returns `None` if it's not a pico, or its firmware has no log port
+pub async fn find_log_port(cmd_path: &str) -> Option<String> {
+    let ports = list_ports().await.ok()?;
+    let device = ports
+        .iter()
+        .find(|p| p.path == cmd_path)?
+        .usb_device
+        .as_ref()?;
+    ports
+        .iter()
+        .find(|p| p.usb_device.as_ref() == Some(device) && p.interface == Some(USB_LOG_INTERFACE))
+        .map(|p| p.path.clone())
+}
+
+/// is there an RP2350 in USB bootloader mode connected
+pub async fn find_bootloader() -> bool {
+    let Ok(mut entries) = fs::read_dir("/sys/bus/usb/devices").await else {
+        return false;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let dir = entry.path();
+        let vid = read_attr(&dir, "idVendor").await;
+        let pid = read_attr(&dir, "idProduct").await;
+        if let (Some(vid), Some(pid)) = (vid, pid)
+            && u16::from_str_radix(&vid, 16) == Ok(BOOTLOADER_VID)
+            && u16::from_str_radix(&pid, 16) == Ok(BOOTLOADER_PID)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+fn list<'a>(ports: impl Iterator<Item = &'a PortInfo>) -> String {
+    ports.map(|p| format!("\n  {}", p)).collect()
+}
+
+fn not_found(what: &str, candidates: &[PortInfo]) -> anyhow::Error {
+    if candidates.is_empty() {
+        anyhow!("Pico {} not found, no USB serial ports are present", what)
+    } else {
+        anyhow!(
+            "Pico {} not found, candidates:{}",
+            what,
+            list(candidates.iter())
+        )
+    }
+}
@@ -0,0 +1,35 @@
+use std::str::FromStr;
+
+use log::{Level, debug, log};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/// logger target the firmware's log is re-emitted with
+const TARGET: &str = "pico";
+
+/// re-emit the lines of the firmware's log port through our own logger, until the port closes
+///
+/// the lines look like `<LEVEL> <target>: <message>`, anything else is logged as is
+pub async fn forward(port: impl AsyncRead + Unpin) {
+    let mut lines = BufReader::new(port).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => emit(line.trim_end()),
+            Ok(None) => break,
+            Err(e) => {
+                debug!("[Firmware log] port failed: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+fn emit(line: &str) {
+    match line
+        .split_once(' ')
+        .and_then(|(level, rest)| Some((Level::from_str(level).ok()?, rest)))
+    {
+        Some((level, rest)) => log!(target: TARGET, level, "{}", rest),
+        None if line.is_empty() => {}
+        None => log!(target: TARGET, Level::Info, "{}", line),
+    }
+}
@@ -0,0 +1,200 @@
+//! flashing firmware through the RP2350's USB bootloader
+//!
+//! the bootloader shows up as a mass storage device, and copying a UF2 image onto it writes the
+//! image into flash and reboots into it
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow};
+use log::{debug, info, warn};
+use roland_protocol::{RebootMode, Version};
+use tokio::{fs, io::AsyncWriteExt, time};
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::{
+    discovery,
+    serial::{self, LinkOptions},
+};
+
+/// how long the bootloader's drive has to show up after rebooting into it
+const DRIVE_TIMEOUT: Duration = Duration::from_secs(30);
+/// how long the new firmware has to answer the hello after the image was written
+const RESTART_TIMEOUT: Duration = Duration::from_secs(15);
+/// delay between looking for the drive or the new firmware
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// file in the root of the bootloader's drive, describing the board
+const INFO_FILE: &str = "INFO_UF2.TXT";
+
+const UF2_BLOCK_SIZE: usize = 512;
+const UF2_MAGIC_START0: u32 = 0x0a32_4655;
+const UF2_MAGIC_START1: u32 = 0x9e5d_5157;
+const UF2_MAGIC_END: u32 = 0x0ab1_6f30;
+/// the block has a family ID instead of a file size
+const UF2_FLAG_FAMILY_ID: u32 = 0x2000;
+const RP2040_FAMILY_ID: u32 = 0xe48b_ff56;
+/// ARM secure, RISC-V and ARM non-secure images
+const RP2350_FAMILY_IDS: [u32; 3] = [0xe48b_ff59, 0xe48b_ff5a, 0xe48b_ff5b];
+
+/// flash the UF2 image at `image`, then check that the pico boots into a compatible firmware
+///
+/// if the pico runs our firmware, it's rebooted into the bootloader first, otherwise it has to be
+/// in the bootloader already, `drive` is where its drive is mounted if it isn't found in
+/// `/proc/mounts`
+pub async fn flash(
+    image: &Path,
+    drive: Option<&Path>,
+    options: &LinkOptions,
+) -> anyhow::Result<Version> {
+    let data = fs::read(image)
+        .await
+        .with_context(|| format!("Couldn't read {}", image.display()))?;
+    check_uf2(&data).with_context(|| format!("{} is not a usable image", image.display()))?;
+
+    if find_drive(drive).await.is_none() {
+        enter_bootloader(options).await?;
+    }
+    let drive = wait_for_drive(drive).await?;
+
+    info!(
+        "Writing {} ({} KiB) to {}",
+        image.display(),
+        data.len() / 1024,
+        drive.display()
+    );
+    let mut file = fs::File::create(drive.join("firmware.uf2")).await?;
+    file.write_all(&data).await?;
+    // the pico reboots as soon as it got the last block, so the drive might be gone already
+    if let Err(e) = file.sync_all().await {
+        debug!("[Flash] syncing the image failed: {}", e);
+    }
+
+    wait_for_firmware(options).await
+}
+
+/// sanity check a UF2 image, so a wrong file doesn't make it to the pico
+fn check_uf2(data: &[u8]) -> anyhow::Result<()> {
+    let (blocks, rest) = data.as_chunks::<UF2_BLOCK_SIZE>();
+    if blocks.is_empty() || !rest.is_empty() {
+        return Err(anyhow!("Not a UF2 file, its size isn't a multiple of 512"));
+    }
+
+    let word = |block: &[u8], offset: usize| {
+        u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+    };
+    let mut rp2040 = false;
+    let mut rp2350 = false;
+    for block in blocks {
+        if word(block, 0) != UF2_MAGIC_START0
+            || word(block, 4) != UF2_MAGIC_START1
+            || word(block, 508) != UF2_MAGIC_END
+        {
+            return Err(anyhow!("Not a UF2 file, a block has the wrong magic"));
+        }
+        if word(block, 8) & UF2_FLAG_FAMILY_ID != 0 {
+            let family = word(block, 28);
+            rp2040 |= family == RP2040_FAMILY_ID;
+            rp2350 |= RP2350_FAMILY_IDS.contains(&family);
+        }
+    }
+
+    match (rp2350, rp2040) {
+        (true, _) => Ok(()),
+        (false, true) => Err(anyhow!("It's built for the RP2040, not the RP2350")),
+        (false, false) => Err(anyhow!("It's not built for the RP2350")),
+    }
+}
+
+/// reboot the pico running our firmware into the bootloader
+async fn enter_bootloader(options: &LinkOptions) -> anyhow::Result<()> {
+    // a link of our own, so it doesn't try to reconnect while the pico is in the bootloader
+    let token = CancellationToken::new();
+    let pico = serial::init(token.clone(), options.clone())
+        .await
+        .context("Couldn't reach the pico to reboot it, hold BOOTSEL while plugging it in")?;
+    let ret = pico.reboot(RebootMode::Bootloader).await;
+    token.cancel();
+    ret?;
+    info!("Pico is rebooting into its bootloader");
+    Ok(())
+}
+
+/// the mount point of the bootloader's drive, if it's mounted
+async fn find_drive(drive: Option<&Path>) -> Option<PathBuf> {
+    if let Some(drive) = drive {
+        return is_rp2350(drive).await.then(|| drive.to_path_buf());
+    }
+
+    let mounts = fs::read_to_string("/proc/mounts").await.ok()?;
+    for line in mounts.lines() {
+        let Some(dir) = line.split(' ').nth(1) else {
+            continue;
+        };
+        // spaces are the only thing the volume label of a pico could need escaped
+        let dir = PathBuf::from(dir.replace("\\040", " "));
+        if is_rp2350(&dir).await {
+            return Some(dir);
+        }
+    }
+    None
+}
+
+/// is `dir` the drive of an RP2350's bootloader
+async fn is_rp2350(dir: &Path) -> bool {
+    fs::read_to_string(dir.join(INFO_FILE))
+        .await
+        .is_ok_and(|info| info.contains("RP2350"))
+}
+
+async fn wait_for_drive(drive: Option<&Path>) -> anyhow::Result<PathBuf> {
+    let deadline = time::Instant::now() + DRIVE_TIMEOUT;
+    let mut warned = false;
+    loop {
+        if let Some(dir) = find_drive(drive).await {
+            return Ok(dir);
+        }
+        if !warned && discovery::find_bootloader().await {
+            warn!(
+                "Pico is in its bootloader, waiting for its drive to be mounted{}",
+                drive.map_or(String::new(), |d| format!(" on {}", d.display()))
+            );
+            warned = true;
+        }
+        if time::Instant::now() >= deadline {
+            return Err(if warned {
+                anyhow!(
+                    "The bootloader's drive was never mounted, mount it and pass it with --drive"
+                )
+            } else {
+                anyhow!("The pico never showed up in its bootloader")
+            });
+        }
+        time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// connect to the freshly flashed firmware and ask for its version
+async fn wait_for_firmware(options: &LinkOptions) -> anyhow::Result<Version> {
+    let deadline = time::Instant::now() + RESTART_TIMEOUT;
+    loop {
+        time::sleep(POLL_INTERVAL).await;
+
+        let token = CancellationToken::new();
+        let ret = match serial::init(token.clone(), options.clone()).await {
+            Ok(pico) => pico.version().await,
+            Err(e) => Err(e),
+        };
+        token.cancel();
+
+        match ret {
+            Ok(version) => return Ok(version),
+            Err(e) if time::Instant::now() >= deadline => {
+                return Err(e.context("The new firmware didn't come up"));
+            }
+            Err(e) => debug!("[Flash] firmware not up yet: {}", e),
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info, warn};
+use roland_protocol::{Fault, FaultKind, SerialCMD, ShortString, UltraError};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::watch,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::{
+    Backend,
+    pico::sensors::{Reading, Sensors, TrackData, UltraData},
+    serial::LinkState,
+};
+
+/// in-process stand-in for the pico
+/// every command is recorded instead of being sent anywhere, and sensor readings are whatever
+/// gets pushed in with [`MockPico::push_ultra`] and [`MockPico::push_track`]
+#[derive(Clone)]
+pub struct MockPico {
+    commands: Arc<Mutex<Vec<SerialCMD>>>,
+    sensor_data: Sensors,
+    link_tx: watch::Sender<LinkState>,
+    fault_tx: watch::Sender<Option<Fault>>,
+    token: CancellationToken,
+}
+
+impl MockPico {
+    /// like the real link, the mock shuts down by cancelling `token` once it receives a reset
+    pub fn new(token: CancellationToken) -> Self {
+        Self {
+            commands: Arc::new(Mutex::new(Vec::new())),
+            sensor_data: Sensors::default(),
+            link_tx: watch::Sender::new(LinkState::Connected),
+            fault_tx: watch::Sender::new(None),
+            token,
+        }
+    }
+
+    /// all commands received since the last call
+    pub fn take_commands(&self) -> Vec<SerialCMD> {
+        std::mem::take(&mut self.commands.lock().unwrap())
+    }
+
+    pub fn push_ultra(&self, dist: UltraData) {
+        self.sensor_data
+            .ultra_sensor
+            .send_replace(Reading::now(dist));
+    }
+
+    pub fn push_track(&self, track: TrackData) {
+        self.sensor_data
+            .track_sensor
+            .send_replace(Reading::now(track));
+    }
+
+    pub fn set_link(&self, link: LinkState) {
+        self.link_tx.send_replace(link);
+    }
+
+    pub fn push_fault(&self, fault: Fault) {
+        self.fault_tx.send_replace(Some(fault));
+    }
+
+    /// drive the mock from stdin, one command per line:
+    /// - `ultra <cm>` or `ultra none`
+    /// - `track <4 digits of 0 or 1>`
+    /// - `link connected|reconnecting|lost`
+    /// - `fault <message>` to report a panic
+    /// - `dump` to print and clear the recorded commands
+    pub async fn console(self) -> anyhow::Result<()> {
+        info!("[Mock] reading sensor values from stdin");
+
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Some(line) = lines.next_line().await? {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("ultra"), Some("none")) => {
+                    self.push_ultra(UltraData::unfiltered(Err(UltraError::NoEcho)))
+                }
+                (Some("ultra"), Some(d)) => match d.parse() {
+                    Ok(d) => self.push_ultra(UltraData::unfiltered(Ok(d))),
+                    Err(e) => warn!("[Mock] invalid distance {}: {}", d, e),
+                },
+                (Some("track"), Some(bits)) if bits.len() == 4 => {
+                    let mut track = [false; 4];
+                    for (t, c) in track.iter_mut().zip(bits.chars()) {
+                        *t = c == '1';
+                    }
+                    self.push_track(track);
+                }
+                (Some("link"), Some(state)) => match state {
+                    "connected" => self.set_link(LinkState::Connected),
+                    "reconnecting" => self.set_link(LinkState::Reconnecting),
+                    "lost" => self.set_link(LinkState::Lost),
+                    _ => warn!("[Mock] unknown link state: {}", state),
+                },
+                (Some("fault"), Some(_)) => {
+                    let message = line.trim_start()["fault".len()..].trim();
+                    self.push_fault(Fault {
+                        kind: FaultKind::Panic,
+                        uptime: 0,
+                        message: ShortString::new(message),
+                        location: None,
+                    });
+                }
+                (Some("dump"), None) => {
+                    for cmd in self.take_commands() {
+                        info!("[Mock] {:?}", cmd);
+                    }
+                }
+                (None, _) => {}
+                _ => warn!("[Mock] unknown command: {}", line),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for MockPico {
+    async fn command(&self, cmd: SerialCMD) -> anyhow::Result<()> {
+        debug!("[Mock] {:?}", cmd);
+        let reset = matches!(cmd, SerialCMD::Reset);
+        self.commands.lock().unwrap().push(cmd);
+        if reset {
+            self.token.cancel();
+        }
+        Ok(())
+    }
+
+    fn subscribe_ultra(&self) -> watch::Receiver<Reading<UltraData>> {
+        self.sensor_data.ultra_sensor.subscribe()
+    }
+
+    fn subscribe_track(&self) -> watch::Receiver<Reading<TrackData>> {
+        self.sensor_data.track_sensor.subscribe()
+    }
+
+    fn subscribe_link(&self) -> watch::Receiver<LinkState> {
+        self.link_tx.subscribe()
+    }
+
+    fn subscribe_fault(&self) -> watch::Receiver<Option<Fault>> {
+        self.fault_tx.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use roland_protocol::Batch;
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::backend::roland::Roland;
+
+    /// give the control loop time to react, and get what it sent
+    async fn sent(mock: &MockPico) -> Vec<SerialCMD> {
+        sleep(Duration::from_millis(10)).await;
+        mock.take_commands()
+    }
+
+    fn drive(motor: (i32, i32), led: (u8, u8, u8)) -> SerialCMD {
+        SerialCMD::Batch(Batch {
+            motor: Some(motor),
+            led: Some(led),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follow_line() {
+        let mock = MockPico::new(CancellationToken::new());
+        let mut r = Roland::new(mock.clone());
+        let task = tokio::spawn(async move { r.follow_line(1.).await });
+
+        // both inner sensors read false on the line
+        assert_eq!(sent(&mock).await, [drive((58981, 58981), (0, 255, 0))]);
+
+        mock.push_track([false, false, true, false]);
+        assert_eq!(sent(&mock).await, [drive((49151, 65535), (0, 128, 128))]);
+
+        // both inner sensors off the line, it's lost towards the side it was drifting to
+        mock.push_track([false, true, true, false]);
+        assert_eq!(sent(&mock).await, [drive((-49151, 65535), (0, 0, 255))]);
+
+        // nothing changed, nothing to send
+        mock.push_track([false, true, true, false]);
+        assert_eq!(sent(&mock).await, []);
+
+        mock.push_track([false, true, false, false]);
+        assert_eq!(sent(&mock).await, [drive((65535, 49151), (128, 128, 0))]);
+
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_distance() {
+        let mock = MockPico::new(CancellationToken::new());
+        let mut r = Roland::new(mock.clone());
+        let task = tokio::spawn(async move { r.keep_distance(40).await });
+
+        // without a reading, it stays put
+        assert_eq!(sent(&mock).await, [SerialCMD::HBridge((0, 0))]);
+
+        let motor = |cmds: Vec<SerialCMD>| match cmds[..] {
+            [SerialCMD::HBridge((l, r))] if l == r => l,
+            _ => panic!("expected a single motor command, got {:?}", cmds),
+        };
+
+        mock.push_ultra(UltraData::unfiltered(Ok(100.)));
+        assert!(
+            motor(sent(&mock).await) > 0,
+            "too far, it should go forward"
+        );
+
+        mock.push_ultra(UltraData::unfiltered(Ok(10.)));
+        assert!(
+            motor(sent(&mock).await) < 0,
+            "too close, it should back off"
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn reset_cancels() {
+        let token = CancellationToken::new();
+        let mock = MockPico::new(token.clone());
+        let mut r = Roland::new(mock.clone());
+
+        r.pico.set_motor(1, 1).await.unwrap();
+        assert!(!token.is_cancelled());
+
+        r.reset().await.unwrap();
+        assert!(token.is_cancelled());
+        assert_eq!(
+            mock.take_commands(),
+            [SerialCMD::HBridge((1, 1)), SerialCMD::Reset]
+        );
+    }
+}
@@ -1,3 +1,162 @@
+use std::sync::{
+    LazyLock,
+    atomic::{AtomicU8, Ordering},
+};
+
+use anyhow::anyhow;
+use roland_protocol::{
+    Fault, LedEffect, MELODY_CAPACITY, MelodyChunk, Note, SerialCMD, ServoMotion, ServoMove,
+};
+use tokio::sync::watch;
+
+use crate::backend::{
+    batch::BatchBuilder,
+    pico::sensors::{Reading, TrackData, UltraData},
+    serial::{FirmwareInfo, LinkState},
+};
+
+pub mod batch;
+pub mod clock;
+pub mod discovery;
+pub mod firmware_log;
+pub mod flash;
+pub mod mock;
 pub mod pico;
+pub mod record;
+pub mod replay;
 pub mod roland;
-mod serial;
+pub mod serial;
+pub mod sim;
+
+/// id of the next melody, the pico only starts a melody if its id differs from the last one
+/// it starts from a different value in every process, so a melody after a restart isn't ignored
+static MELODY_ID: LazyLock<AtomicU8> = LazyLock::new(|| AtomicU8::new(std::process::id() as u8));
+
+/// degrees to the 0.01° steps the protocol uses
+pub fn centidegrees(deg: f32) -> i16 {
+    (deg * 100.).round() as i16
+}
+
+/// everything Roland needs from the hardware: actuator commands and sensor readings
+/// implementations can be cheaply cloned, and all clones share the same device
+pub trait Backend: Clone + Send + Sync + 'static {
+    /// execute a single command
+    fn command(&self, cmd: SerialCMD) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// get a receiver handle for the ultra sensor
+    fn subscribe_ultra(&self) -> watch::Receiver<Reading<UltraData>>;
+
+    /// get a receiver handle for the track sensor
+    fn subscribe_track(&self) -> watch::Receiver<Reading<TrackData>>;
+
+    /// get a receiver handle for the state of the link to the hardware
+    fn subscribe_link(&self) -> watch::Receiver<LinkState>;
+
+    /// get a receiver handle for the last crash the firmware reported after rebooting
+    fn subscribe_fault(&self) -> watch::Receiver<Option<Fault>>;
+
+    /// what the firmware on the other side of the link supports, if there's any
+    fn firmware(&self) -> Option<FirmwareInfo> {
+        None
+    }
+
+    /// gets the current state of the track sensor
+    fn get_track(&self) -> TrackData {
+        self.subscribe_track().borrow().value
+    }
+
+    /// Reset all hardware peripherals to a neutral state
+    ///
+    /// this should be called before terminating the program, in avoidance of some very serious
+    /// consequences (RIP camera holder, you won't be forgotten)
+    fn reset(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.command(SerialCMD::Reset)
+    }
+
+    /// Reset all hardware peripherals to a neutral state
+    ///
+    /// this does not initiate a shutdown sequence
+    fn soft_reset(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.batch()
+            .buzzer(0)
+            .led(0, 0, 0)
+            .servo(0.)
+            .motor(0, 0)
+            .send()
+    }
+
+    /// change several actuators at once, eg. `pico.batch().motor(l, r).led(r, g, b).send()`
+    fn batch(&self) -> BatchBuilder<'_, Self> {
+        BatchBuilder::new(self)
+    }
+
+    /// sets the buzzer to the specified frequency (Hz)
+    /// NOTE: a `freq` of `0` turns off the buzzer
+    fn set_buzzer(&mut self, freq: u16) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.command(SerialCMD::Buzzer(freq))
+    }
+
+    /// play `notes` on the buzzer, replacing the melody being played, [`Backend::set_buzzer`] stops
+    /// it
+    /// this returns once the melody was handed over, without waiting for it to finish
+    fn play_melody(&mut self, notes: &[Note]) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async move {
+            if notes.is_empty() || notes.len() > MELODY_CAPACITY {
+                return Err(anyhow!(
+                    "A melody has to have 1 to {} notes, not {}",
+                    MELODY_CAPACITY,
+                    notes.len()
+                ));
+            }
+
+            let id = MELODY_ID.fetch_add(1, Ordering::Relaxed);
+            for (i, chunk) in notes.chunks(MelodyChunk::LEN).enumerate() {
+                let offset = (i * MelodyChunk::LEN) as u16;
+                self.command(SerialCMD::Melody(MelodyChunk::new(id, offset, chunk)))
+                    .await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// sets the RGB LEDs to the specified rgb color (0 to 255)
+    fn set_led(&mut self, r: u8, g: u8, b: u8) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.command(SerialCMD::LED((r, g, b)))
+    }
+
+    /// run an effect on the RGB LEDs, until the next LED command
+    fn set_led_effect(
+        &mut self,
+        effect: LedEffect,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.command(SerialCMD::LedEffect(effect))
+    }
+
+    /// stop the running LED effect, turning the LEDs off
+    fn stop_led_effect(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.set_led(0, 0, 0)
+    }
+
+    /// sets the servo to the specified orientation (-90° to 90° in steps of 0.01°, 0° is the
+    /// midpoint)
+    fn set_servo(&mut self, deg: f32) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.command(SerialCMD::ServoMove(ServoMove {
+            angle: centidegrees(deg),
+            motion: ServoMotion::Instant,
+        }))
+    }
+
+    /// stop the motors right away, braking instead of ramping down
+    fn emergency_stop(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.command(SerialCMD::EmergencyStop)
+    }
+
+    /// sets the motor speeds as specified (both -0xffff to 0xffff, sign means direction)
+    fn set_motor(
+        &mut self,
+        left: i32,
+        right: i32,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.command(SerialCMD::HBridge((left, right)))
+    }
+}
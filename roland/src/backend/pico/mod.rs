@@ -1,42 +1,107 @@
-use log::{debug, error, info};
-use tokio::sync::{broadcast, mpsc, watch};
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use log::{debug, error, warn};
+use roland_protocol::{
+    ActuatorState, Calibration, Capabilities, Fault, LedEffect, Query, RebootMode, Response,
+    SERVO_MAX_ANGLE, Sensor, SerialCMD, SerialData, ServoMotion, ServoMove, ServoSweep, Stats,
+    StreamMode, Timestamp, Version,
+};
+use tokio::{
+    sync::{
+        Notify,
+        broadcast::{self, error::RecvError},
+        mpsc, oneshot, watch,
+    },
+    time::{self, Instant},
+};
 use tokio_util::sync::CancellationToken;
 
 use crate::backend::{
-    pico::sensors::{Sensors, TrackData, UltraData},
-    serial::{SerialCMD, SerialData, TrackSensorID},
+    Backend, centidegrees,
+    clock::Clock,
+    pico::sensors::{Reading, SensorOptions, Sensors, TrackData, TrackOrder, UltraData},
+    serial::{CmdRequest, FirmwareInfo, LinkState, QueryRequest},
 };
 
-mod sensors;
+pub mod sensors;
+
+/// how long to wait for the answer to a query
+const QUERY_TIMEOUT: Duration = Duration::from_millis(250);
+/// extra time a servo move gets to finish, on top of how long it should take
+const SERVO_MOVE_SLACK: Duration = Duration::from_secs(1);
+/// how long a sensor keeps streaming after its last subscriber is gone, so a client reconnecting
+/// doesn't turn it off and on again
+const STREAM_LINGER: Duration = Duration::from_secs(1);
+/// how long to wait before trying to set a stream again after it failed, doubling up to
+/// [`STREAM_RETRY_MAX`] while it keeps failing
+const STREAM_RETRY: Duration = Duration::from_millis(100);
+const STREAM_RETRY_MAX: Duration = Duration::from_secs(5);
+/// how often the firmware's link statistics are checked
+const STATS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// everything a [`Pico`] needs from whatever is talking to the device
+pub struct PicoChannels {
+    pub cmd_tx: mpsc::Sender<CmdRequest>,
+    pub query_tx: mpsc::Sender<QueryRequest>,
+    pub data_rx: broadcast::Receiver<SerialData>,
+    pub link_rx: watch::Receiver<LinkState>,
+    /// maps the pico's timestamps to host time, if it's `None` the arrival time is used
+    pub clock_rx: watch::Receiver<Option<Clock>>,
+    /// `None` while disconnected
+    pub firmware_rx: watch::Receiver<Option<FirmwareInfo>>,
+}
 
 /// wrapper around the pico serial communication channels used for state management and other
 /// abstractions
 /// this can be cheaply cloned
 #[derive(Clone)]
 pub struct Pico {
-    cmd_tx: mpsc::Sender<SerialCMD>,
+    cmd_tx: mpsc::Sender<CmdRequest>,
+    query_tx: mpsc::Sender<QueryRequest>,
     sensor_data: Sensors,
+    link_rx: watch::Receiver<LinkState>,
+    firmware_rx: watch::Receiver<Option<FirmwareInfo>>,
+    /// angle the last servo move finished at (0.01°), notified on every finished move
+    servo_done: watch::Sender<Option<i16>>,
+    /// the last crash the firmware reported
+    fault: watch::Sender<Option<Fault>>,
+    /// woken when someone subscribes to a sensor, its stream might have to be turned on
+    ultra_subscribed: Arc<Notify>,
+    track_subscribed: Arc<Notify>,
+    /// wait for the pico to acknowledge every command
+    reliable: bool,
 }
 
 impl Pico {
-    pub fn new(
-        cmd_tx: mpsc::Sender<SerialCMD>,
-        data_rx: broadcast::Receiver<SerialData>,
-        token: CancellationToken,
-    ) -> Self {
-        let (ultra_sensor, _) = watch::channel(None);
-        let (track_sensor, _) = watch::channel([false; 4]);
-
-        let sensor_data = Sensors {
-            ultra_sensor,
-            track_sensor,
-        };
+    pub fn new(channels: PicoChannels, options: SensorOptions, token: CancellationToken) -> Self {
+        let PicoChannels {
+            cmd_tx,
+            query_tx,
+            data_rx,
+            link_rx,
+            clock_rx,
+            firmware_rx,
+        } = channels;
+        let sensor_data = Sensors::default();
+        let (servo_done, _) = watch::channel(None);
+        let (fault, _) = watch::channel(None);
 
         {
             let sensor_data = sensor_data.clone();
+            let servo_done = servo_done.clone();
+            let fault = fault.clone();
+            let token = token.clone();
             tokio::spawn(async move {
                 tokio::select! {
-                    ret = Self::data_task(data_rx, sensor_data) => match ret {
+                    ret = Self::data_task(
+                        data_rx,
+                        clock_rx,
+                        sensor_data,
+                        servo_done,
+                        fault,
+                        options.track_order,
+                    ) => match ret {
                         Ok(()) => debug!("[Pico] task shutting down"),
                         Err(e) => error!("[Pico] task shutting down: {}", e),
                     },
@@ -45,96 +110,615 @@ impl Pico {
             });
         }
 
-        Self {
+        let pico = Self {
             cmd_tx,
+            query_tx,
             sensor_data,
+            link_rx,
+            firmware_rx,
+            servo_done,
+            fault,
+            ultra_subscribed: Arc::default(),
+            track_subscribed: Arc::default(),
+            reliable: false,
+        };
+
+        {
+            let pico = pico.clone();
+            let token = token.clone();
+            tokio::spawn(async move {
+                let readings = pico.sensor_data.ultra_sensor.clone();
+                let subscribed = pico.ultra_subscribed.clone();
+                tokio::select! {
+                    _ = pico.stream_task(Sensor::Ultra, options.ultra_stream, readings, subscribed) => {},
+                    _ = token.cancelled() => {},
+                }
+            });
         }
+        {
+            let pico = pico.clone();
+            let token = token.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = pico.stats_task() => {},
+                    _ = token.cancelled() => {},
+                }
+            });
+        }
+        {
+            let pico = pico.clone();
+            tokio::spawn(async move {
+                let readings = pico.sensor_data.track_sensor.clone();
+                let subscribed = pico.track_subscribed.clone();
+                tokio::select! {
+                    _ = pico.stream_task(Sensor::Track, options.track_stream, readings, subscribed) => {},
+                    _ = token.cancelled() => {},
+                }
+            });
+        }
+
+        pico
     }
 
     async fn data_task(
         mut data_rx: broadcast::Receiver<SerialData>,
+        clock_rx: watch::Receiver<Option<Clock>>,
         sensor_data: Sensors,
+        servo_done: watch::Sender<Option<i16>>,
+        fault: watch::Sender<Option<Fault>>,
+        track_order: TrackOrder,
     ) -> anyhow::Result<()> {
+        let host_time = |t: Timestamp| match *clock_rx.borrow() {
+            Some(clock) => clock.to_host(t),
+            None => Instant::now(),
+        };
+
         loop {
-            match data_rx.recv().await? {
-                SerialData::UltraSensor(dist) => {
-                    sensor_data.ultra_sensor.send_replace(dist);
+            let data = match data_rx.recv().await {
+                Ok(data) => data,
+                // the link shut down
+                Err(RecvError::Closed) => return Ok(()),
+                Err(RecvError::Lagged(n)) => {
+                    warn!("[Pico] fell behind, {} packets were skipped", n);
+                    continue;
                 }
-                SerialData::TrackSensor((id, val)) => {
-                    let mut current = *sensor_data.track_sensor.borrow();
+            };
 
-                    // TODO: the track sensor ids are a bit goofy, this need some further
-                    // investigation
-                    current[match id {
-                        TrackSensorID::L1 => 1,
-                        TrackSensorID::L2 => 0,
-                        TrackSensorID::R1 => 2,
-                        TrackSensorID::R2 => 3,
-                    }] = val;
+            match data {
+                SerialData::UltraSensor((t, dist)) => {
+                    sensor_data.ultra_sensor.send_replace(Reading {
+                        value: UltraData {
+                            distance: dist.map(f32::from),
+                            raw: None,
+                        },
+                        time: host_time(t),
+                    });
+                }
+                SerialData::Ultra(reading) => {
+                    let cm = |mm: u16| mm as f32 / 10.;
+                    sensor_data.ultra_sensor.send_replace(Reading {
+                        value: UltraData {
+                            distance: reading.filtered.map(cm),
+                            raw: Some(reading.raw.map(cm)),
+                        },
+                        time: host_time(reading.time),
+                    });
+                }
+                SerialData::TrackSensor((t, id, val)) => {
+                    let mut current = sensor_data.track_sensor.borrow().value;
+                    current[track_order.index(id)] = val;
 
-                    sensor_data.track_sensor.send_replace(current);
+                    sensor_data.track_sensor.send_replace(Reading {
+                        value: current,
+                        time: host_time(t),
+                    });
+                }
+                // the edges only tell what changed, a missed one leaves the state wrong until
+                // the snapshot
+                SerialData::Track(snapshot) => {
+                    let value = track_order.arrange(&snapshot);
+                    sensor_data.track_sensor.send_if_modified(|current| {
+                        if current.value == value {
+                            return false;
+                        }
+                        debug!("[Pico] track sensors corrected to {:?}", value);
+                        *current = Reading {
+                            value,
+                            time: host_time(snapshot.time),
+                        };
+                        true
+                    });
+                }
+                SerialData::ServoDone((_, angle)) => {
+                    servo_done.send_replace(Some(angle));
                 }
+                SerialData::WatchdogTripped => {
+                    warn!("[Pico] watchdog tripped, the motors were stopped")
+                }
+                SerialData::Fault(f) => {
+                    error!("[Pico] before rebooting, the firmware {}", f);
+                    fault.send_replace(Some(f));
+                }
+                // handled by the serial layer
+                SerialData::Ack(_)
+                | SerialData::Nack(_)
+                | SerialData::Sync(_)
+                | SerialData::Response(_)
+                | SerialData::Hello(_) => {}
             }
         }
     }
 
-    /// Reset all hardware peripherals to a neutral state
-    ///
-    /// this should be called before terminating the program, in avoidance of some very serious
-    /// consequences (RIP camera holder, you won't be forgotten)
-    pub async fn reset(&mut self) -> anyhow::Result<()> {
-        self.cmd_tx.send(SerialCMD::Reset).await?;
-        Ok(())
+    /// keep a sensor streaming in `mode` while anyone is subscribed to its `readings`, and turn it
+    /// off while nobody is
+    async fn stream_task<T>(
+        &self,
+        sensor: Sensor,
+        mode: StreamMode,
+        readings: watch::Sender<Reading<T>>,
+        subscribed: Arc<Notify>,
+    ) {
+        let mut firmware_rx = self.firmware_rx.clone();
+        // what the firmware was last told, it forgets it when it reboots
+        let mut sent = None;
+        // set while the last attempt failed
+        let mut retry: Option<Duration> = None;
+
+        loop {
+            let wanted = if readings.receiver_count() > 0 {
+                mode
+            } else {
+                StreamMode::Off
+            };
+            let supported = firmware_rx
+                .borrow_and_update()
+                .is_some_and(|f| f.capabilities.contains(Capabilities::STREAMS));
+            if supported && sent != Some(wanted) {
+                match self
+                    .send_confirmed(SerialCMD::Stream((sensor, wanted)))
+                    .await
+                {
+                    Ok(()) => {
+                        debug!("[Pico] {:?} stream set to {:?}", sensor, wanted);
+                        sent = Some(wanted);
+                        retry = None;
+                    }
+                    Err(e) => {
+                        let delay = retry.map_or(STREAM_RETRY, |d| (d * 2).min(STREAM_RETRY_MAX));
+                        warn!(
+                            "[Pico] couldn't set the {:?} stream to {:?}, retrying in {:?}: {}",
+                            sensor, wanted, delay, e
+                        );
+                        retry = Some(delay);
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = subscribed.notified(), if wanted == StreamMode::Off => {},
+                _ = readings.closed(), if wanted != StreamMode::Off => {
+                    time::sleep(STREAM_LINGER).await;
+                },
+                ret = firmware_rx.changed() => {
+                    // the link shut down, or there's none
+                    if ret.is_err() {
+                        return;
+                    }
+                    sent = None;
+                    retry = None;
+                },
+                // try again, unless something above changed what to send first
+                _ = time::sleep(retry.unwrap_or_default()), if retry.is_some() => {},
+            }
+        }
+    }
+
+    /// warn if the firmware dropped or couldn't decode frames since the last check
+    async fn stats_task(&self) {
+        let mut firmware_rx = self.firmware_rx.clone();
+        let mut interval = time::interval(STATS_INTERVAL);
+        let mut last: Option<Stats> = None;
+
+        loop {
+            interval.tick().await;
+            // the counters kept going while nobody was listening, only what happens during a
+            // connection counts
+            if firmware_rx.has_changed().unwrap_or(true) {
+                firmware_rx.borrow_and_update();
+                last = None;
+            }
+            if !self
+                .firmware()
+                .is_some_and(|f| f.capabilities.contains(Capabilities::STATS))
+            {
+                continue;
+            }
+
+            let stats = match self.stats().await {
+                Ok(stats) => stats,
+                Err(e) => {
+                    debug!("[Pico] couldn't get the link statistics: {}", e);
+                    continue;
+                }
+            };
+            debug!("[Pico] firmware {}", stats);
+            if let Some(last) = last {
+                let dropped_data = stats.dropped_data.wrapping_sub(last.dropped_data);
+                let dropped_commands = stats.dropped_commands.wrapping_sub(last.dropped_commands);
+                let decode_errors = stats.decode_errors.wrapping_sub(last.decode_errors);
+                if dropped_data > 0 || dropped_commands > 0 || decode_errors > 0 {
+                    warn!(
+                        "[Pico] in the last {:?}, the firmware dropped {} sensor frames and {} \
+                         commands, and couldn't decode {} frames",
+                        STATS_INTERVAL, dropped_data, dropped_commands, decode_errors
+                    );
+                }
+            }
+            last = Some(stats);
+        }
+    }
+
+    /// in reliable mode, every command waits for the pico to confirm that it was applied, and
+    /// returns an error if it was refused or never acknowledged
+    pub fn set_reliable(&mut self, reliable: bool) {
+        self.reliable = reliable;
     }
 
-    /// Reset all hardware peripherals to a neutral state
-    ///
-    /// this does not initiate a shutdown sequence
-    pub async fn soft_reset(&mut self) -> anyhow::Result<()> {
-        self.set_buzzer(0).await?;
-        self.set_led(0, 0, 0).await?;
-        self.set_servo(0).await?;
-        self.set_motor(0, 0).await?;
+    /// send a command without waiting for it to be applied
+    pub async fn send(&self, cmd: SerialCMD) -> anyhow::Result<()> {
+        self.cmd_tx.send(CmdRequest { cmd, confirm: None }).await?;
         Ok(())
     }
 
-    /// get a receiver handle for the ultra sensor
-    pub fn subscribe_ultra(&self) -> watch::Receiver<UltraData> {
-        self.sensor_data.ultra_sensor.subscribe()
+    /// send a command and wait until the pico confirms that it was applied
+    /// it gets retransmitted a few times if no answer arrives
+    pub async fn send_confirmed(&self, cmd: SerialCMD) -> anyhow::Result<()> {
+        let (confirm, confirm_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(CmdRequest {
+                cmd,
+                confirm: Some(confirm),
+            })
+            .await?;
+        confirm_rx.await?
     }
 
-    /// gets the current state of the track sensor
-    pub fn get_track(&self) -> [bool; 4] {
-        *self.sensor_data.track_sensor.borrow()
+    /// send a single command in the current mode
+    async fn dispatch(&self, cmd: SerialCMD) -> anyhow::Result<()> {
+        if self.reliable {
+            self.send_confirmed(cmd).await
+        } else {
+            self.send(cmd).await
+        }
     }
 
-    /// get a receiver handle for the track sensor
-    pub fn subscribe_track(&self) -> watch::Receiver<TrackData> {
-        self.sensor_data.track_sensor.subscribe()
+    /// fail if the firmware is known not to support `capability`
+    fn require(&self, capability: Capabilities, what: &str) -> anyhow::Result<()> {
+        match self.firmware() {
+            Some(firmware) if !firmware.capabilities.contains(capability) => Err(anyhow!(
+                "Firmware (protocol v{}) doesn't support {}",
+                firmware.protocol,
+                what
+            )),
+            _ => Ok(()),
+        }
     }
 
-    /// sets the buzzer to the specified frequency (Hz)
-    /// NOTE: a `freq` of `0` turns off the buzzer
-    pub async fn set_buzzer(&mut self, freq: u16) -> anyhow::Result<()> {
-        self.cmd_tx.send(SerialCMD::Buzzer(freq)).await?;
-        Ok(())
+    /// ask the pico something and wait for the answer
+    pub async fn request(&self, query: Query) -> anyhow::Result<Response> {
+        self.require(Capabilities::QUERIES, "queries")?;
+
+        let (respond, response_rx) = oneshot::channel();
+        self.query_tx
+            .send(QueryRequest { query, respond })
+            .await
+            .map_err(|_| anyhow!("The pico can't be queried"))?;
+
+        match time::timeout(QUERY_TIMEOUT, response_rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(anyhow!("Link lost before {:?} was answered", query)),
+            Err(_) => Err(anyhow!("{:?} was never answered", query)),
+        }
     }
 
-    /// sets the RGB LEDs to the specified rgb color (0 to 255)
-    pub async fn set_led(&mut self, r: u8, g: u8, b: u8) -> anyhow::Result<()> {
-        self.cmd_tx.send(SerialCMD::LED((r, g, b))).await?;
-        Ok(())
+    /// round trip time to the pico
+    pub async fn ping(&self) -> anyhow::Result<Duration> {
+        let start = Instant::now();
+        match self.request(Query::Ping).await? {
+            Response::Pong => Ok(start.elapsed()),
+            r => Err(unexpected(Query::Ping, r)),
+        }
     }
 
-    /// sets the servo to the specified orientation (-90° to 90°, 0° is the midpoint)
-    pub async fn set_servo(&mut self, deg: i8) -> anyhow::Result<()> {
-        self.cmd_tx.send(SerialCMD::Servo(deg)).await?;
-        Ok(())
+    pub async fn version(&self) -> anyhow::Result<Version> {
+        match self.request(Query::GetVersion).await? {
+            Response::Version(v) => Ok(v),
+            r => Err(unexpected(Query::GetVersion, r)),
+        }
     }
 
-    /// sets the motor speeds as specified (both -0xffff to 0xffff, sign means direction)
-    pub async fn set_motor(&mut self, left: i32, right: i32) -> anyhow::Result<()> {
-        self.cmd_tx.send(SerialCMD::HBridge((left, right))).await?;
-        Ok(())
+    /// what the pico's actuators are currently set to
+    pub async fn actuator_state(&self) -> anyhow::Result<ActuatorState> {
+        match self.request(Query::GetActuatorState).await? {
+            Response::ActuatorState(s) => Ok(s),
+            r => Err(unexpected(Query::GetActuatorState, r)),
+        }
+    }
+
+    /// the counters of the firmware's serial link
+    pub async fn stats(&self) -> anyhow::Result<Stats> {
+        self.require(Capabilities::STATS, "statistics")?;
+        match self.request(Query::GetStats).await? {
+            Response::Stats(s) => Ok(s),
+            r => Err(unexpected(Query::GetStats, r)),
+        }
+    }
+
+    /// the calibration the pico is currently using
+    pub async fn calibration(&self) -> anyhow::Result<Calibration> {
+        self.require(Capabilities::CALIBRATION, "calibration")?;
+        match self.request(Query::GetCalibration).await? {
+            Response::Calibration(c) => Ok(c),
+            r => Err(unexpected(Query::GetCalibration, r)),
+        }
+    }
+
+    /// start using `calibration`, until the pico reboots (see [`Pico::save_calibration`])
+    pub async fn set_calibration(&self, calibration: Calibration) -> anyhow::Result<()> {
+        self.require(Capabilities::CALIBRATION, "calibration")?;
+        calibration
+            .validate()
+            .map_err(|e| anyhow!("Invalid calibration {:?}: {}", calibration, e))?;
+        self.send_confirmed(SerialCMD::SetCalibration(calibration))
+            .await
+    }
+
+    /// store the calibration in use in the pico's flash, so it boots with it
+    pub async fn save_calibration(&self) -> anyhow::Result<()> {
+        self.require(Capabilities::CALIBRATION, "calibration")?;
+        self.send_confirmed(SerialCMD::SaveCalibration).await
+    }
+
+    /// make the pico reboot into `mode`, the link drops right after this returns
+    pub async fn reboot(&self, mode: RebootMode) -> anyhow::Result<()> {
+        self.require(Capabilities::REBOOT, "rebooting")?;
+        self.send_confirmed(SerialCMD::Reboot(mode)).await
+    }
+
+    /// move the servo to `deg` (-90° to 90°, in steps of 0.01°), returns once it got there
+    pub async fn move_servo(&self, deg: f32, motion: ServoMotion) -> anyhow::Result<()> {
+        self.require(Capabilities::SERVO_MOTION, "servo motion")?;
+        let m = ServoMove {
+            angle: centidegrees(deg),
+            motion,
+        };
+        m.validate()
+            .map_err(|e| anyhow!("Invalid servo move {:?}: {}", m, e))?;
+
+        // the longest it can take, the firmware doesn't tell how far the servo has to go
+        let duration = match motion {
+            ServoMotion::Instant => Duration::ZERO,
+            ServoMotion::Duration(ms) => Duration::from_millis(ms as u64),
+            ServoMotion::Speed(speed) => {
+                Duration::from_millis(2 * SERVO_MAX_ANGLE as u64 * 10 / speed as u64)
+            }
+        };
+
+        let mut done_rx = self.servo_done.subscribe();
+        done_rx.mark_unchanged();
+        self.send_confirmed(SerialCMD::ServoMove(m)).await?;
+
+        let finished = async {
+            loop {
+                done_rx.changed().await?;
+                if *done_rx.borrow_and_update() == Some(m.angle) {
+                    return anyhow::Ok(());
+                }
+            }
+        };
+        match time::timeout(duration + SERVO_MOVE_SLACK, finished).await {
+            Ok(ret) => ret,
+            Err(_) => Err(anyhow!(
+                "Servo move to {}° was interrupted or never finished",
+                deg
+            )),
+        }
+    }
+
+    /// swing the servo from `from` to `to` and back (in degrees) every `period`, until the next
+    /// servo command
+    pub async fn sweep_servo(&self, from: f32, to: f32, period: Duration) -> anyhow::Result<()> {
+        self.require(Capabilities::SERVO_MOTION, "servo motion")?;
+        let sweep = ServoSweep {
+            from: centidegrees(from),
+            to: centidegrees(to),
+            period: period
+                .as_millis()
+                .try_into()
+                .map_err(|_| anyhow!("Sweep period {:?} is too long", period))?,
+        };
+        sweep
+            .validate()
+            .map_err(|e| anyhow!("Invalid servo sweep {:?}: {}", sweep, e))?;
+        self.send_confirmed(SerialCMD::ServoSweep(sweep)).await
+    }
+
+    /// stop driving the servo so it can be turned by hand, the next servo command drives it again
+    pub async fn detach_servo(&self) -> anyhow::Result<()> {
+        self.require(Capabilities::SERVO_MOTION, "servo motion")?;
+        self.send_confirmed(SerialCMD::ServoDetach).await
+    }
+}
+
+fn unexpected(query: Query, response: Response) -> anyhow::Error {
+    anyhow!("Unexpected answer to {:?}: {:?}", query, response)
+}
+
+impl Backend for Pico {
+    /// send a command, in a way that depends on the current mode (see [`Pico::set_reliable`])
+    /// batches are split up if the firmware can't apply them at once, emergency stops become plain
+    /// stops if it doesn't know them, and so do LED effects that end up at a single color
+    async fn command(&self, cmd: SerialCMD) -> anyhow::Result<()> {
+        let supports = |capability| {
+            self.firmware()
+                .is_none_or(|f| f.capabilities.contains(capability))
+        };
+        match cmd {
+            SerialCMD::Batch(batch) if !supports(Capabilities::BATCH) => {
+                for cmd in batch.commands() {
+                    // older firmware only takes whole degrees
+                    let cmd = match cmd {
+                        SerialCMD::ServoMove(m) if !supports(Capabilities::SERVO_MOTION) => {
+                            SerialCMD::Servo(m.degrees())
+                        }
+                        cmd => cmd,
+                    };
+                    self.dispatch(cmd).await?;
+                }
+                Ok(())
+            }
+            SerialCMD::EmergencyStop if !supports(Capabilities::MOTOR_CONTROL) => {
+                self.dispatch(SerialCMD::HBridge((0, 0))).await
+            }
+            SerialCMD::LedEffect(LedEffect::Solid(color) | LedEffect::Fade { color, .. })
+                if !supports(Capabilities::LED_EFFECTS) =>
+            {
+                self.dispatch(SerialCMD::LED(color)).await
+            }
+            // older firmware only takes whole degrees
+            SerialCMD::ServoMove(m)
+                if m.motion == ServoMotion::Instant && !supports(Capabilities::SERVO_MOTION) =>
+            {
+                self.dispatch(SerialCMD::Servo(m.degrees())).await
+            }
+            SerialCMD::LedEffect(_) => {
+                self.require(Capabilities::LED_EFFECTS, "LED effects")?;
+                self.dispatch(cmd).await
+            }
+            SerialCMD::UltraConfig(_) => {
+                self.require(Capabilities::ULTRA_READINGS, "ultra sensor settings")?;
+                self.dispatch(cmd).await
+            }
+            SerialCMD::TrackConfig(_) => {
+                self.require(Capabilities::TRACK_SNAPSHOTS, "track sensor settings")?;
+                self.dispatch(cmd).await
+            }
+            SerialCMD::Stream(_) => {
+                self.require(Capabilities::STREAMS, "sensor streams")?;
+                self.dispatch(cmd).await
+            }
+            SerialCMD::Melody(_) => {
+                self.require(Capabilities::MELODY, "melodies")?;
+                self.dispatch(cmd).await
+            }
+            cmd => self.dispatch(cmd).await,
+        }
+    }
+
+    /// the sensor streams while anyone is subscribed to it
+    fn subscribe_ultra(&self) -> watch::Receiver<Reading<UltraData>> {
+        let rx = self.sensor_data.ultra_sensor.subscribe();
+        self.ultra_subscribed.notify_one();
+        rx
+    }
+
+    /// the sensors stream while anyone is subscribed to them
+    fn subscribe_track(&self) -> watch::Receiver<Reading<TrackData>> {
+        let rx = self.sensor_data.track_sensor.subscribe();
+        self.track_subscribed.notify_one();
+        rx
+    }
+
+    fn subscribe_link(&self) -> watch::Receiver<LinkState> {
+        self.link_rx.clone()
+    }
+
+    fn subscribe_fault(&self) -> watch::Receiver<Option<Fault>> {
+        self.fault.subscribe()
+    }
+
+    /// `None` while disconnected
+    fn firmware(&self) -> Option<FirmwareInfo> {
+        *self.firmware_rx.borrow()
+    }
+
+    async fn reset(&mut self) -> anyhow::Result<()> {
+        // the serial connection is closed right after this is sent, so there's nobody to wait for
+        // the ack
+        self.send(SerialCMD::Reset).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use roland_protocol::PROTOCOL_VERSION;
+
+    use super::*;
+
+    /// the next command setting the stream of `sensor`, everything else is confirmed right away
+    async fn next_stream(cmd_rx: &mut mpsc::Receiver<CmdRequest>, sensor: Sensor) -> CmdRequest {
+        loop {
+            let req = cmd_rx.recv().await.unwrap();
+            match req.cmd {
+                SerialCMD::Stream((s, _)) if s == sensor => return req,
+                _ => {
+                    if let Some(confirm) = req.confirm {
+                        let _ = confirm.send(Ok(()));
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_retried_until_set() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel(8);
+        let (query_tx, _query_rx) = mpsc::channel(8);
+        let (_data_tx, data_rx) = broadcast::channel(8);
+        let (_link_tx, link_rx) = watch::channel(LinkState::Connected);
+        let (_clock_tx, clock_rx) = watch::channel(None);
+        let (_firmware_tx, firmware_rx) = watch::channel(Some(FirmwareInfo {
+            protocol: PROTOCOL_VERSION,
+            capabilities: Capabilities::STREAMS,
+        }));
+        let pico = Pico::new(
+            PicoChannels {
+                cmd_tx,
+                query_tx,
+                data_rx,
+                link_rx,
+                clock_rx,
+                firmware_rx,
+            },
+            SensorOptions::default(),
+            CancellationToken::new(),
+        );
+        let _ultra = pico.subscribe_ultra();
+
+        // the ack gets lost twice
+        let mut last = Instant::now();
+        for delay in [Duration::ZERO, STREAM_RETRY, 2 * STREAM_RETRY] {
+            let req = next_stream(&mut cmd_rx, Sensor::Ultra).await;
+            assert_eq!(
+                req.cmd,
+                SerialCMD::Stream((Sensor::Ultra, StreamMode::Continuous))
+            );
+            assert!(last.elapsed() >= delay);
+            last = Instant::now();
+            let _ = req
+                .confirm
+                .unwrap()
+                .send(Err(anyhow!("never acknowledged")));
+        }
+
+        let req = next_stream(&mut cmd_rx, Sensor::Ultra).await;
+        let _ = req.confirm.unwrap().send(Ok(()));
+
+        // it's set now, nothing left to retry
+        let more = time::timeout(
+            2 * STREAM_RETRY_MAX,
+            next_stream(&mut cmd_rx, Sensor::Ultra),
+        );
+        assert!(more.await.is_err());
     }
 }
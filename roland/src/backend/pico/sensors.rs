@@ -1,11 +1,155 @@
-use tokio::sync::watch;
+use std::str::FromStr;
 
-pub type UltraData = Option<u16>;
+use anyhow::anyhow;
+use roland_protocol::{StreamMode, TrackSensorID, TrackSnapshot, UltraError};
+use tokio::{sync::watch, time::Instant};
+
+/// the level of every track sensor, from left to right
 pub type TrackData = [bool; 4];
 
+/// which sensor is at each position of [`TrackData`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackOrder([TrackSensorID; 4]);
+
+impl TrackOrder {
+    /// how the sensors are wired on the robot
+    pub const DEFAULT: Self = Self([
+        TrackSensorID::L2,
+        TrackSensorID::L1,
+        TrackSensorID::R1,
+        TrackSensorID::R2,
+    ]);
+
+    /// every sensor has to be in the order exactly once
+    pub fn new(order: [TrackSensorID; 4]) -> anyhow::Result<Self> {
+        match TrackSensorID::ALL
+            .into_iter()
+            .find(|id| !order.contains(id))
+        {
+            Some(missing) => Err(anyhow!(
+                "{:?} is missing from the track sensor order",
+                missing
+            )),
+            None => Ok(Self(order)),
+        }
+    }
+
+    /// position of a sensor in [`TrackData`]
+    pub fn index(&self, id: TrackSensorID) -> usize {
+        self.0
+            .iter()
+            .position(|o| *o == id)
+            .expect("every sensor is in the order")
+    }
+
+    /// the levels of a snapshot, in this order
+    pub fn arrange(&self, snapshot: &TrackSnapshot) -> TrackData {
+        self.0.map(|id| snapshot.get(id))
+    }
+}
+
+impl Default for TrackOrder {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// four comma separated sensor ids from left to right, like `l2,l1,r1,r2`
+impl FromStr for TrackOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let ids = s
+            .split(',')
+            .map(|id| match id.trim().to_ascii_lowercase().as_str() {
+                "l1" => Ok(TrackSensorID::L1),
+                "l2" => Ok(TrackSensorID::L2),
+                "r1" => Ok(TrackSensorID::R1),
+                "r2" => Ok(TrackSensorID::R2),
+                id => Err(anyhow!("Unknown track sensor {}", id)),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let order = ids
+            .try_into()
+            .map_err(|_| anyhow!("Expected 4 track sensors, like l2,l1,r1,r2"))?;
+        Self::new(order)
+    }
+}
+
+/// how the sensors of a pico are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SensorOptions {
+    pub track_order: TrackOrder,
+    /// what the sensors send while anyone is subscribed to them, they're off otherwise
+    pub ultra_stream: StreamMode,
+    pub track_stream: StreamMode,
+}
+
+impl Default for SensorOptions {
+    fn default() -> Self {
+        Self {
+            track_order: TrackOrder::default(),
+            ultra_stream: StreamMode::Continuous,
+            track_stream: StreamMode::Continuous,
+        }
+    }
+}
+
+/// a measurement of the ultra sensor, distances are in cm
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UltraData {
+    /// the filtered distance, `None` if the sensor isn't measuring anything reliably
+    pub distance: Option<f32>,
+    /// the measurement on its own, or why it failed
+    /// `None` if the firmware only reports filtered distances
+    pub raw: Option<Result<f32, UltraError>>,
+}
+
+impl UltraData {
+    /// a reading of a sensor without any filtering
+    pub fn unfiltered(raw: Result<f32, UltraError>) -> Self {
+        Self {
+            distance: raw.ok(),
+            raw: Some(raw),
+        }
+    }
+}
+
+/// a sensor value and when it was captured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading<T> {
+    pub value: T,
+    /// capture time in host time
+    /// if the capture time isn't known (eg. the pico's clock isn't synced yet), this is when the
+    /// value arrived
+    pub time: Instant,
+}
+
+impl<T> Reading<T> {
+    /// a value captured right now
+    pub fn now(value: T) -> Self {
+        Self {
+            value,
+            time: Instant::now(),
+        }
+    }
+}
+
 /// wrapper for all sensor state
 #[derive(Clone)]
 pub struct Sensors {
-    pub ultra_sensor: watch::Sender<UltraData>,
-    pub track_sensor: watch::Sender<TrackData>,
+    pub ultra_sensor: watch::Sender<Reading<UltraData>>,
+    pub track_sensor: watch::Sender<Reading<TrackData>>,
+}
+
+impl Default for Sensors {
+    fn default() -> Self {
+        let (ultra_sensor, _) = watch::channel(Reading::now(UltraData::default()));
+        let (track_sensor, _) = watch::channel(Reading::now([false; 4]));
+
+        Self {
+            ultra_sensor,
+            track_sensor,
+        }
+    }
 }
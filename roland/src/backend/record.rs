@@ -0,0 +1,273 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+use anyhow::{Context, anyhow};
+use log::{debug, error, info, warn};
+use roland_protocol::{
+    Command, PROTOCOL_VERSION, SerialData,
+    frame::{self, FrameDecoder, MAX_FRAME_SIZE},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+/// first bytes of every recording, the last one is the format version
+const MAGIC: &[u8; 8] = b"RLNDREC\x02";
+/// [`MAGIC`] and the protocol version the records are encoded with
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+/// a single message that went over the serial link
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Entry {
+    Sent(Command),
+    Received(SerialData),
+}
+
+/// an [`Entry`] and when it happened
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Record {
+    /// microseconds since the recording started
+    pub t: u64,
+    pub entry: Entry,
+}
+
+/// records serial traffic into a file
+///
+/// the file is [`MAGIC`] and the little endian [`PROTOCOL_VERSION`], followed by [`Record`]s, each
+/// framed the same way as the serial traffic itself
+/// writing happens on a separate thread, so recording never holds up the link and survives the
+/// runtime shutting down
+/// this can be cheaply cloned
+#[derive(Clone)]
+pub struct Recorder {
+    tx: mpsc::UnboundedSender<Record>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut file = BufWriter::new(
+            File::create(path)
+                .with_context(|| format!("Couldn't create recording {}", path.display()))?,
+        );
+        file.write_all(MAGIC)?;
+        file.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        std::thread::spawn(move || match write_task(file, rx) {
+            Ok(()) => debug!("[Recorder] task shutting down"),
+            Err(e) => error!("[Recorder] task shutting down: {}", e),
+        });
+
+        info!("Recording serial traffic to {}", path.display());
+
+        Ok(Self {
+            tx,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn sent(&self, cmd: &Command) {
+        self.record(Entry::Sent(cmd.clone()));
+    }
+
+    pub fn received(&self, data: &SerialData) {
+        self.record(Entry::Received(data.clone()));
+    }
+
+    fn record(&self, entry: Entry) {
+        let t = self.start.elapsed().as_micros() as u64;
+        // the writer only stops if the file became unwritable, which it already reported
+        let _ = self.tx.send(Record { t, entry });
+    }
+}
+
+fn write_task(
+    mut file: BufWriter<File>,
+    mut rx: mpsc::UnboundedReceiver<Record>,
+) -> anyhow::Result<()> {
+    let mut buf = [0u8; MAX_FRAME_SIZE];
+    while let Some(record) = rx.blocking_recv() {
+        let n = frame::encode(&record, &mut buf).map_err(|e| anyhow!("{}", e))?;
+        file.write_all(&buf[..n])?;
+
+        // flush whenever there's a pause, so the file is complete even if the process is killed
+        if rx.is_empty() {
+            file.flush()?;
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// read back a recording made by [`Recorder`]
+pub async fn load(path: impl AsRef<Path>) -> anyhow::Result<Vec<Record>> {
+    let path = path.as_ref();
+    let data = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Couldn't read recording {}", path.display()))?;
+
+    if !data.starts_with(MAGIC) {
+        // same magic, different format version
+        return Err(if data.starts_with(&MAGIC[..MAGIC.len() - 1]) {
+            anyhow!(
+                "{} was recorded by an incompatible version of Roland",
+                path.display()
+            )
+        } else {
+            anyhow!("{} is not a Roland recording", path.display())
+        });
+    }
+
+    // the records are only readable with the encoding they were written with
+    let version = data
+        .get(MAGIC.len()..HEADER_LEN)
+        .ok_or_else(|| anyhow!("{} is truncated", path.display()))?;
+    let version = u16::from_le_bytes([version[0], version[1]]);
+    if version != PROTOCOL_VERSION {
+        return Err(anyhow!(
+            "{} was recorded with protocol version {}, this build uses version {}",
+            path.display(),
+            version,
+            PROTOCOL_VERSION
+        ));
+    }
+
+    let body = &data[HEADER_LEN..];
+    let mut decoder = FrameDecoder::<MAX_FRAME_SIZE>::new();
+    let mut records = Vec::new();
+    for &b in body {
+        match decoder.push::<Record>(b) {
+            Some(Ok(record)) => records.push(record),
+            Some(Err(e)) => warn!("Skipped corrupt record: {}", e),
+            None => {}
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use roland_protocol::SerialCMD;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("roland-{}-{}.rec", name, std::process::id()))
+    }
+
+    /// the writer thread only finishes once every recorder is dropped, give it a moment
+    async fn load_all(path: &Path, n: usize) -> Vec<Record> {
+        for _ in 0..100 {
+            // nothing at all has been written before the first flush
+            if let Ok(records) = load(path).await
+                && records.len() >= n
+            {
+                return records;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        load(path).await.unwrap()
+    }
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry::Sent(Command {
+                seq: Some(1),
+                cmd: SerialCMD::HBridge((1000, -1000)),
+            }),
+            Entry::Received(SerialData::Ack(1)),
+            Entry::Received(SerialData::UltraSensor((12345, Some(42)))),
+        ]
+    }
+
+    fn record(path: &Path) {
+        let recorder = Recorder::create(path).unwrap();
+        for entry in entries() {
+            match entry {
+                Entry::Sent(cmd) => recorder.sent(&cmd),
+                Entry::Received(data) => recorder.received(&data),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let path = temp_path("round-trip");
+        record(&path);
+
+        let records = load_all(&path, 3).await;
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(data.starts_with(MAGIC));
+        assert_eq!(
+            data[MAGIC.len()..HEADER_LEN],
+            PROTOCOL_VERSION.to_le_bytes()
+        );
+        assert_eq!(
+            records.iter().map(|r| r.entry.clone()).collect::<Vec<_>>(),
+            entries()
+        );
+        assert!(records.windows(2).all(|w| w[0].t <= w[1].t));
+    }
+
+    #[tokio::test]
+    async fn skips_corrupt_records() {
+        let path = temp_path("corrupt");
+        record(&path);
+        load_all(&path, 3).await;
+
+        // break a byte in the middle of the second frame
+        let mut data = std::fs::read(&path).unwrap();
+        let second = HEADER_LEN
+            + data[HEADER_LEN..]
+                .iter()
+                .position(|&b| b == frame::SENTINEL)
+                .unwrap()
+            + 1;
+        let b = &mut data[second + 2];
+        *b = b.wrapping_add(1).max(1);
+        std::fs::write(&path, &data).unwrap();
+
+        let records = load(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut expected = entries();
+        expected.remove(1);
+        assert_eq!(
+            records.into_iter().map(|r| r.entry).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    async fn load_err(name: &str, data: &[u8]) -> String {
+        let path = temp_path(name);
+        std::fs::write(&path, data).unwrap();
+        let result = load(&path).await;
+        let _ = std::fs::remove_file(&path);
+        result.unwrap_err().to_string()
+    }
+
+    #[tokio::test]
+    async fn rejects_other_files() {
+        let e = load_err("not-a-recording", b"RIFF\0\0\0\0").await;
+        assert!(e.contains("not a Roland recording"), "{}", e);
+
+        let e = load_err("old-format", b"RLNDREC\x01\x02\x00").await;
+        assert!(e.contains("incompatible version"), "{}", e);
+
+        let e = load_err("truncated", MAGIC).await;
+        assert!(e.contains("truncated"), "{}", e);
+
+        let mut data = MAGIC.to_vec();
+        data.extend((PROTOCOL_VERSION - 1).to_le_bytes());
+        let e = load_err("old-protocol", &data).await;
+        assert!(e.contains("protocol version"), "{}", e);
+    }
+}
@@ -0,0 +1,109 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::anyhow;
+use log::{debug, info, trace};
+use roland_protocol::{SerialCMD, SerialData};
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::{
+    pico::{Pico, PicoChannels, sensors::SensorOptions},
+    record::{self, Entry, Record},
+    serial::{CmdRequest, LinkState},
+};
+
+/// play back a recording made with [`record::Recorder`]
+/// returns a Pico whose sensors see the recorded data with the original timing, divided by `speed`
+///
+/// commands sent to it are accepted (and confirmed) but otherwise ignored, except for a reset,
+/// which shuts it down like it would shut down the real link
+pub async fn init(
+    token: CancellationToken,
+    path: impl AsRef<Path>,
+    speed: f64,
+    sensors: SensorOptions,
+) -> anyhow::Result<Pico> {
+    if !speed.is_finite() || speed <= 0. {
+        return Err(anyhow!("Invalid replay speed {}", speed));
+    }
+
+    let records = record::load(&path).await?;
+    info!(
+        "Replaying {} records from {} at {}x speed",
+        records.len(),
+        path.as_ref().display(),
+        speed
+    );
+
+    let (cmd_tx, cmd_rx) = mpsc::channel::<CmdRequest>(32);
+    // a recording can't answer queries
+    let (query_tx, _) = mpsc::channel(1);
+    let (data_tx, data_rx) = broadcast::channel::<SerialData>(32);
+    let (link_tx, link_rx) = watch::channel(LinkState::Connected);
+    // the recorded timestamps belong to a clock that's long gone, arrival times are used instead
+    let (_, clock_rx) = watch::channel(None);
+    // there's no firmware to talk to
+    let (_, firmware_rx) = watch::channel(None);
+
+    {
+        let token = token.clone();
+        tokio::spawn(async move {
+            // a recording has no link to lose
+            let _link_tx = link_tx;
+            tokio::select! {
+                _ = play_task(records, speed, data_tx) => {},
+                _ = cmd_task(cmd_rx) => {
+                    debug!("[Replay] task shutting down");
+                    token.cancel();
+                },
+                _ = token.cancelled() => {},
+            }
+        });
+    }
+
+    Ok(Pico::new(
+        PicoChannels {
+            cmd_tx,
+            query_tx,
+            data_rx,
+            link_rx,
+            clock_rx,
+            firmware_rx,
+        },
+        sensors,
+        token,
+    ))
+}
+
+/// send the recorded data on schedule, then wait forever
+async fn play_task(records: Vec<Record>, speed: f64, data_tx: broadcast::Sender<SerialData>) {
+    let start = time::Instant::now();
+    for Record { t, entry } in records {
+        time::sleep_until(start + Duration::from_micros(t).div_f64(speed)).await;
+        match entry {
+            Entry::Received(data) => {
+                trace!("Replayed: {:?}", data);
+                let _ = data_tx.send(data);
+            }
+            Entry::Sent(cmd) => trace!("Originally sent: {:?}", cmd),
+        }
+    }
+    info!("[Replay] end of recording");
+    std::future::pending().await
+}
+
+/// swallow the commands, returns once a reset was received
+async fn cmd_task(mut cmd_rx: mpsc::Receiver<CmdRequest>) {
+    while let Some(CmdRequest { cmd, confirm }) = cmd_rx.recv().await {
+        trace!("Ignored: {:?}", cmd);
+        if let Some(confirm) = confirm {
+            let _ = confirm.send(Ok(()));
+        }
+        if let SerialCMD::Reset = cmd {
+            return;
+        }
+    }
+}
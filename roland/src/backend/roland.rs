@@ -4,7 +4,11 @@ use tokio::time::{self, sleep};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    backend::{pico::Pico, serial},
+    backend::{
+        Backend,
+        pico::Pico,
+        serial::{self, LinkOptions},
+    },
     util::{
         color::{HSV, RGB},
         pid::PID,
@@ -15,16 +19,22 @@ use crate::{
 /// all primitive and complex control procedures are defined here
 /// it can be cheaply cloned
 #[derive(Clone)]
-pub struct Roland {
-    pub pico: Pico,
+pub struct Roland<B: Backend = Pico> {
+    pub pico: B,
 }
 
-impl Roland {
-    pub async fn init(token: CancellationToken) -> anyhow::Result<Self> {
+impl Roland<Pico> {
+    pub async fn init(token: CancellationToken, options: LinkOptions) -> anyhow::Result<Self> {
         Ok(Self {
-            pico: serial::init(token).await?,
+            pico: serial::init(token, options).await?,
         })
     }
+}
+
+impl<B: Backend> Roland<B> {
+    pub fn new(pico: B) -> Self {
+        Self { pico }
+    }
 
     pub async fn reset(&mut self) -> anyhow::Result<()> {
         self.pico.reset().await?;
@@ -35,7 +45,7 @@ impl Roland {
     pub async fn track_sensor_test(&self) -> anyhow::Result<()> {
         let mut track_rx = self.pico.subscribe_track();
         loop {
-            let track = *track_rx.borrow_and_update();
+            let track = track_rx.borrow_and_update().value;
             info!("{:?}", track);
             track_rx.changed().await?;
         }
@@ -43,7 +53,7 @@ impl Roland {
 
     pub async fn servo_test(&mut self) -> anyhow::Result<()> {
         loop {
-            for d in [-30, 0, 30, 0] {
+            for d in [-30., 0., 30., 0.] {
                 self.pico.set_servo(d).await?;
                 info!("Servo set to {}", d);
                 sleep(Duration::from_secs(2)).await;
@@ -69,13 +79,20 @@ impl Roland {
         let mut ultra_rx = self.pico.subscribe_ultra();
         let mut last_t = Instant::now();
         loop {
-            let d = match *ultra_rx.borrow_and_update() {
-                Some(d) => d,
-                None => 0,
+            let ultra = ultra_rx.borrow_and_update().value;
+            let raw = match ultra.raw {
+                Some(Ok(d)) => format!("{:>5.1} cm", d),
+                Some(Err(e)) => e.to_string(),
+                None => "-".to_string(),
             };
 
             let now = Instant::now();
-            info!("{:>3} cm | {:>3} ms", d, (now - last_t).as_millis());
+            info!(
+                "{:>5.1} cm | raw {} | {:>3} ms",
+                ultra.distance.unwrap_or(0.),
+                raw,
+                (now - last_t).as_millis()
+            );
             last_t = now;
 
             ultra_rx.changed().await?;
@@ -97,13 +114,14 @@ impl Roland {
         let mut pid = PID::new(500., 10., 0., -5., 5., sp as f64);
 
         loop {
-            let pv = (*ultra_rx.borrow_and_update()).unwrap_or(sp);
+            let ultra = *ultra_rx.borrow_and_update();
+            let pv = ultra.value.distance.unwrap_or(sp as f32);
 
-            let speed = -pid.step(pv as f64).round() as i32;
+            let speed = -pid.step(pv as f64, ultra.time).round() as i32;
             let speed =
                 speed.signum() * (speed.abs() + 20000).clamp(0, (0xffff as f64 * 0.8) as i32);
 
-            info!("{:>4} {:>5}", pv, speed);
+            info!("{:>5.1} {:>5}", pv, speed);
             self.pico.set_motor(speed, speed).await?;
 
             ultra_rx.changed().await?;
@@ -126,7 +144,7 @@ impl Roland {
         let mut track_rx = self.pico.subscribe_track();
 
         loop {
-            let [_a, b, c, _d] = *track_rx.borrow_and_update();
+            let [_a, b, c, _d] = track_rx.borrow_and_update().value;
 
             let state = match (b, c) {
                 (false, false) => TrackState::OnLine,
@@ -166,8 +184,12 @@ impl Roland {
                     TrackState::Unknown => (255, 255, 255),
                 };
 
-                self.pico.set_motor(left, right).await?;
-                self.pico.set_led(r, g, b).await?;
+                self.pico
+                    .batch()
+                    .motor(left, right)
+                    .led(r, g, b)
+                    .send()
+                    .await?;
 
                 info!("{:?}", state);
 
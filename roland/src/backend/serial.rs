@@ -1,146 +1,1017 @@
+use std::{
+    collections::HashMap,
+    future,
+    time::{Duration, Instant},
+};
+
 use anyhow::anyhow;
-use log::{debug, error, info, trace};
-use postcard::{from_bytes, to_stdvec};
-use serde::{Deserialize, Serialize};
+use log::{debug, error, info, trace, warn};
+use roland_protocol::{
+    Batch, Capabilities, Command, DEFAULT_WATCHDOG_TIMEOUT, Hello, LedEffect, MIN_PROTOCOL_VERSION,
+    MotorRamp, PROTOCOL_VERSION, Query, Rejection, Response, SerialCMD, SerialData, ServoMotion,
+    ServoMove, StopMode, Timestamp, TrackConfig, UltraConfig,
+    frame::{self, FrameDecoder, MAX_FRAME_SIZE, SENTINEL},
+};
+use serde::Serialize;
 use tokio::{
-    fs,
-    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf, split},
-    sync::{broadcast, mpsc},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf, split},
+    sync::{broadcast, mpsc, oneshot, watch},
+    time,
 };
 use tokio_serial::{SerialPortBuilderExt, SerialStream};
 use tokio_util::sync::CancellationToken;
 
-use crate::backend::pico::Pico;
-
-#[derive(Deserialize, Debug, Clone)]
-pub enum TrackSensorID {
-    L1,
-    L2,
-    R1,
-    R2,
-}
-
-/// data packet coming from the pico
-/// currently it's only used for sensor data
-#[derive(Deserialize, Debug, Clone)]
-pub enum SerialData {
-    /// measured distance in cm
-    UltraSensor(Option<u16>),
-    /// sensor id and value
-    TrackSensor((TrackSensorID, bool)),
-}
-
-/// command packet for direct control of devices managed by the pico
-#[derive(Serialize, Debug)]
-pub enum SerialCMD {
-    /// frequency (Hz)
-    Buzzer(u16),
-    /// RGB color (0 to 255)
-    LED((u8, u8, u8)),
-    /// rotation in degrees (0 is the midpoint, -90 to 90)
-    Servo(i8),
-    /// duty cycle (sign is direction)
-    HBridge((i32, i32)),
-    /// this variant only exists on the pi side
-    /// upon receiving this message, all commands defined listed in it will get sent, and the serial is
-    /// closed
+use crate::backend::{
+    clock::{Clock, ClockSync},
+    discovery::{self, DeviceSelector},
+    firmware_log,
+    pico::{Pico, PicoChannels, sensors::SensorOptions},
+    record::Recorder,
+};
+
+/// how long to wait for an ack before retransmitting a command
+const ACK_TIMEOUT: Duration = Duration::from_millis(50);
+/// number of retransmissions before giving up on a command
+const MAX_RETRIES: u8 = 3;
+/// a heartbeat is sent if no other command went out for this long, to keep the pico's watchdog
+/// from tripping
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+/// how often the pico's clock is sampled
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// how long to wait for the pico to answer the hello
+const HELLO_TIMEOUT: Duration = Duration::from_millis(200);
+/// number of hellos sent before giving up on a connection
+const HELLO_ATTEMPTS: u8 = 3;
+
+/// first delay between reconnection attempts, doubled after every failure
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// the link is reported as [`LinkState::Lost`] if reconnecting takes longer than this
+const LOST_AFTER: Duration = Duration::from_secs(10);
+
+/// state of the serial connection to the pico
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Connected,
+    /// the connection dropped, the port is being rediscovered and reopened
+    Reconnecting,
+    /// reconnecting failed for a while, attempts continue in the background
+    Lost,
+}
+
+/// what to do with the actuators after the link comes back
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReconnectPolicy {
+    /// set every actuator to a neutral state
+    #[default]
     Reset,
+    /// re-apply the last commanded state of every actuator
+    Restore,
 }
 
-/// try finding the pico device
-/// currently it returns the path for the first device named ttyACM*
-/// TODO: make this actually verify that the device is a pico
-async fn find_pico_path() -> anyhow::Result<String> {
-    let mut entries = fs::read_dir("/dev").await?;
+/// everything that can be configured about the link
+#[derive(Clone)]
+pub struct LinkOptions {
+    pub device: DeviceSelector,
+    pub policy: ReconnectPolicy,
+    /// all traffic is written here, if given
+    pub recorder: Option<Recorder>,
+    /// firmware watchdog timeout (ms), 0 disables it
+    pub watchdog: u16,
+    pub motor_ramp: MotorRamp,
+    pub stop_mode: StopMode,
+    pub ultra: UltraConfig,
+    pub track: TrackConfig,
+    pub sensors: SensorOptions,
+}
 
-    while let Ok(Some(entry)) = entries.next_entry().await {
-        if let Some(name) = entry.file_name().to_str()
-            && name.starts_with("ttyACM")
-        {
-            return Ok(format!("/dev/{}", name));
+impl Default for LinkOptions {
+    fn default() -> Self {
+        Self {
+            device: DeviceSelector::default(),
+            policy: ReconnectPolicy::default(),
+            recorder: None,
+            watchdog: DEFAULT_WATCHDOG_TIMEOUT,
+            motor_ramp: MotorRamp::default(),
+            stop_mode: StopMode::default(),
+            ultra: UltraConfig::default(),
+            track: TrackConfig::default(),
+            sensors: SensorOptions::default(),
+        }
+    }
+}
+
+/// what the pico told about itself when the connection was established
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    /// protocol version of the firmware
+    pub protocol: u16,
+    /// the capabilities both sides support, only these are used
+    pub capabilities: Capabilities,
+}
+
+/// a freshly opened port to a pico that completed the hello exchange
+struct Connection {
+    port: SerialStream,
+    /// might hold a partial frame that arrived right after the hello
+    decoder: FrameDecoder<MAX_FRAME_SIZE>,
+    firmware: FirmwareInfo,
+    /// the firmware's log port, if it has one
+    log: Option<SerialStream>,
+}
+
+/// a command queued for sending
+/// if `confirm` is set, the command is sent in reliable mode and the result is reported back once
+/// the pico acknowledged (or refused) it
+pub struct CmdRequest {
+    pub cmd: SerialCMD,
+    pub confirm: Option<oneshot::Sender<anyhow::Result<()>>>,
+}
+
+/// a query waiting to be sent, the answer is sent to `respond`
+pub struct QueryRequest {
+    pub query: Query,
+    pub respond: oneshot::Sender<Response>,
+}
+
+/// answers the read task forwards to the write task
+enum Reply {
+    /// answer to a reliable command
+    Ack(u16, Result<(), Rejection>),
+    /// answer to a sync request, and when it arrived
+    Sync(u16, Timestamp, time::Instant),
+    /// answer to a query
+    Response(u16, Response),
+}
+
+/// a reliable command waiting for its ack
+struct PendingCmd {
+    cmd: SerialCMD,
+    confirm: oneshot::Sender<anyhow::Result<()>>,
+    sent: Instant,
+    retries: u8,
+}
+
+/// last commanded state of every actuator
+#[derive(Debug, Default)]
+struct Actuators {
+    buzzer: u16,
+    led: (u8, u8, u8),
+    /// 0.01°
+    servo: i16,
+    motor: (i32, i32),
+}
+
+impl Actuators {
+    fn update(&mut self, cmd: &SerialCMD) {
+        match *cmd {
+            SerialCMD::Buzzer(freq) => self.buzzer = freq,
+            // melodies are over by the time the pico is back
+            SerialCMD::Melody(_) => self.buzzer = 0,
+            SerialCMD::LED(rgb) => self.led = rgb,
+            SerialCMD::LedEffect(LedEffect::Solid(rgb) | LedEffect::Fade { color: rgb, .. }) => {
+                self.led = rgb
+            }
+            SerialCMD::Servo(deg) => self.servo = deg as i16 * 100,
+            SerialCMD::HBridge(speed) => self.motor = speed,
+            SerialCMD::Reset => *self = Self::default(),
+            SerialCMD::EmergencyStop => self.motor = (0, 0),
+            SerialCMD::ServoMove(m) => self.servo = m.angle,
+            SerialCMD::Batch(batch) => batch.commands().for_each(|cmd| self.update(&cmd)),
+            SerialCMD::Heartbeat
+            | SerialCMD::Watchdog(_)
+            | SerialCMD::Sync(_)
+            | SerialCMD::Query(_)
+            | SerialCMD::Hello(_)
+            | SerialCMD::SetCalibration(_)
+            | SerialCMD::SaveCalibration
+            | SerialCMD::Reboot(_)
+            | SerialCMD::MotorRamp(_)
+            | SerialCMD::StopMode(_)
+            | SerialCMD::ServoSweep(_)
+            | SerialCMD::ServoDetach
+            | SerialCMD::LedEffect(_)
+            | SerialCMD::UltraConfig(_)
+            | SerialCMD::TrackConfig(_)
+            | SerialCMD::Stream(_) => {}
+        }
+    }
+
+    /// commands that bring the hardware to this state, using what the firmware supports
+    fn commands(&self, capabilities: Capabilities) -> [SerialCMD; 4] {
+        let servo = ServoMove {
+            angle: self.servo,
+            motion: ServoMotion::Instant,
+        };
+        [
+            SerialCMD::Buzzer(self.buzzer),
+            SerialCMD::LED(self.led),
+            if capabilities.contains(Capabilities::SERVO_MOTION) {
+                SerialCMD::ServoMove(servo)
+            } else {
+                SerialCMD::Servo(servo.degrees())
+            },
+            SerialCMD::HBridge(self.motor),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Actuator {
+    Buzzer,
+    Led,
+    Servo,
+    Motor,
+}
+
+/// the actuators `cmd` sets, stops set all of them
+fn targets(cmd: &SerialCMD) -> Vec<Actuator> {
+    match cmd {
+        SerialCMD::Buzzer(_) | SerialCMD::Melody(_) => vec![Actuator::Buzzer],
+        SerialCMD::LED(_) | SerialCMD::LedEffect(_) => vec![Actuator::Led],
+        SerialCMD::Servo(_)
+        | SerialCMD::ServoMove(_)
+        | SerialCMD::ServoSweep(_)
+        | SerialCMD::ServoDetach => vec![Actuator::Servo],
+        SerialCMD::HBridge(_) => vec![Actuator::Motor],
+        SerialCMD::Batch(batch) => [
+            batch.buzzer.map(|_| Actuator::Buzzer),
+            batch.led.map(|_| Actuator::Led),
+            batch.servo.map(|_| Actuator::Servo),
+            batch.motor.map(|_| Actuator::Motor),
+        ]
+        .into_iter()
+        .flatten()
+        .collect(),
+        SerialCMD::Reset | SerialCMD::EmergencyStop => vec![
+            Actuator::Buzzer,
+            Actuator::Led,
+            Actuator::Servo,
+            Actuator::Motor,
+        ],
+        _ => vec![],
+    }
+}
+
+/// what of `older` can still be retransmitted after `newer` was sent, without undoing any of it
+fn remainder(older: &SerialCMD, newer: &SerialCMD) -> Option<SerialCMD> {
+    // chunks of the same melody are appended to it, and already queued ones are ignored
+    if let (SerialCMD::Melody(a), SerialCMD::Melody(b)) = (older, newer)
+        && a.id == b.id
+    {
+        return Some(older.clone());
+    }
+
+    let newer = targets(newer);
+    match older {
+        SerialCMD::Batch(batch) => {
+            let mut batch = *batch;
+            if newer.contains(&Actuator::Buzzer) {
+                batch.buzzer = None;
+            }
+            if newer.contains(&Actuator::Led) {
+                batch.led = None;
+            }
+            if newer.contains(&Actuator::Servo) {
+                batch.servo = None;
+            }
+            if newer.contains(&Actuator::Motor) {
+                batch.motor = None;
+            }
+            (batch != Batch::default()).then_some(SerialCMD::Batch(batch))
+        }
+        older => (!targets(older).iter().any(|a| newer.contains(a))).then(|| older.clone()),
+    }
+}
+
+/// fail the commands waiting for an ack that `newer` overtakes, retransmitting them would undo it
+/// the firmware applies commands in the order they arrive, only a retransmission can come after
+/// a newer command for the same actuator. a stop also makes the firmware drop the queued actuator
+/// commands without answering
+/// batches that are only partly overtaken keep retransmitting the rest
+fn supersede(pending: &mut HashMap<u16, PendingCmd>, newer: &SerialCMD) {
+    let overtaken = pending.extract_if(|_, p| match remainder(&p.cmd, newer) {
+        Some(cmd) => {
+            p.cmd = cmd;
+            false
         }
+        None => true,
+    });
+    for (seq, p) in overtaken {
+        debug!("{:?} (#{}) was overtaken by {:?}", p.cmd, seq, newer);
+        let _ = p
+            .confirm
+            .send(Err(anyhow!("{:?} was overtaken by {:?}", p.cmd, newer)));
     }
+}
 
-    Err(anyhow!("Pico not found"))
+/// owns the serial port and everything that has to survive reconnecting it
+struct Link {
+    options: LinkOptions,
+    cmd_rx: mpsc::Receiver<CmdRequest>,
+    query_rx: mpsc::Receiver<QueryRequest>,
+    data_tx: broadcast::Sender<SerialData>,
+    link_tx: watch::Sender<LinkState>,
+    clock_tx: watch::Sender<Option<Clock>>,
+    firmware_tx: watch::Sender<Option<FirmwareInfo>>,
+    clock_sync: ClockSync,
+    /// id and send time of the last sync request
+    sync: (u16, time::Instant),
+    seq: u16,
+    pending: HashMap<u16, PendingCmd>,
+    query_id: u16,
+    /// queries waiting for an answer
+    queries: HashMap<u16, oneshot::Sender<Response>>,
+    actuators: Actuators,
 }
 
 /// initialize serial communication with the Pico
 /// returns a clone-able Pico device
-pub async fn init(token: CancellationToken) -> anyhow::Result<Pico> {
-    let (cmd_tx, cmd_rx) = mpsc::channel::<SerialCMD>(32);
-    let (data_tx, data_rx) = broadcast::channel::<SerialData>(32);
+///
+/// the initial connection has to succeed, including the protocol version check, after that the
+/// link is reestablished automatically whenever it drops
+pub async fn init(token: CancellationToken, options: LinkOptions) -> anyhow::Result<Pico> {
+    let min_watchdog = 2 * HEARTBEAT_INTERVAL.as_millis() as u16;
+    if options.watchdog != 0 && options.watchdog < min_watchdog {
+        return Err(anyhow!(
+            "Watchdog timeout has to be at least {}ms, or 0 to disable it",
+            min_watchdog
+        ));
+    }
 
-    let path = find_pico_path().await?;
-    let port = tokio_serial::new(&path, 115200).open_native_async()?;
+    let (cmd_tx, cmd_rx) = mpsc::channel::<CmdRequest>(32);
+    let (query_tx, query_rx) = mpsc::channel::<QueryRequest>(32);
+    let (data_tx, data_rx) = broadcast::channel::<SerialData>(32);
+    let (link_tx, link_rx) = watch::channel(LinkState::Connected);
+    let (clock_tx, clock_rx) = watch::channel(None);
 
-    info!("TTY-ACM port opened on {}", path);
+    let conn = connect(&options).await?;
+    let sensors = options.sensors;
+    let (firmware_tx, firmware_rx) = watch::channel(Some(conn.firmware));
 
-    let (reader, writer) = split(port);
+    let link = Link {
+        options,
+        cmd_rx,
+        query_rx,
+        data_tx,
+        link_tx,
+        clock_tx,
+        firmware_tx,
+        clock_sync: ClockSync::default(),
+        sync: (0, time::Instant::now()),
+        seq: 0,
+        pending: HashMap::new(),
+        query_id: 0,
+        queries: HashMap::new(),
+        actuators: Actuators::default(),
+    };
 
     {
         let token = token.clone();
         tokio::spawn(async move {
             tokio::select! {
-                ret = read_task(reader, data_tx) => {
-                    match ret {
-                        Ok(()) => debug!("[Serial Data] task shutting down"),
-                        Err(e) => error!("[Serial Data] task shutting down: {}", e),
-                    }
+                _ = link.run(conn) => {
+                    debug!("[Serial] task shutting down");
                     token.cancel();
                 },
-                ret = write_task(writer, cmd_rx) => {
-                    match ret {
-                        Ok(()) => debug!("[Serial Write] task shutting down"),
-                        Err(e) => error!("[Serial Write] task shutting down: {}", e),
-                    }
-                    token.cancel();
-                }
                 _ = token.cancelled() => {},
             }
         });
     }
 
-    Ok(Pico::new(cmd_tx, data_rx, token))
+    Ok(Pico::new(
+        PicoChannels {
+            cmd_tx,
+            query_tx,
+            data_rx,
+            link_rx,
+            clock_rx,
+            firmware_rx,
+        },
+        sensors,
+        token,
+    ))
+}
+
+/// open the pico's port and check that we can talk to it
+async fn connect(options: &LinkOptions) -> anyhow::Result<Connection> {
+    let (path, mut port) = open(&options.device).await?;
+    let (firmware, decoder) = handshake(&mut port, &options.recorder).await?;
+    let log = open_log(&path).await;
+
+    info!(
+        "Firmware speaks protocol v{}, using [{}]",
+        firmware.protocol,
+        firmware.capabilities.names().collect::<Vec<_>>().join(", ")
+    );
+    let missing = Capabilities::all().intersection(Capabilities(!firmware.capabilities.0));
+    if missing != Capabilities::default() {
+        warn!(
+            "Firmware doesn't support [{}], flash a newer one to use them",
+            missing.names().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    Ok(Connection {
+        port,
+        decoder,
+        firmware,
+        log,
+    })
+}
+
+/// exchange hellos with the pico and negotiate the capabilities to use
+/// returns the decoder used for reading, which has to be kept for the rest of the connection
+async fn handshake<P: AsyncRead + AsyncWrite + Unpin>(
+    port: &mut P,
+    recorder: &Option<Recorder>,
+) -> anyhow::Result<(FirmwareInfo, FrameDecoder<MAX_FRAME_SIZE>)> {
+    let ours = Hello::new(Capabilities::all());
+    let mut buf = [0u8; MAX_FRAME_SIZE];
+    let mut decoder = FrameDecoder::<MAX_FRAME_SIZE>::new();
+
+    // flush whatever partial frame the pico might still be holding from a previous session
+    port.write_all(&[SENTINEL]).await?;
+
+    for _ in 0..HELLO_ATTEMPTS {
+        let cmd = Command {
+            seq: None,
+            cmd: SerialCMD::Hello(ours),
+        };
+        send_cmd(port, &mut buf, cmd, recorder).await?;
+
+        let deadline = time::Instant::now() + HELLO_TIMEOUT;
+        // read byte by byte, so nothing after the hello is lost
+        while let Ok(b) = time::timeout_at(deadline, port.read_u8()).await {
+            let data = match decoder.push::<SerialData>(b?) {
+                Some(Ok(data)) => data,
+                // leftovers from before the sentinel
+                Some(Err(_)) | None => continue,
+            };
+            if let Some(recorder) = recorder {
+                recorder.received(&data);
+            }
+
+            let SerialData::Hello(theirs) = data else {
+                trace!("Received before the hello: {:?}", data);
+                continue;
+            };
+            if !ours.compatible(&theirs) {
+                return Err(anyhow!(
+                    "Firmware speaks protocol v{} (compatible down to v{}), but this host speaks \
+                     v{} (compatible down to v{}), flash the firmware matching this version",
+                    theirs.protocol,
+                    theirs.min_protocol,
+                    PROTOCOL_VERSION,
+                    MIN_PROTOCOL_VERSION
+                ));
+            }
+            let firmware = FirmwareInfo {
+                protocol: theirs.protocol,
+                capabilities: ours.capabilities.intersection(theirs.capabilities),
+            };
+            return Ok((firmware, decoder));
+        }
+    }
+
+    Err(anyhow!(
+        "The pico never answered the hello, its firmware is probably older than protocol v{}, \
+         flash the firmware matching this version",
+        MIN_PROTOCOL_VERSION
+    ))
+}
+
+/// find and open the pico's serial port
+async fn open(device: &DeviceSelector) -> anyhow::Result<(String, SerialStream)> {
+    let path = discovery::find_pico(device).await?;
+    let port = tokio_serial::new(&path, 115200).open_native_async()?;
+
+    info!("TTY-ACM port opened on {}", path);
+
+    Ok((path, port))
+}
+
+/// open the log port of the pico whose command port is at `cmd_path`, if it has one
+async fn open_log(cmd_path: &str) -> Option<SerialStream> {
+    let Some(path) = discovery::find_log_port(cmd_path).await else {
+        debug!("No firmware log port found next to {}", cmd_path);
+        return None;
+    };
+    match tokio_serial::new(&path, 115200).open_native_async() {
+        Ok(port) => {
+            info!("Firmware log port opened on {}", path);
+            Some(port)
+        }
+        Err(e) => {
+            warn!("Couldn't open the firmware log port {}: {}", path, e);
+            None
+        }
+    }
 }
 
-/// reads and deserializes all incoming traffic, forwarding it to the data channel
+impl Link {
+    /// serve connections until shutdown
+    async fn run(mut self, mut conn: Connection) {
+        let mut restore = Vec::new();
+        loop {
+            self.firmware_tx.send_replace(Some(conn.firmware));
+            self.link_tx.send_replace(LinkState::Connected);
+
+            match self.serve(conn, &restore).await {
+                Ok(()) => return,
+                Err(e) => error!("[Serial] link lost: {}", e),
+            }
+
+            conn = match self.reconnect().await {
+                Some(conn) => conn,
+                None => return,
+            };
+
+            if self.options.policy == ReconnectPolicy::Reset {
+                self.actuators = Actuators::default();
+            }
+            restore = self.actuators.commands(conn.firmware.capabilities).to_vec();
+            info!("[Serial] link reestablished, applying {:?}", restore);
+        }
+    }
+
+    /// run a single connection, sending the `restore` commands first
+    /// returns `Ok` on shutdown, and the cause of the disconnect otherwise
+    async fn serve(&mut self, conn: Connection, restore: &[SerialCMD]) -> anyhow::Result<()> {
+        let Connection {
+            port,
+            decoder,
+            firmware,
+            log,
+        } = conn;
+        let (reader, mut writer) = split(port);
+        let (reply_tx, reply_rx) = mpsc::channel::<Reply>(32);
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+
+        // this might be a different pico, or the same one after a reboot
+        self.clock_sync.reset();
+        self.clock_tx.send_replace(None);
+
+        // the firmware forgets these on reboot
+        let mut settings = Vec::new();
+        if firmware.capabilities.contains(Capabilities::WATCHDOG) {
+            settings.push(SerialCMD::Watchdog(self.options.watchdog));
+        }
+        if firmware.capabilities.contains(Capabilities::MOTOR_CONTROL) {
+            settings.push(SerialCMD::MotorRamp(self.options.motor_ramp));
+            settings.push(SerialCMD::StopMode(self.options.stop_mode));
+        }
+        if firmware.capabilities.contains(Capabilities::ULTRA_READINGS) {
+            settings.push(SerialCMD::UltraConfig(self.options.ultra));
+        }
+        if firmware
+            .capabilities
+            .contains(Capabilities::TRACK_SNAPSHOTS)
+        {
+            settings.push(SerialCMD::TrackConfig(self.options.track));
+        }
+        for cmd in settings.iter().chain(restore) {
+            let cmd = Command {
+                seq: None,
+                cmd: cmd.clone(),
+            };
+            send_cmd(&mut writer, &mut buf, cmd, &self.options.recorder).await?;
+        }
+
+        let data_tx = self.data_tx.clone();
+        let recorder = self.options.recorder.clone();
+        tokio::select! {
+            ret = read_task(reader, decoder, data_tx, reply_tx, recorder) => ret,
+            ret = self.write_task(writer, reply_rx, firmware.capabilities) => ret,
+            // losing the log doesn't affect the link
+            ret = async {
+                if let Some(log) = log {
+                    firmware_log::forward(log).await;
+                }
+                future::pending().await
+            } => ret,
+        }
+    }
+
+    /// rediscover and reopen the port with exponential backoff
+    /// returns `None` if a shutdown was requested in the meantime
+    async fn reconnect(&mut self) -> Option<Connection> {
+        self.link_tx.send_replace(LinkState::Reconnecting);
+        self.firmware_tx.send_replace(None);
+
+        // nothing in flight can be acknowledged anymore
+        for (_, p) in self.pending.drain() {
+            let _ = p.confirm.send(Err(anyhow!(
+                "Link lost before {:?} was acknowledged",
+                p.cmd
+            )));
+        }
+        // dropping the senders lets the waiting queries fail right away
+        self.queries.clear();
+
+        let started = Instant::now();
+        let mut backoff = RECONNECT_MIN_BACKOFF;
+        loop {
+            // keep accepting commands while waiting, so callers don't block on a full channel
+            let deadline = time::Instant::now() + backoff;
+            loop {
+                tokio::select! {
+                    _ = time::sleep_until(deadline) => break,
+                    req = self.cmd_rx.recv() => match req {
+                        Some(req) => {
+                            if !self.handle_offline(req) {
+                                return None;
+                            }
+                        }
+                        None => return None,
+                    },
+                    // there's nobody to answer them
+                    Some(_) = self.query_rx.recv() => {}
+                }
+            }
+
+            match connect(&self.options).await {
+                Ok(conn) => return Some(conn),
+                Err(e) => debug!("[Serial] reconnect failed: {}", e),
+            }
+
+            if started.elapsed() >= LOST_AFTER && *self.link_tx.borrow() != LinkState::Lost {
+                warn!(
+                    "[Serial] pico unreachable for {:?}, still trying",
+                    LOST_AFTER
+                );
+                self.link_tx.send_replace(LinkState::Lost);
+            }
+            backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+        }
+    }
+
+    /// record a command that arrived while disconnected
+    /// returns `false` if it requested a shutdown
+    fn handle_offline(&mut self, req: CmdRequest) -> bool {
+        if let SerialCMD::Reset = req.cmd {
+            return false;
+        }
+        self.actuators.update(&req.cmd);
+        if let Some(confirm) = req.confirm {
+            let _ = confirm.send(Err(anyhow!("Link to the pico is down")));
+        }
+        true
+    }
+
+    /// serializes and frames commands going to the pico, keeping track of unacknowledged reliable
+    /// commands and retransmitting them on timeout
+    /// it also periodically samples the pico's clock
+    /// only the negotiated `capabilities` are used, everything else falls back to what the
+    /// firmware can do, or fails
+    async fn write_task(
+        &mut self,
+        mut writer: WriteHalf<SerialStream>,
+        mut reply_rx: mpsc::Receiver<Reply>,
+        capabilities: Capabilities,
+    ) -> anyhow::Result<()> {
+        let reliable = capabilities.contains(Capabilities::RELIABLE);
+        let watchdog = capabilities.contains(Capabilities::WATCHDOG) && self.options.watchdog != 0;
+
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let mut retransmit = time::interval(ACK_TIMEOUT / 2);
+        let mut heartbeat = time::interval(HEARTBEAT_INTERVAL / 2);
+        let mut sync = time::interval(SYNC_INTERVAL);
+        let mut last_sent = Instant::now();
+
+        loop {
+            tokio::select! {
+                req = self.cmd_rx.recv() => {
+                    let Some(CmdRequest { cmd, confirm }) = req else {
+                        return Ok(());
+                    };
+
+                    self.actuators.update(&cmd);
+                    supersede(&mut self.pending, &cmd);
+
+                    // the firmware can't confirm anything, so there's nothing to wait for
+                    let confirm = match confirm {
+                        Some(confirm) if !reliable => {
+                            let _ = confirm.send(Ok(()));
+                            None
+                        }
+                        confirm => confirm,
+                    };
+
+                    let cmd_seq = confirm.map(|confirm| {
+                        self.seq = self.seq.wrapping_add(1);
+                        self.pending.insert(
+                            self.seq,
+                            PendingCmd {
+                                cmd: cmd.clone(),
+                                confirm,
+                                sent: Instant::now(),
+                                retries: 0,
+                            },
+                        );
+                        self.seq
+                    });
+
+                    let reset = matches!(cmd, SerialCMD::Reset);
+                    let cmd = Command { seq: cmd_seq, cmd };
+                    send_cmd(&mut writer, &mut buf, cmd, &self.options.recorder).await?;
+                    last_sent = Instant::now();
+                    if reset {
+                        return Ok(());
+                    }
+                }
+                Some(QueryRequest { query, respond }) = self.query_rx.recv() => {
+                    // dropping `respond` fails the query
+                    if !capabilities.contains(Capabilities::QUERIES) {
+                        continue;
+                    }
+                    self.query_id = self.query_id.wrapping_add(1);
+                    self.queries.insert(self.query_id, respond);
+                    let cmd = Command {
+                        seq: None,
+                        cmd: SerialCMD::Query((self.query_id, query)),
+                    };
+                    send_cmd(&mut writer, &mut buf, cmd, &self.options.recorder).await?;
+                    last_sent = Instant::now();
+                }
+                Some(reply) = reply_rx.recv() => match reply {
+                    Reply::Ack(seq, ret) => match self.pending.remove(&seq) {
+                        Some(p) => {
+                            let _ = p
+                                .confirm
+                                .send(ret.map_err(|e| anyhow!("{:?} refused: {}", p.cmd, e)));
+                        }
+                        None => trace!("Ack for unknown command #{}", seq),
+                    },
+                    Reply::Sync(id, pico, received) => {
+                        let (sync_id, sent) = self.sync;
+                        // answers to older requests are too late to be accurate
+                        if id == sync_id {
+                            let clock = self.clock_sync.sample(sent, received, pico);
+                            trace!("Clock sync: {:?} round trip", received - sent);
+                            self.clock_tx.send_replace(Some(clock));
+                        }
+                    }
+                    Reply::Response(id, response) => match self.queries.remove(&id) {
+                        Some(respond) => {
+                            let _ = respond.send(response);
+                        }
+                        None => trace!("Response to unknown query #{}", id),
+                    },
+                },
+                _ = retransmit.tick() => {
+                    // the requester gave up on these
+                    self.queries.retain(|_, respond| !respond.is_closed());
+
+                    let expired: Vec<u16> = self
+                        .pending
+                        .iter()
+                        .filter(|(_, p)| p.sent.elapsed() >= ACK_TIMEOUT)
+                        .map(|(seq, _)| *seq)
+                        .collect();
+
+                    for seq in expired {
+                        let Some(p) = self.pending.get_mut(&seq) else {
+                            continue;
+                        };
+                        if p.retries >= MAX_RETRIES {
+                            let p = self.pending.remove(&seq).unwrap();
+                            warn!("{:?} (#{}) was never acknowledged", p.cmd, seq);
+                            let _ = p.confirm.send(Err(anyhow!("{:?} was never acknowledged", p.cmd)));
+                            continue;
+                        }
+
+                        p.retries += 1;
+                        p.sent = Instant::now();
+                        debug!("Retransmitting {:?} (#{}, retry {})", p.cmd, seq, p.retries);
+                        let cmd = Command {
+                            seq: Some(seq),
+                            cmd: p.cmd.clone(),
+                        };
+                        send_cmd(&mut writer, &mut buf, cmd, &self.options.recorder).await?;
+                        last_sent = Instant::now();
+                    }
+                }
+                _ = sync.tick(), if capabilities.contains(Capabilities::CLOCK_SYNC) => {
+                    let id = self.sync.0.wrapping_add(1);
+                    let cmd = Command {
+                        seq: None,
+                        cmd: SerialCMD::Sync(id),
+                    };
+                    self.sync = (id, time::Instant::now());
+                    send_cmd(&mut writer, &mut buf, cmd, &self.options.recorder).await?;
+                    last_sent = Instant::now();
+                }
+                _ = heartbeat.tick(), if watchdog => {
+                    if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                        let cmd = Command {
+                            seq: None,
+                            cmd: SerialCMD::Heartbeat,
+                        };
+                        send_cmd(&mut writer, &mut buf, cmd, &self.options.recorder).await?;
+                        last_sent = Instant::now();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// reads and decodes all incoming traffic, forwarding acks and answers to the write task and
+/// everything else to the data channel
+/// this only returns if the connection failed
 async fn read_task(
     mut reader: ReadHalf<SerialStream>,
+    mut decoder: FrameDecoder<MAX_FRAME_SIZE>,
     data_tx: broadcast::Sender<SerialData>,
+    reply_tx: mpsc::Sender<Reply>,
+    recorder: Option<Recorder>,
 ) -> anyhow::Result<()> {
     let mut buf = vec![0u8; 64];
     loop {
-        match reader.read(&mut buf).await? {
-            0 => {
-                return Err(anyhow!("Serial port closed by peer"));
-            }
-            n => match from_bytes::<SerialData>(&buf[..n]) {
-                Ok(data) => {
-                    if let Err(e) = data_tx.send(data.clone()) {
-                        error!("Couldn't send data: {}", e);
-                    } else {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("Serial port closed by peer"));
+        }
+
+        for &b in &buf[..n] {
+            let data = decoder.push::<SerialData>(b);
+            if let (Some(recorder), Some(Ok(data))) = (&recorder, &data) {
+                recorder.received(data);
+            }
+
+            match data {
+                Some(Ok(SerialData::Ack(seq))) => reply_tx.send(Reply::Ack(seq, Ok(()))).await?,
+                Some(Ok(SerialData::Nack((seq, reason)))) => {
+                    reply_tx.send(Reply::Ack(seq, Err(reason))).await?
+                }
+                Some(Ok(SerialData::Sync((id, t)))) => {
+                    let received = time::Instant::now();
+                    reply_tx.send(Reply::Sync(id, t, received)).await?
+                }
+                Some(Ok(SerialData::Response((id, response)))) => {
+                    reply_tx.send(Reply::Response(id, response)).await?
+                }
+                Some(Ok(data)) => {
+                    // nobody listening is not an error, the data is simply not needed
+                    if data_tx.send(data.clone()).is_ok() {
                         trace!("Received: {:?}", data)
                     }
                 }
-                Err(e) => error!("Couldn't parse data({:?}): {}", &buf[..n], e),
-            },
+                Some(Err(e)) => warn!(
+                    "Dropped frame: {} ({} framing errors so far)",
+                    e,
+                    decoder.errors()
+                ),
+                None => {}
+            }
         }
     }
 }
 
-/// serializes commands going to the pico
-async fn write_task(
-    mut writer: WriteHalf<SerialStream>,
-    mut cmd_rx: mpsc::Receiver<SerialCMD>,
+async fn send_cmd(
+    writer: &mut (impl AsyncWrite + Unpin),
+    buf: &mut [u8],
+    cmd: Command,
+    recorder: &Option<Recorder>,
 ) -> anyhow::Result<()> {
-    while let Some(cmd) = cmd_rx.recv().await {
-        let data = to_stdvec(&cmd).unwrap();
-        writer.write_all(&data).await?;
-        trace!("Sent: {:?}", cmd);
-
-        if let SerialCMD::Reset = cmd {
-            break;
-        }
+    let n = frame::encode(&cmd, buf).map_err(|e| anyhow!("{}", e))?;
+    writer.write_all(&buf[..n]).await?;
+    if let Some(recorder) = recorder {
+        recorder.sent(&cmd);
     }
+    trace!("Sent: {:?}", cmd);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use roland_protocol::{MelodyChunk, UltraConfig};
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    fn pending(cmd: SerialCMD) -> (PendingCmd, oneshot::Receiver<anyhow::Result<()>>) {
+        let (confirm, rx) = oneshot::channel();
+        let p = PendingCmd {
+            cmd,
+            confirm,
+            sent: Instant::now(),
+            retries: 0,
+        };
+        (p, rx)
+    }
+
+    #[test]
+    fn stop_supersedes_queued_actuator_commands() {
+        let mut queue = HashMap::new();
+        let (drive, mut drive_rx) = pending(SerialCMD::HBridge((0xffff, 0xffff)));
+        let (led, mut led_rx) = pending(SerialCMD::LED((255, 0, 0)));
+        let (config, mut config_rx) = pending(SerialCMD::UltraConfig(UltraConfig::DEFAULT));
+        queue.insert(1, drive);
+        queue.insert(2, led);
+        queue.insert(3, config);
+
+        supersede(&mut queue, &SerialCMD::EmergencyStop);
+
+        // retransmitting the drive would undo the stop
+        assert!(drive_rx.try_recv().unwrap().is_err());
+        assert!(led_rx.try_recv().unwrap().is_err());
+        // the config is still retransmitted and applied after the stop
+        assert_eq!(queue.keys().collect::<Vec<_>>(), [&3]);
+        assert!(config_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn older_drive_never_resent_after_newer() {
+        let mut queue = HashMap::new();
+        let (old_drive, mut old_drive_rx) = pending(SerialCMD::HBridge((1000, 1000)));
+        let (led, mut led_rx) = pending(SerialCMD::LED((255, 0, 0)));
+        queue.insert(1, old_drive);
+        queue.insert(2, led);
+
+        // the ack of the old drive got lost, then a new one goes out and is acked
+        let new_drive = SerialCMD::HBridge((2000, 2000));
+        supersede(&mut queue, &new_drive);
+        let (new_drive, _new_drive_rx) = pending(new_drive);
+        queue.insert(3, new_drive);
+        queue.remove(&3);
+
+        // nothing is left to bring the motors back to the old speed
+        assert!(old_drive_rx.try_recv().unwrap().is_err());
+        assert_eq!(queue.keys().collect::<Vec<_>>(), [&2]);
+        assert!(led_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn batch_partly_superseded() {
+        let mut queue = HashMap::new();
+        let (batch, mut batch_rx) = pending(SerialCMD::Batch(Batch {
+            led: Some((0, 255, 0)),
+            motor: Some((1000, 1000)),
+            ..Default::default()
+        }));
+        queue.insert(1, batch);
+
+        supersede(&mut queue, &SerialCMD::HBridge((0, 0)));
+        assert_eq!(
+            queue[&1].cmd,
+            SerialCMD::Batch(Batch {
+                led: Some((0, 255, 0)),
+                ..Default::default()
+            })
+        );
+        assert!(batch_rx.try_recv().is_err());
+
+        supersede(
+            &mut queue,
+            &SerialCMD::LedEffect(LedEffect::Solid((0, 0, 0))),
+        );
+        assert!(queue.is_empty());
+        assert!(batch_rx.try_recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn melody_chunks() {
+        let chunk = |id, offset| SerialCMD::Melody(MelodyChunk::new(id, offset, &[]));
+        let mut queue = HashMap::new();
+        let (first, _first_rx) = pending(chunk(1, 0));
+        queue.insert(1, first);
+
+        // the rest of the same melody is appended to it
+        supersede(&mut queue, &chunk(1, 16));
+        assert!(queue.contains_key(&1));
+
+        // a new melody or tone replaces it
+        supersede(&mut queue, &chunk(2, 0));
+        assert!(queue.is_empty());
+
+        let (first, _first_rx) = pending(chunk(1, 0));
+        queue.insert(1, first);
+        supersede(&mut queue, &SerialCMD::Buzzer(0));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn servo_restored_to_the_centidegree() {
+        let mut actuators = Actuators::default();
+        let m = ServoMove {
+            angle: 1234,
+            motion: ServoMotion::Instant,
+        };
+        actuators.update(&SerialCMD::ServoMove(m));
+
+        assert_eq!(
+            actuators.commands(Capabilities::SERVO_MOTION)[2],
+            SerialCMD::ServoMove(m)
+        );
+        // older firmware only takes whole degrees
+        assert_eq!(
+            actuators.commands(Capabilities::default())[2],
+            SerialCMD::Servo(12)
+        );
+
+        actuators.update(&SerialCMD::Servo(-45));
+        assert_eq!(actuators.servo, -4500);
+    }
+}
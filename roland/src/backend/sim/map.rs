@@ -0,0 +1,203 @@
+use std::path::Path;
+
+use anyhow::{Context, anyhow};
+
+/// 2D point in cm
+pub type Point = (f64, f64);
+
+/// world the simulated robot drives around in
+///
+/// loaded from a plain text file, one entry per line (all lengths in cm, angles in degrees):
+/// ```text
+/// # comment
+/// start <x> <y> <heading>
+/// width <line width>
+/// line <x> <y> <x> <y> ...
+/// wall <x> <y> <x> <y> ...
+/// ```
+/// `line` is a polyline the track sensors can see, `wall` is a polyline the ultra sensor can see,
+/// both can be repeated
+///
+/// this format replaces importing SVG or PNG drawings, a track is a handful of polylines anyway,
+/// and it doesn't need an XML or image parser
+#[derive(Debug, Clone)]
+pub struct Map {
+    pub lines: Vec<Vec<Point>>,
+    pub line_width: f64,
+    pub walls: Vec<Vec<Point>>,
+    /// x, y and heading (radians, counterclockwise from the x axis)
+    pub start: (f64, f64, f64),
+}
+
+impl Map {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read map {}", path.display()))?;
+        Self::parse(&src).with_context(|| format!("Invalid map {}", path.display()))
+    }
+
+    pub fn parse(src: &str) -> anyhow::Result<Self> {
+        let mut map = Self {
+            lines: Vec::new(),
+            line_width: 2.5,
+            walls: Vec::new(),
+            start: (0., 0., 0.),
+        };
+
+        for (i, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let Some(kind) = words.next() else {
+                continue;
+            };
+            let nums = words
+                .map(str::parse::<f64>)
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("line {}", i + 1))?;
+
+            match (kind, nums.as_slice()) {
+                ("start", &[x, y, heading]) => map.start = (x, y, heading.to_radians()),
+                ("width", &[w]) => map.line_width = w,
+                ("line" | "wall", nums) if nums.len() >= 4 && nums.len() % 2 == 0 => {
+                    let points = nums.chunks(2).map(|p| (p[0], p[1])).collect();
+                    match kind {
+                        "line" => map.lines.push(points),
+                        _ => map.walls.push(points),
+                    }
+                }
+                _ => return Err(anyhow!("line {}: invalid entry \"{}\"", i + 1, line.trim())),
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// is `p` over the line
+    pub fn on_line(&self, p: Point) -> bool {
+        segments(&self.lines).any(|(a, b)| segment_distance(p, a, b) <= self.line_width / 2.)
+    }
+
+    /// distance to the closest wall from `from` in the direction of `dir` (radians)
+    pub fn raycast(&self, from: Point, dir: f64) -> Option<f64> {
+        let d = (dir.cos(), dir.sin());
+        segments(&self.walls)
+            .filter_map(|(a, b)| ray_intersection(from, d, a, b))
+            .min_by(f64::total_cmp)
+    }
+}
+
+fn segments(polylines: &[Vec<Point>]) -> impl Iterator<Item = (Point, Point)> + '_ {
+    polylines
+        .iter()
+        .flat_map(|l| l.windows(2).map(|w| (w[0], w[1])))
+}
+
+fn segment_distance(p: Point, a: Point, b: Point) -> f64 {
+    let ab = (b.0 - a.0, b.1 - a.1);
+    let ap = (p.0 - a.0, p.1 - a.1);
+    let len2 = ab.0 * ab.0 + ab.1 * ab.1;
+    let t = if len2 > 0. {
+        ((ap.0 * ab.0 + ap.1 * ab.1) / len2).clamp(0., 1.)
+    } else {
+        0.
+    };
+    let closest = (a.0 + ab.0 * t, a.1 + ab.1 * t);
+    (p.0 - closest.0).hypot(p.1 - closest.1)
+}
+
+/// distance along the ray (`from`, unit vector `d`) to segment `a`-`b`, if they intersect
+fn ray_intersection(from: Point, d: Point, a: Point, b: Point) -> Option<f64> {
+    let ab = (b.0 - a.0, b.1 - a.1);
+    let denom = d.0 * ab.1 - d.1 * ab.0;
+    if denom.abs() < 1e-9 {
+        return None;
+    }
+    let af = (a.0 - from.0, a.1 - from.1);
+    let t = (af.0 * ab.1 - af.1 * ab.0) / denom;
+    let u = (af.0 * d.1 - af.1 * d.0) / denom;
+    (t >= 0. && (0. ..=1.).contains(&u)).then_some(t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn parse() {
+        let map = Map::parse(
+            "# a test map\n\
+             start 10 20 90\n\
+             \n\
+             width 3 # wider than the default\n\
+             line 0 0 100 0 100 100\n\
+             line 0 50 10 50\n\
+             wall -10 -10 -10 110\n",
+        )
+        .unwrap();
+        assert_eq!(
+            map.lines,
+            [
+                vec![(0., 0.), (100., 0.), (100., 100.)],
+                vec![(0., 50.), (10., 50.)]
+            ]
+        );
+        assert_eq!(map.walls, [vec![(-10., -10.), (-10., 110.)]]);
+        assert_eq!(map.line_width, 3.);
+        assert_eq!((map.start.0, map.start.1), (10., 20.));
+        assert!(close(map.start.2, std::f64::consts::FRAC_PI_2));
+
+        let empty = Map::parse("").unwrap();
+        assert!(empty.lines.is_empty() && empty.walls.is_empty());
+        assert_eq!(empty.line_width, 2.5);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for src in [
+            "start 1 2",
+            "width",
+            "line 0 0",
+            "line 0 0 1 1 2",
+            "wall 0 0 x 1",
+            "curve 0 0 1 1",
+        ] {
+            assert!(Map::parse(src).is_err(), "{:?} parsed", src);
+        }
+        let e = Map::parse("width 1\nline 0\n").unwrap_err();
+        assert!(format!("{:#}", e).contains("line 2"), "{:#}", e);
+    }
+
+    #[test]
+    fn on_line() {
+        let map = Map::parse("line 0 0 100 0 100 100").unwrap();
+        assert!(map.on_line((50., 0.)));
+        assert!(map.on_line((50., 1.)));
+        assert!(!map.on_line((50., 2.)));
+        // the line has round ends
+        assert!(map.on_line((101., 0.)));
+        assert!(!map.on_line((-2., 0.)));
+        // second segment
+        assert!(map.on_line((100.5, 60.)));
+        assert!(!map.on_line((50., 50.)));
+    }
+
+    #[test]
+    fn raycast() {
+        let map = Map::parse("wall 100 -50 100 50\nwall 200 -50 200 50").unwrap();
+        // the closest wall wins
+        assert!(close(map.raycast((0., 0.), 0.).unwrap(), 100.));
+        assert!(close(map.raycast((150., 0.), 0.).unwrap(), 50.));
+        let dir = 20f64.to_radians();
+        assert!(close(map.raycast((0., 0.), dir).unwrap(), 100. / dir.cos()));
+        // past the end of the walls, and away from them
+        assert_eq!(map.raycast((0., 0.), 30f64.to_radians()), None);
+        assert_eq!(map.raycast((0., 0.), std::f64::consts::PI), None);
+        // parallel to a wall
+        assert_eq!(map.raycast((0., 0.), std::f64::consts::FRAC_PI_2), None);
+    }
+}
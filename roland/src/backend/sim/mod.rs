@@ -0,0 +1,403 @@
+use std::{
+    f64::consts::PI,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::debug;
+use roland_protocol::{Fault, SerialCMD, ServoSweep, UltraConfig, UltraError};
+use tokio::{
+    sync::watch,
+    time::{self, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::{
+    Backend,
+    pico::sensors::{Reading, Sensors, TrackData, UltraData},
+    serial::LinkState,
+    sim::map::Map,
+};
+
+pub mod map;
+
+/// physics time step
+const DT: Duration = Duration::from_millis(5);
+/// ultra sensor measurement period, same as the firmware's
+const ULTRA_PERIOD: Duration = Duration::from_millis(UltraConfig::DEFAULT.period as u64);
+/// ultra sensor range (cm), same as the firmware's
+const ULTRA_RANGE: (f64, f64) = (2., 400.);
+
+/// wheel speed at full duty cycle (cm/s)
+const MAX_WHEEL_SPEED: f64 = 60.;
+/// distance between the wheels (cm)
+const WHEELBASE: f64 = 14.;
+/// how fast the wheels follow the commanded speed (s)
+const MOTOR_TIME_CONSTANT: f64 = 0.1;
+
+/// track sensor positions (cm forward, cm left of the center) in the order of [`TrackData`]
+const TRACK_SENSORS: [(f64, f64); 4] = [(8., 3.), (8., 0.8), (8., -0.8), (8., -3.)];
+/// ultra sensor position (cm forward of the center)
+const ULTRA_OFFSET: f64 = 6.;
+
+/// position and actuator state of the simulated robot
+#[derive(Debug, Clone, Default)]
+struct Robot {
+    x: f64,
+    y: f64,
+    heading: f64,
+    /// actual wheel speeds (cm/s)
+    speed: (f64, f64),
+    /// commanded duty cycles
+    duty: (i32, i32),
+    /// servo angle (0.01°, counterclockwise)
+    servo: i16,
+    /// the sweep the servo is doing, and how long it has been going (s)
+    sweep: Option<(ServoSweep, f64)>,
+}
+
+impl Robot {
+    fn step(&mut self, dt: f64) {
+        let target = (
+            self.duty.0.clamp(-0xffff, 0xffff) as f64 / 0xffff as f64 * MAX_WHEEL_SPEED,
+            self.duty.1.clamp(-0xffff, 0xffff) as f64 / 0xffff as f64 * MAX_WHEEL_SPEED,
+        );
+        let k = (dt / MOTOR_TIME_CONSTANT).min(1.);
+        self.speed.0 += (target.0 - self.speed.0) * k;
+        self.speed.1 += (target.1 - self.speed.1) * k;
+
+        let v = (self.speed.0 + self.speed.1) / 2.;
+        let w = (self.speed.1 - self.speed.0) / WHEELBASE;
+
+        self.heading = (self.heading + w * dt).rem_euclid(2. * PI);
+        self.x += v * self.heading.cos() * dt;
+        self.y += v * self.heading.sin() * dt;
+
+        if let Some((sweep, t)) = &mut self.sweep {
+            *t += dt;
+            // there and back again, like the firmware
+            let phase = (*t * 1000. / sweep.period as f64).fract();
+            let progress = 1. - (2. * phase - 1.).abs();
+            self.servo = (sweep.from as f64 + (sweep.to - sweep.from) as f64 * progress) as i16;
+        }
+    }
+
+    /// point the servo somewhere, ending the sweep
+    fn set_servo(&mut self, angle: i16) {
+        self.servo = angle;
+        self.sweep = None;
+    }
+
+    /// world position of a point given in robot coordinates
+    fn to_world(&self, (fwd, left): (f64, f64)) -> (f64, f64) {
+        let (sin, cos) = self.heading.sin_cos();
+        (
+            self.x + fwd * cos - left * sin,
+            self.y + fwd * sin + left * cos,
+        )
+    }
+
+    fn track(&self, map: &Map) -> TrackData {
+        // the sensors read low over the (dark) line
+        TRACK_SENSORS.map(|s| !map.on_line(self.to_world(s)))
+    }
+
+    /// true distance to the wall the ultra sensor is facing
+    fn range(&self, map: &Map) -> Option<f64> {
+        map.raycast(
+            self.to_world((ULTRA_OFFSET, 0.)),
+            self.heading + (self.servo as f64 / 100.).to_radians(),
+        )
+    }
+}
+
+/// summary of a simulation run
+#[derive(Debug, Clone, Default)]
+pub struct SimStats {
+    /// simulated time (s)
+    pub time: f64,
+    /// distance travelled by the center of the robot (cm)
+    pub distance: f64,
+    /// time spent with the center over the line (s)
+    pub on_line: f64,
+    range_samples: u32,
+    range_sum: f64,
+    range_sq_sum: f64,
+}
+
+impl SimStats {
+    /// root mean square deviation of the measured wall distance from `target` (cm)
+    pub fn range_error(&self, target: f64) -> Option<f64> {
+        if self.range_samples == 0 {
+            return None;
+        }
+        let n = self.range_samples as f64;
+        let mse = self.range_sq_sum / n - 2. * target * self.range_sum / n + target * target;
+        Some(mse.max(0.).sqrt())
+    }
+}
+
+struct World {
+    map: Map,
+    robot: Robot,
+    stats: SimStats,
+}
+
+/// simulated Roland, a differential drive robot moving around on a [`Map`]
+/// the simulation runs on tokio time, so with a paused clock it runs as fast as possible
+#[derive(Clone)]
+pub struct SimPico {
+    world: Arc<Mutex<World>>,
+    sensor_data: Sensors,
+    link_tx: watch::Sender<LinkState>,
+    /// the simulated firmware never crashes
+    fault_tx: watch::Sender<Option<Fault>>,
+    token: CancellationToken,
+}
+
+impl SimPico {
+    /// start the simulation, it stops once `token` is cancelled
+    /// like the real link, a reset cancels `token`
+    pub fn new(map: Map, token: CancellationToken) -> Self {
+        let (x, y, heading) = map.start;
+        let robot = Robot {
+            x,
+            y,
+            heading,
+            ..Default::default()
+        };
+
+        let s = Self {
+            world: Arc::new(Mutex::new(World {
+                map,
+                robot,
+                stats: SimStats::default(),
+            })),
+            sensor_data: Sensors::default(),
+            link_tx: watch::Sender::new(LinkState::Connected),
+            fault_tx: watch::Sender::new(None),
+            token,
+        };
+
+        {
+            let s = s.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = s.physics_task() => {},
+                    _ = s.token.cancelled() => debug!("[Sim] task shutting down"),
+                }
+            });
+        }
+
+        s
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.world.lock().unwrap().stats.clone()
+    }
+
+    async fn physics_task(&self) {
+        let mut tick = time::interval(DT);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut since_ultra = Duration::ZERO;
+        let dt = DT.as_secs_f64();
+
+        loop {
+            tick.tick().await;
+
+            let (track, ultra) = {
+                let mut world = self.world.lock().unwrap();
+                let World { map, robot, stats } = &mut *world;
+
+                let (x, y) = (robot.x, robot.y);
+                robot.step(dt);
+
+                stats.time += dt;
+                stats.distance += (robot.x - x).hypot(robot.y - y);
+                if map.on_line((robot.x, robot.y)) {
+                    stats.on_line += dt;
+                }
+
+                since_ultra += DT;
+                let ultra = if since_ultra >= ULTRA_PERIOD {
+                    since_ultra = Duration::ZERO;
+                    let raw = match robot.range(map) {
+                        Some(d) if (ULTRA_RANGE.0..=ULTRA_RANGE.1).contains(&d) => Ok(d),
+                        Some(_) => Err(UltraError::OutOfRange),
+                        None => Err(UltraError::NoEcho),
+                    };
+                    if let Ok(d) = raw {
+                        stats.range_samples += 1;
+                        stats.range_sum += d;
+                        stats.range_sq_sum += d * d;
+                    }
+                    // the firmware measures in mm
+                    Some(UltraData::unfiltered(
+                        raw.map(|d| ((d * 10.).round() / 10.) as f32),
+                    ))
+                } else {
+                    None
+                };
+
+                (robot.track(map), ultra)
+            };
+
+            // like the firmware, the track sensor only reports changes
+            self.sensor_data.track_sensor.send_if_modified(|current| {
+                let changed = current.value != track;
+                if changed {
+                    *current = Reading::now(track);
+                }
+                changed
+            });
+            if let Some(ultra) = ultra {
+                self.sensor_data
+                    .ultra_sensor
+                    .send_replace(Reading::now(ultra));
+            }
+        }
+    }
+}
+
+impl Backend for SimPico {
+    async fn command(&self, cmd: SerialCMD) -> anyhow::Result<()> {
+        let mut world = self.world.lock().unwrap();
+        match cmd {
+            SerialCMD::Servo(deg) => world.robot.set_servo(deg as i16 * 100),
+            SerialCMD::ServoMove(m) => world.robot.set_servo(m.angle),
+            SerialCMD::ServoSweep(sweep) => {
+                world.robot.servo = sweep.from;
+                world.robot.sweep = Some((sweep, 0.));
+            }
+            // nothing turns it by hand in here, so it stays where it was
+            SerialCMD::ServoDetach => world.robot.sweep = None,
+            SerialCMD::HBridge(duty) => world.robot.duty = duty,
+            SerialCMD::Batch(batch) => {
+                if let Some(angle) = batch.servo {
+                    world.robot.set_servo(angle);
+                }
+                if let Some(duty) = batch.motor {
+                    world.robot.duty = duty;
+                }
+            }
+            SerialCMD::Buzzer(_)
+            | SerialCMD::Melody(_)
+            | SerialCMD::LED(_)
+            | SerialCMD::LedEffect(_)
+            | SerialCMD::UltraConfig(_)
+            | SerialCMD::TrackConfig(_)
+            | SerialCMD::Stream(_)
+            | SerialCMD::Heartbeat
+            | SerialCMD::Watchdog(_)
+            | SerialCMD::Sync(_)
+            | SerialCMD::Query(_)
+            | SerialCMD::Hello(_)
+            | SerialCMD::SetCalibration(_)
+            | SerialCMD::SaveCalibration
+            | SerialCMD::Reboot(_)
+            | SerialCMD::MotorRamp(_)
+            | SerialCMD::StopMode(_) => {}
+            SerialCMD::EmergencyStop => world.robot.duty = (0, 0),
+            SerialCMD::Reset => {
+                world.robot.duty = (0, 0);
+                world.robot.set_servo(0);
+                self.token.cancel();
+            }
+        }
+        Ok(())
+    }
+
+    fn subscribe_ultra(&self) -> watch::Receiver<Reading<UltraData>> {
+        self.sensor_data.ultra_sensor.subscribe()
+    }
+
+    fn subscribe_track(&self) -> watch::Receiver<Reading<TrackData>> {
+        self.sensor_data.track_sensor.subscribe()
+    }
+
+    fn subscribe_link(&self) -> watch::Receiver<LinkState> {
+        self.link_tx.subscribe()
+    }
+
+    fn subscribe_fault(&self) -> watch::Receiver<Option<Fault>> {
+        self.fault_tx.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::FRAC_PI_2;
+
+    use super::*;
+
+    fn run(robot: &mut Robot, secs: f64) {
+        let dt = DT.as_secs_f64();
+        for _ in 0..(secs / dt).round() as u32 {
+            robot.step(dt);
+        }
+    }
+
+    #[test]
+    fn drive_straight() {
+        let mut robot = Robot {
+            duty: (0xffff, 0xffff),
+            ..Default::default()
+        };
+        run(&mut robot, 2.);
+        // the motors got up to speed long ago
+        assert!((robot.speed.0 - MAX_WHEEL_SPEED).abs() < 0.1);
+        assert!(robot.x > MAX_WHEEL_SPEED * 1.8 && robot.x < MAX_WHEEL_SPEED * 2.);
+        assert_eq!((robot.y, robot.heading), (0., 0.));
+
+        // only half as fast backwards
+        robot.duty = (-0x7fff, -0x7fff);
+        run(&mut robot, 2.);
+        assert!((robot.speed.0 + MAX_WHEEL_SPEED / 2.).abs() < 0.1);
+    }
+
+    #[test]
+    fn turn_in_place() {
+        let mut robot = Robot {
+            duty: (-0xffff, 0xffff),
+            ..Default::default()
+        };
+        run(&mut robot, 0.2);
+        // counterclockwise, without going anywhere
+        assert!(robot.heading > 0.);
+        assert!(robot.x.hypot(robot.y) < 1e-9);
+    }
+
+    #[test]
+    fn to_world() {
+        let robot = Robot {
+            x: 10.,
+            y: 20.,
+            heading: FRAC_PI_2,
+            ..Default::default()
+        };
+        let (x, y) = robot.to_world((5., 1.));
+        assert!((x - 9.).abs() < 1e-9 && (y - 25.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn servo_sweep() {
+        let mut robot = Robot::default();
+        let sweep = ServoSweep {
+            from: -1000,
+            to: 1000,
+            period: 1000,
+        };
+        robot.sweep = Some((sweep, 0.));
+
+        run(&mut robot, 0.25);
+        assert!(robot.servo.abs() <= 1);
+        run(&mut robot, 0.25);
+        assert!((robot.servo - 1000).abs() <= 1);
+        run(&mut robot, 0.5);
+        assert!((robot.servo + 1000).abs() <= 1);
+
+        robot.set_servo(500);
+        run(&mut robot, 0.25);
+        assert_eq!(robot.servo, 500);
+    }
+}
@@ -1,26 +1,294 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum, error::ErrorKind};
 use log::{debug, error, info};
+use roland_protocol::{
+    Calibration, Capabilities, DEFAULT_WATCHDOG_TIMEOUT, MotorRamp, RebootMode, ServoMotion,
+    StopMode, StreamMode, TrackConfig, UltraConfig, UltraFilter,
+};
 use tokio_util::sync::CancellationToken;
 
-use crate::{backend::roland::Roland, server::ws::Server};
+use crate::{
+    backend::{
+        Backend,
+        discovery::DeviceSelector,
+        flash,
+        mock::MockPico,
+        pico::{
+            Pico,
+            sensors::{SensorOptions, TrackOrder},
+        },
+        record::Recorder,
+        replay,
+        roland::Roland,
+        serial::{LinkOptions, LinkState, ReconnectPolicy},
+        sim::{SimPico, map::Map},
+    },
+    server::ws::Server,
+    util::calibration,
+};
 
 mod backend;
 mod server;
 mod util;
 
-async fn main_task(r: Roland) -> anyhow::Result<()> {
-    Server::new(r).run().await
+#[derive(Parser, Debug)]
+#[command(version, about = "Roland robot controller")]
+struct Args {
+    /// serial port of the pico, skips auto-detection
+    #[arg(long, conflicts_with = "serial")]
+    port: Option<String>,
+    /// USB serial number of the pico to use when more than one is connected
+    #[arg(long)]
+    serial: Option<String>,
+    /// re-apply the last commanded actuator state after the serial link recovers, instead of
+    /// resetting everything to neutral
+    #[arg(long)]
+    restore: bool,
+    /// wait for the pico to confirm every command, so lost or refused commands surface as errors,
+    /// at the cost of holding up the control loops for a round trip on every command
+    #[arg(long, conflicts_with_all = ["mock", "sim"])]
+    reliable: bool,
+    /// run without hardware, against an in-process mock pico driven from stdin
+    #[arg(long, conflicts_with_all = ["port", "serial"])]
+    mock: bool,
+    /// run without hardware, against a simulated robot driving around on this map
+    #[arg(long, value_name = "MAP", conflicts_with_all = ["port", "serial", "mock"])]
+    sim: Option<PathBuf>,
+    /// record all serial traffic into this file
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["mock", "sim"])]
+    record: Option<PathBuf>,
+    /// run without hardware, playing back the sensor data of a recording
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["port", "serial", "mock", "sim", "record"]
+    )]
+    replay: Option<PathBuf>,
+    /// playback speed multiplier for --replay
+    #[arg(long, default_value_t = 1., requires = "replay")]
+    speed: f64,
+    /// stop the motors if the pico doesn't hear from us for this long (ms), 0 disables it
+    #[arg(long, default_value_t = DEFAULT_WATCHDOG_TIMEOUT)]
+    watchdog: u16,
+    /// time it takes the motors to speed up from standing still to full speed (ms), 0 is instant
+    #[arg(long, default_value_t = MotorRamp::DEFAULT.accel)]
+    motor_accel: u16,
+    /// time it takes the motors to slow down from full speed to standing still (ms), 0 is instant
+    #[arg(long, default_value_t = MotorRamp::DEFAULT.decel)]
+    motor_decel: u16,
+    /// brake the motors when they're stopped, instead of letting them spin freely
+    #[arg(long)]
+    brake: bool,
+    /// how the ultra sensor's measurements are filtered: none, median:<samples> or ema:<percent>,
+    /// where the percent is the weight of a new measurement
+    #[arg(long, default_value = "median:5", value_parser = parse_ultra_filter)]
+    ultra_filter: UltraFilter,
+    /// time between ultra sensor measurements (ms)
+    #[arg(
+        long,
+        default_value_t = UltraConfig::DEFAULT.period,
+        value_parser = clap::value_parser!(u16).range(UltraConfig::MIN_PERIOD as i64..)
+    )]
+    ultra_period: u16,
+    /// how long a track sensor has to stay at a new level before it counts (µs), either one value
+    /// for all of them, or one for each of L1, L2, R1 and R2
+    #[arg(long, default_value = "1000", value_parser = parse_track_debounce)]
+    track_debounce: [u16; 4],
+    /// time between snapshots of every track sensor, which correct missed changes (ms), 0 disables
+    /// them
+    #[arg(long, default_value_t = TrackConfig::DEFAULT.snapshot_period)]
+    track_snapshot_period: u16,
+    /// the track sensors from left to right
+    #[arg(long, default_value = "l2,l1,r1,r2")]
+    track_order: TrackOrder,
+    /// what the ultra sensor sends while it's used: continuous, on-change, off, or the latest
+    /// reading every this many ms
+    #[arg(long, default_value = "continuous", value_parser = parse_stream_mode)]
+    ultra_stream: StreamMode,
+    /// what the track sensors send while they're used: continuous (every change), off, or a
+    /// snapshot every this many ms
+    #[arg(long, default_value = "continuous", value_parser = parse_stream_mode)]
+    track_stream: StreamMode,
+    /// instead of starting the server, run a behaviour in the simulator as fast as possible and
+    /// report how well it did
+    #[arg(long, requires = "sim")]
+    score: Option<Behaviour>,
+    /// simulated time to score the behaviour for (s)
+    #[arg(long, default_value_t = 60., requires = "score")]
+    duration: f64,
+    /// print the firmware version, latency and actuator state of the pico, then exit
+    #[arg(long, conflicts_with_all = ["mock", "sim", "replay"])]
+    info: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() {
-    simple_logger::init_with_level(log::Level::Debug).unwrap();
+/// one-off actions on a connected pico, instead of starting the server
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// show or change the pico's calibration (servo range, PWM frequencies, polarities)
+    Calibration {
+        /// `key=value` pairs to change, run without any to see the keys
+        values: Vec<String>,
+        /// start from the defaults instead of the current calibration
+        #[arg(long)]
+        reset: bool,
+        /// store the result in flash, so the pico boots with it
+        #[arg(long)]
+        save: bool,
+    },
+    /// reboot the pico and wait for the firmware to come back
+    Reboot {
+        /// reboot into the USB bootloader instead, as if BOOTSEL was held
+        #[arg(long)]
+        bootloader: bool,
+    },
+    /// write a UF2 firmware image to the pico through its USB bootloader, and check that it boots
+    Flash {
+        image: PathBuf,
+        /// where the bootloader's drive gets mounted, if it isn't found automatically
+        #[arg(long)]
+        drive: Option<PathBuf>,
+    },
+    /// move, sweep or detach the servo, then hold it like that until ^C
+    Servo {
+        /// angle to move to, in degrees (-90 to 90)
+        #[arg(
+            allow_negative_numbers = true,
+            required_unless_present_any = ["sweep", "detach"]
+        )]
+        angle: Option<f32>,
+        /// take this long to get there (ms)
+        #[arg(long, conflicts_with = "speed")]
+        duration: Option<u16>,
+        /// move at this speed (degrees per second)
+        #[arg(long)]
+        speed: Option<u16>,
+        /// swing between two angles instead
+        #[arg(
+            long,
+            num_args = 2,
+            value_names = ["FROM", "TO"],
+            allow_negative_numbers = true,
+            conflicts_with_all = ["angle", "duration", "speed"]
+        )]
+        sweep: Option<Vec<f32>>,
+        /// time of a full swing there and back (ms)
+        #[arg(long, default_value_t = 2000, requires = "sweep")]
+        period: u64,
+        /// stop driving the servo, so it can be turned by hand
+        #[arg(long, conflicts_with_all = ["angle", "duration", "speed", "sweep"])]
+        detach: bool,
+    },
+}
 
-    let token = CancellationToken::new();
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Behaviour {
+    FollowLine,
+    KeepDistance,
+}
 
-    let mut r = Roland::init(token.clone())
-        .await
-        .expect("Failed to init backend");
+impl Args {
+    fn device(&self) -> DeviceSelector {
+        match (&self.port, &self.serial) {
+            (Some(path), _) => DeviceSelector::Path(path.clone()),
+            (None, Some(serial)) => DeviceSelector::Serial(serial.clone()),
+            (None, None) => DeviceSelector::Auto,
+        }
+    }
 
+    fn reconnect_policy(&self) -> ReconnectPolicy {
+        if self.restore {
+            ReconnectPolicy::Restore
+        } else {
+            ReconnectPolicy::Reset
+        }
+    }
+
+    fn ultra_config(&self) -> UltraConfig {
+        UltraConfig {
+            filter: self.ultra_filter,
+            period: self.ultra_period,
+        }
+    }
+
+    fn track_config(&self) -> TrackConfig {
+        TrackConfig {
+            debounce: self.track_debounce,
+            snapshot_period: self.track_snapshot_period,
+        }
+    }
+
+    fn sensor_options(&self) -> SensorOptions {
+        SensorOptions {
+            track_order: self.track_order,
+            ultra_stream: self.ultra_stream,
+            track_stream: self.track_stream,
+        }
+    }
+
+    fn stop_mode(&self) -> StopMode {
+        if self.brake {
+            StopMode::Brake
+        } else {
+            StopMode::Coast
+        }
+    }
+}
+
+fn parse_ultra_filter(s: &str) -> Result<UltraFilter, String> {
+    let filter = match s.split_once(':') {
+        None if s == "none" => UltraFilter::None,
+        Some(("median", n)) => UltraFilter::Median(n.parse().map_err(|e| format!("{}", e))?),
+        Some(("ema", weight)) => UltraFilter::Ema(weight.parse().map_err(|e| format!("{}", e))?),
+        _ => return Err("expected none, median:<samples> or ema:<percent>".to_string()),
+    };
+    UltraConfig {
+        filter,
+        ..UltraConfig::DEFAULT
+    }
+    .validate()
+    .map_err(|_| {
+        format!(
+            "the median takes 1 to {} samples, the percent is 1 to 100",
+            UltraConfig::MAX_WINDOW
+        )
+    })?;
+    Ok(filter)
+}
+
+fn parse_track_debounce(s: &str) -> Result<[u16; 4], String> {
+    let values = s
+        .split(',')
+        .map(|us| us.trim().parse().map_err(|e| format!("{}", e)))
+        .collect::<Result<Vec<u16>, _>>()?;
+    match values[..] {
+        [us] => Ok([us; 4]),
+        [l1, l2, r1, r2] => Ok([l1, l2, r1, r2]),
+        _ => Err("expected one value, or four separated by commas".to_string()),
+    }
+}
+
+fn parse_stream_mode(s: &str) -> Result<StreamMode, String> {
+    match s {
+        "continuous" => Ok(StreamMode::Continuous),
+        "on-change" => Ok(StreamMode::OnChange),
+        "off" => Ok(StreamMode::Off),
+        ms => match ms.parse() {
+            Ok(0) => Err("the interval has to be more than 0ms".to_string()),
+            Ok(ms) => Ok(StreamMode::Interval(ms)),
+            Err(_) => Err("expected continuous, on-change, off or an interval in ms".to_string()),
+        },
+    }
+}
+
+async fn main_task<B: Backend>(r: Roland<B>) -> anyhow::Result<()> {
+    Server::new(r).run().await
+}
+
+/// run the server until shutdown
+async fn run<B: Backend>(mut r: Roland<B>, token: CancellationToken) {
     {
         let mut r = r.clone();
         tokio::spawn(async move {
@@ -41,3 +309,282 @@ async fn main() {
         _ = token.cancelled() => {}
     }
 }
+
+/// run `behaviour` on the simulator for `duration` of simulated time and log the results
+async fn score(sim: SimPico, behaviour: Behaviour, duration: Duration) {
+    let mut r = Roland::new(sim.clone());
+
+    // same parameters as the ones the websocket server starts them with
+    let ret = tokio::select! {
+        ret = async {
+            match behaviour {
+                Behaviour::FollowLine => r.follow_line(0.7).await,
+                Behaviour::KeepDistance => r.keep_distance(40).await,
+            }
+        } => ret,
+        _ = tokio::time::sleep(duration) => Ok(()),
+    };
+    if let Err(e) = ret {
+        error!("[Sim] {:?} stopped early: {}", behaviour, e);
+    }
+
+    let stats = sim.stats();
+    info!(
+        "[Sim] {:?}: {:.1}s simulated, {:.1}cm travelled",
+        behaviour, stats.time, stats.distance
+    );
+    match behaviour {
+        Behaviour::FollowLine => info!(
+            "[Sim] on the line {:.1}% of the time",
+            stats.on_line / stats.time * 100.
+        ),
+        Behaviour::KeepDistance => match stats.range_error(40.) {
+            Some(e) => info!("[Sim] distance RMS error {:.2}cm", e),
+            None => info!("[Sim] no wall was ever in range"),
+        },
+    }
+
+    let _ = r.reset().await;
+}
+
+/// query the pico and log everything it tells about itself
+async fn print_info(pico: &Pico) -> anyhow::Result<()> {
+    if let Some(firmware) = pico.firmware() {
+        info!(
+            "Protocol v{}, capabilities [{}]",
+            firmware.protocol,
+            firmware.capabilities.names().collect::<Vec<_>>().join(", ")
+        );
+    }
+    info!("Firmware {}", pico.version().await?);
+    info!("Round trip time {:?}", pico.ping().await?);
+    info!("Actuators {:?}", pico.actuator_state().await?);
+    if pico
+        .firmware()
+        .is_some_and(|f| f.capabilities.contains(Capabilities::STATS))
+    {
+        info!("Link {}", pico.stats().await?);
+    }
+    Ok(())
+}
+
+/// apply `values` on top of the current calibration (or the defaults), and log the result
+async fn calibrate(pico: &Pico, values: &[String], reset: bool, save: bool) -> anyhow::Result<()> {
+    let mut c = if reset {
+        Calibration::DEFAULT
+    } else {
+        pico.calibration().await?
+    };
+    for value in values {
+        calibration::set(&mut c, value)?;
+    }
+
+    if reset || !values.is_empty() {
+        pico.set_calibration(c).await?;
+    }
+    if save {
+        pico.save_calibration().await?;
+        info!("Calibration saved");
+    }
+
+    for (key, value) in calibration::entries(&c) {
+        info!("{}={}", key, value);
+    }
+    Ok(())
+}
+
+/// reboot the pico, and wait for it to come back unless it's going into the bootloader
+async fn reboot(pico: &Pico, bootloader: bool) -> anyhow::Result<()> {
+    if bootloader {
+        pico.reboot(RebootMode::Bootloader).await?;
+        info!("Pico is in its bootloader, flash it with the flash subcommand");
+        return Ok(());
+    }
+
+    let mut link_rx = pico.subscribe_link();
+    pico.reboot(RebootMode::Application).await?;
+    info!("Pico is rebooting");
+    tokio::time::timeout(Duration::from_secs(15), async {
+        link_rx.wait_for(|l| *l != LinkState::Connected).await?;
+        link_rx.wait_for(|l| *l == LinkState::Connected).await?;
+        anyhow::Ok(())
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("The pico didn't come back after rebooting"))??;
+    info!("Firmware {}", pico.version().await?);
+    Ok(())
+}
+
+/// carry out a servo subcommand, then keep the link up until ^C so the watchdog doesn't reset the
+/// servo
+async fn servo(pico: &Pico, command: &Command) -> anyhow::Result<()> {
+    let Command::Servo {
+        angle,
+        duration,
+        speed,
+        sweep,
+        period,
+        detach,
+    } = command
+    else {
+        unreachable!();
+    };
+
+    if *detach {
+        pico.detach_servo().await?;
+        info!("Servo detached");
+    } else if let Some(sweep) = sweep {
+        pico.sweep_servo(sweep[0], sweep[1], Duration::from_millis(*period))
+            .await?;
+        info!("Servo sweeping between {}° and {}°", sweep[0], sweep[1]);
+    } else if let Some(angle) = angle {
+        let motion = match (duration, speed) {
+            (Some(ms), _) => ServoMotion::Duration(*ms),
+            (None, Some(speed)) => ServoMotion::Speed(*speed),
+            (None, None) => ServoMotion::Instant,
+        };
+        pico.move_servo(*angle, motion).await?;
+        info!("Servo at {}°", angle);
+    }
+
+    info!("Holding, ^C to exit");
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+async fn async_main(args: Args) {
+    let token = CancellationToken::new();
+
+    if let Some(path) = &args.sim {
+        let map = Map::load(path).expect("Failed to load map");
+        let sim = SimPico::new(map, token.clone());
+        return match args.score {
+            Some(behaviour) => score(sim, behaviour, Duration::from_secs_f64(args.duration)).await,
+            None => run(Roland::new(sim), token).await,
+        };
+    }
+
+    if let Some(path) = &args.replay {
+        let pico = replay::init(token.clone(), path, args.speed, args.sensor_options())
+            .await
+            .expect("Failed to load recording");
+        return run(Roland::new(pico), token).await;
+    }
+
+    if args.mock {
+        let mock = MockPico::new(token.clone());
+        tokio::spawn(mock.clone().console());
+        return run(Roland::new(mock), token).await;
+    }
+
+    let recorder = args
+        .record
+        .as_ref()
+        .map(|path| Recorder::create(path).expect("Failed to start recording"));
+
+    let options = LinkOptions {
+        device: args.device(),
+        policy: args.reconnect_policy(),
+        recorder,
+        watchdog: args.watchdog,
+        motor_ramp: MotorRamp {
+            accel: args.motor_accel,
+            decel: args.motor_decel,
+        },
+        stop_mode: args.stop_mode(),
+        ultra: args.ultra_config(),
+        track: args.track_config(),
+        sensors: args.sensor_options(),
+    };
+
+    // the pico might not be running our firmware at all
+    if let Some(Command::Flash { image, drive }) = &args.command {
+        match flash::flash(image, drive.as_deref(), &options).await {
+            Ok(version) => info!("Flashed, the pico is running firmware {}", version),
+            Err(e) => error!("Flashing failed: {:#}", e),
+        }
+        return;
+    }
+
+    let mut r = Roland::init(token.clone(), options)
+        .await
+        .expect("Failed to init backend");
+
+    if args.info {
+        if let Err(e) = print_info(&r.pico).await {
+            error!("Couldn't query the pico: {}", e);
+        }
+        return;
+    }
+
+    match &args.command {
+        Some(Command::Calibration {
+            values,
+            reset,
+            save,
+        }) => {
+            if let Err(e) = calibrate(&r.pico, values, *reset, *save).await {
+                error!("Calibration failed: {}", e);
+            }
+            return;
+        }
+        Some(Command::Reboot { bootloader }) => {
+            if let Err(e) = reboot(&r.pico, *bootloader).await {
+                error!("Reboot failed: {}", e);
+            }
+            return;
+        }
+        Some(command @ Command::Servo { .. }) => {
+            if let Err(e) = servo(&r.pico, command).await {
+                error!("Servo command failed: {}", e);
+            }
+            return;
+        }
+        Some(Command::Flash { .. }) | None => {}
+    }
+
+    r.pico.set_reliable(args.reliable);
+
+    run(r, token).await
+}
+
+fn main() {
+    let args = Args::parse();
+    if args.command.is_some() && (args.mock || args.sim.is_some() || args.replay.is_some()) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "subcommands need a real pico, they can't be used with --mock, --sim or --replay",
+            )
+            .exit();
+    }
+    if args.track_config().validate().is_err() {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "--track-snapshot-period has to be 0 or at least {}ms",
+                    TrackConfig::MIN_SNAPSHOT_PERIOD
+                ),
+            )
+            .exit();
+    }
+
+    simple_logger::init_with_level(log::Level::Debug).unwrap();
+
+    // when scoring, time only advances when every task is idle, so the simulation runs as fast as
+    // the cpu allows
+    let rt = if args.score.is_some() {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+    };
+
+    rt.expect("Failed to start runtime")
+        .block_on(async_main(args))
+}
@@ -1,18 +1,35 @@
+use roland_protocol::{Fault, FaultKind, LedEffect, UltraError};
 use serde::{Deserialize, Serialize};
 
+use crate::backend::{
+    pico::sensors::UltraData,
+    serial::{FirmwareInfo, LinkState},
+};
+
 /// This is the message a client can send to control Roland
 #[derive(Debug, Deserialize)]
 pub enum ClientMessage {
-    /// frequency (Hz)
+    /// frequency (Hz), this also stops the melody being played
     Buzzer(u16),
-    /// RGB color (0 to 255)
+    /// play an RTTTL melody on the buzzer
+    Melody(String),
+    /// play one of the built-in jingles (startup, connected, disconnected, done, warning, error)
+    Jingle(String),
+    /// RGB color (0 to 255), this also stops the running LED effect
     LED((u8, u8, u8)),
-    /// rotation in degrees (0 is the midpoint, -90 to 90)
-    Servo(i8),
+    /// run an effect on the LEDs
+    /// eg. `{"LedEffect": {"Blink": {"color": [255, 0, 0], "period": 500}}}`
+    LedEffect(LedEffect),
+    /// stop the running LED effect, turning the LEDs off
+    StopLedEffect,
+    /// rotation in degrees (0 is the midpoint, -90 to 90, in steps of 0.01)
+    Servo(f32),
     /// Motor duty cycle (-1 to 1)
     Motor((f32, f32)),
     /// IE manual-control, follow-line, keep-distance
     ControlState(String),
+    /// stop the motors right away and switch to manual control
+    EmergencyStop,
 }
 
 /// This is the message Roland can send to a client
@@ -25,10 +42,84 @@ pub enum ServerMessage {
     },
     Ultra {
         #[serde(rename = "Ultra")]
-        ultra: Option<u16>,
+        ultra: Ultra,
     },
     Track {
         #[serde(rename = "Track")]
         track: [bool; 4],
     },
+    Link {
+        #[serde(rename = "Link")]
+        link: LinkState,
+    },
+    /// `None` if there's no firmware, or the link is down
+    Firmware {
+        #[serde(rename = "Firmware")]
+        firmware: Option<Firmware>,
+    },
+    /// the last crash the firmware reported, only sent if there was one
+    Fault {
+        #[serde(rename = "Fault")]
+        fault: FirmwareFault,
+    },
+}
+
+/// an ultra sensor reading, distances are in cm
+#[derive(Serialize, Debug)]
+pub struct Ultra {
+    /// the filtered distance, `None` if the sensor isn't measuring anything reliably
+    pub distance: Option<f32>,
+    /// the last measurement on its own, `None` if it failed or the firmware doesn't report it
+    pub raw: Option<f32>,
+    /// why the last measurement failed
+    pub error: Option<UltraError>,
+}
+
+impl From<UltraData> for Ultra {
+    fn from(data: UltraData) -> Self {
+        Self {
+            distance: data.distance,
+            raw: data.raw.and_then(Result::ok),
+            error: data.raw.and_then(Result::err),
+        }
+    }
+}
+
+/// what the connected firmware supports
+#[derive(Serialize, Debug)]
+pub struct Firmware {
+    pub protocol: u16,
+    /// names of the capabilities in use
+    pub capabilities: Vec<&'static str>,
+}
+
+impl From<FirmwareInfo> for Firmware {
+    fn from(info: FirmwareInfo) -> Self {
+        Self {
+            protocol: info.protocol,
+            capabilities: info.capabilities.names().collect(),
+        }
+    }
+}
+
+/// a crash of the firmware, reported after it rebooted
+#[derive(Serialize, Debug)]
+pub struct FirmwareFault {
+    pub kind: FaultKind,
+    pub message: String,
+    /// `file:line:column` of a panic
+    pub location: Option<String>,
+    /// how long the firmware had been running (s)
+    pub uptime: f32,
+}
+
+impl From<Fault> for FirmwareFault {
+    fn from(fault: Fault) -> Self {
+        Self {
+            kind: fault.kind,
+            message: fault.message.to_string(),
+            location: fault.location.map(|l| l.to_string()),
+            uptime: fault.uptime as f32 / 1e6,
+        }
+    }
 }
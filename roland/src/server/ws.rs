@@ -10,8 +10,12 @@ use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
 use tokio_tungstenite::{WebSocketStream, accept_async};
 use tokio_util::sync::CancellationToken;
 
-use crate::backend::roland::Roland;
+use crate::backend::{Backend, roland::Roland};
 use crate::server::message::{ClientMessage, ServerMessage};
+use crate::util::rtttl;
+
+/// how loud melodies from clients are played
+const MELODY_VOLUME: u8 = u8::MAX;
 
 #[derive(Deserialize, Debug, PartialEq)]
 enum ControlState {
@@ -33,14 +37,14 @@ impl FromStr for ControlState {
     }
 }
 
-pub struct Server {
-    roland: Roland,
+pub struct Server<B: Backend> {
+    roland: Roland<B>,
     state: ControlState,
     auto_cancel: Option<CancellationToken>,
 }
 
-impl Server {
-    pub fn new(roland: Roland) -> Self {
+impl<B: Backend> Server<B> {
+    pub fn new(roland: Roland<B>) -> Self {
         Self {
             roland,
             state: ControlState::ManualControl,
@@ -85,7 +89,7 @@ impl Server {
 
     async fn write_task(
         mut write: SplitSink<WebSocketStream<TcpStream>, WsMessage>,
-        r: Roland,
+        r: Roland<B>,
     ) -> anyhow::Result<()> {
         let (write_tx, mut write_rx) = mpsc::channel::<WsMessage>(32);
 
@@ -102,7 +106,7 @@ impl Server {
             async move {
                 let mut ultra_rx = r.pico.subscribe_ultra();
                 loop {
-                    let ultra = *ultra_rx.borrow_and_update();
+                    let ultra = ultra_rx.borrow_and_update().value.into();
                     if write_tx
                         .send(WsMessage::Text(
                             serde_json::to_string(&ServerMessage::Ultra { ultra })
@@ -120,10 +124,58 @@ impl Server {
             }
         };
 
+        let link_task = {
+            let r = r.clone();
+            let write_tx = write_tx.clone();
+            async move {
+                let mut link_rx = r.pico.subscribe_link();
+                'link: loop {
+                    let link = *link_rx.borrow_and_update();
+                    // the firmware is only known after connecting, and might be different after
+                    // reconnecting
+                    let firmware = r.pico.firmware().map(Into::into);
+                    let msgs = [
+                        ServerMessage::Link { link },
+                        ServerMessage::Firmware { firmware },
+                    ];
+                    for msg in msgs {
+                        let msg = WsMessage::Text(serde_json::to_string(&msg).unwrap().into());
+                        if write_tx.send(msg).await.is_err() {
+                            break 'link;
+                        }
+                    }
+                    link_rx.changed().await?;
+                }
+                Ok::<(), anyhow::Error>(())
+            }
+        };
+
+        let fault_task = {
+            let r = r.clone();
+            let write_tx = write_tx.clone();
+            async move {
+                let mut fault_rx = r.pico.subscribe_fault();
+                loop {
+                    let fault = *fault_rx.borrow_and_update();
+                    if let Some(fault) = fault {
+                        let msg = ServerMessage::Fault {
+                            fault: fault.into(),
+                        };
+                        let msg = WsMessage::Text(serde_json::to_string(&msg).unwrap().into());
+                        if write_tx.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    fault_rx.changed().await?;
+                }
+                Ok::<(), anyhow::Error>(())
+            }
+        };
+
         let track_task = async move {
             let mut track_rx = r.pico.subscribe_track();
             loop {
-                let track = *track_rx.borrow_and_update();
+                let track = track_rx.borrow_and_update().value;
                 if write_tx
                     .send(WsMessage::Text(
                         serde_json::to_string(&ServerMessage::Track { track })
@@ -145,6 +197,8 @@ impl Server {
             cur_ret = write_rx_task => { ret = cur_ret; },
             cur_ret = ultra_task => { ret = cur_ret; },
             cur_ret = track_task => { ret = cur_ret; },
+            cur_ret = link_task => { ret = cur_ret; },
+            cur_ret = fault_task => { ret = cur_ret; },
         };
 
         ret
@@ -180,9 +234,20 @@ impl Server {
             ClientMessage::Buzzer(freq) => {
                 self.roland.pico.set_buzzer(freq).await?;
             }
+            ClientMessage::Melody(rtttl) => self.play(&rtttl).await,
+            ClientMessage::Jingle(name) => match rtttl::jingle(&name) {
+                Some(jingle) => self.play(jingle).await,
+                None => debug!("Unknown jingle: {}", name),
+            },
             ClientMessage::LED((r, g, b)) => {
                 self.roland.pico.set_led(r, g, b).await?;
             }
+            ClientMessage::LedEffect(effect) => {
+                self.roland.pico.set_led_effect(effect).await?;
+            }
+            ClientMessage::StopLedEffect => {
+                self.roland.pico.stop_led_effect().await?;
+            }
             ClientMessage::Servo(deg) => {
                 self.roland.pico.set_servo(deg).await?;
             }
@@ -191,6 +256,15 @@ impl Server {
                 let r = (r * 0xffff as f32).round() as i32;
                 self.roland.pico.set_motor(l, r).await?;
             }
+            ClientMessage::EmergencyStop => {
+                info!("Emergency stop");
+                // cancelling doesn't wait for anything, and keeps the auto task from sending
+                // another command after the stop
+                self.stop_auto();
+                self.roland.pico.emergency_stop().await?;
+                // no soft reset, its ramped stop would only come after the emergency stop
+                self.state = ControlState::ManualControl;
+            }
             ClientMessage::ControlState(state) => match ControlState::from_str(&state) {
                 Ok(state) => {
                     info!("Swiching state to: {:?}", state);
@@ -205,18 +279,33 @@ impl Server {
         Ok(())
     }
 
+    /// play an RTTTL melody, a bad melody or a firmware without a buzzer queue is only logged
+    async fn play(&mut self, rtttl: &str) {
+        let ret = match rtttl::parse(rtttl, MELODY_VOLUME) {
+            Ok(notes) => self.roland.pico.play_melody(&notes).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = ret {
+            error!("Couldn't play melody: {:#}", e);
+        }
+    }
+
+    /// cancel the running auto task, if there's one
+    fn stop_auto(&mut self) {
+        if let Some(cancel) = self.auto_cancel.take() {
+            cancel.cancel();
+        }
+    }
+
     /// FIXME: a bunch of code duplication here, I don't like this
     async fn change_state(&mut self, new_state: ControlState) -> anyhow::Result<()> {
         if self.state == new_state {
             return Ok(());
         }
         self.state = new_state;
-        if let Some(cancel) = self.auto_cancel.clone() {
-            cancel.cancel();
-        }
+        self.stop_auto();
         match self.state {
             ControlState::ManualControl => {
-                self.auto_cancel = None;
                 self.roland.pico.soft_reset().await?;
             }
             ControlState::FollowLine => {
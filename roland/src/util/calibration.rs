@@ -0,0 +1,130 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::anyhow;
+use roland_protocol::Calibration;
+
+/// every field of a [`Calibration`] as `key=value` pairs, the same way [`set`] takes them
+pub fn entries(c: &Calibration) -> [(&'static str, String); 9] {
+    [
+        ("servo-min", c.servo_min.to_string()),
+        ("servo-mid", c.servo_mid.to_string()),
+        ("servo-max", c.servo_max.to_string()),
+        ("servo-reversed", c.servo_reversed.to_string()),
+        ("led-freq", c.led_freq.to_string()),
+        ("led-inverted", c.led_inverted.to_string()),
+        ("motor-freq", c.motor_freq.to_string()),
+        ("motor-left-reversed", c.motor_left_reversed.to_string()),
+        ("motor-right-reversed", c.motor_right_reversed.to_string()),
+    ]
+}
+
+/// change a single field from a `key=value` string
+pub fn set(c: &mut Calibration, assignment: &str) -> anyhow::Result<()> {
+    let (key, value) = assignment
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected key=value, got {}", assignment))?;
+
+    match key {
+        "servo-min" => c.servo_min = parse(key, value)?,
+        "servo-mid" => c.servo_mid = parse(key, value)?,
+        "servo-max" => c.servo_max = parse(key, value)?,
+        "servo-reversed" => c.servo_reversed = parse(key, value)?,
+        "led-freq" => c.led_freq = parse(key, value)?,
+        "led-inverted" => c.led_inverted = parse(key, value)?,
+        "motor-freq" => c.motor_freq = parse(key, value)?,
+        "motor-left-reversed" => c.motor_left_reversed = parse(key, value)?,
+        "motor-right-reversed" => c.motor_right_reversed = parse(key, value)?,
+        _ => {
+            let keys: Vec<_> = entries(c).iter().map(|(k, _)| *k).collect();
+            return Err(anyhow!(
+                "Unknown calibration key {}, expected one of: {}",
+                key,
+                keys.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> anyhow::Result<T>
+where
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e| anyhow!("Invalid value {:?} for {}: {}", value, key, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// every field differs from the default and from the others
+    const CUSTOM: Calibration = Calibration {
+        servo_min: 1000,
+        servo_mid: 5000,
+        servo_max: 9000,
+        servo_reversed: false,
+        led_freq: 500,
+        led_inverted: true,
+        motor_freq: 20000,
+        motor_left_reversed: true,
+        motor_right_reversed: true,
+    };
+
+    #[test]
+    fn round_trip() {
+        let mut c = Calibration::DEFAULT;
+        for (key, value) in entries(&CUSTOM) {
+            set(&mut c, &format!("{}={}", key, value)).unwrap();
+        }
+        assert_eq!(c, CUSTOM);
+    }
+
+    #[test]
+    fn keys_change_their_own_field() {
+        let custom = entries(&CUSTOM);
+        for (i, (key, value)) in custom.iter().enumerate() {
+            let mut c = Calibration::DEFAULT;
+            set(&mut c, &format!("{}={}", key, value)).unwrap();
+            for (j, (entry, default)) in entries(&c)
+                .iter()
+                .zip(entries(&Calibration::DEFAULT))
+                .enumerate()
+            {
+                if i == j {
+                    assert_eq!(entry, &custom[i], "{}", key);
+                } else {
+                    assert_eq!(entry, &default, "{} changed {}", key, entry.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let mut c = Calibration::DEFAULT;
+        for assignment in [
+            "servo-min",
+            "servo-min 100",
+            "servo-minimum=100",
+            "=100",
+            "servo-min=-1",
+            "servo-max=65536",
+            "led-freq=",
+            "motor-freq=2k",
+            "servo-reversed=yes",
+            "led-inverted=1",
+        ] {
+            assert!(set(&mut c, assignment).is_err(), "{} accepted", assignment);
+        }
+        assert_eq!(c, Calibration::DEFAULT);
+
+        let e = set(&mut c, "servo=1").unwrap_err().to_string();
+        assert!(
+            e.contains("servo-min") && e.contains("motor-right-reversed"),
+            "{}",
+            e
+        );
+    }
+}
@@ -1,2 +1,4 @@
+pub mod calibration;
 pub mod color;
 pub mod pid;
+pub mod rtttl;
@@ -1,6 +1,5 @@
-use std::time::Instant;
-
 use log::info;
+use tokio::time::Instant;
 
 pub struct PID {
     pub kp: f64,
@@ -32,11 +31,11 @@ impl PID {
         }
     }
 
-    pub fn step(&mut self, pv: f64) -> f64 {
-        let now = Instant::now();
-
+    /// `now` is when `pv` was measured
+    pub fn step(&mut self, pv: f64, now: Instant) -> f64 {
         match self.last_t {
-            Some(last_t) => {
+            // readings captured at the same time carry no new information about the rates
+            Some(last_t) if now > last_t => {
                 let dt = (now - last_t).as_secs_f64();
                 self.last_t = Some(now);
 
@@ -52,6 +51,7 @@ impl PID {
 
                 p + i + d
             }
+            Some(_) => self.kp * (self.sp - pv) + self.ki * self.int,
             // in the first step, no time reference point is available, so only proportional can be
             // safely calculated
             None => {
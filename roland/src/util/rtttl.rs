@@ -0,0 +1,280 @@
+//! RTTTL (ring tone text transfer language) melodies
+//!
+//! a melody looks like `name:d=4,o=5,b=120:8c,8e,g,p,2c6`, with the default duration, octave and
+//! tempo in the middle, then the notes: an optional duration (1 is a whole note, 32 a thirty-second
+//! one), the note (`p` is a rest), an optional `#`, an optional octave and an optional `.`, which
+//! makes the note half as long again
+
+use anyhow::{Context, anyhow};
+use roland_protocol::Note;
+
+/// fastest tempo (beats per minute)
+const MAX_TEMPO: u32 = 900;
+
+/// built-in jingles for signalling what's going on, by name
+pub const JINGLES: &[(&str, &str)] = &[
+    ("startup", "startup:d=16,o=6,b=180:c,e,g,8c7"),
+    ("connected", "connected:d=16,o=6,b=200:g,8c7"),
+    ("disconnected", "disconnected:d=16,o=6,b=200:c7,8g"),
+    ("done", "done:d=16,o=6,b=160:e,g,e7,8c7"),
+    ("warning", "warning:d=8,o=6,b=180:a,p,a,p,a"),
+    ("error", "error:d=8,o=5,b=160:a,p,a,p,2f"),
+];
+
+/// the RTTTL string of a built-in jingle
+pub fn jingle(name: &str) -> Option<&'static str> {
+    JINGLES.iter().find(|(n, _)| *n == name).map(|(_, s)| *s)
+}
+
+/// parse an RTTTL melody into the notes the buzzer plays, all at `volume`
+pub fn parse(rtttl: &str, volume: u8) -> anyhow::Result<Vec<Note>> {
+    let mut sections = rtttl.trim().splitn(3, ':');
+    let (Some(_name), Some(defaults), Some(notes)) =
+        (sections.next(), sections.next(), sections.next())
+    else {
+        return Err(anyhow!("Expected name:defaults:notes, got {}", rtttl));
+    };
+
+    let mut duration = 4;
+    let mut octave = 6;
+    let mut bpm = 63;
+    for default in defaults.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let (key, value) = default
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected key=value, got {}", default))?;
+        let value: u32 = value
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid value for {}", key))?;
+        match key.trim() {
+            "d" => duration = check_duration(value)?,
+            "o" => octave = check_octave(value)?,
+            "b" => bpm = check_tempo(value)?,
+            key => return Err(anyhow!("Unknown default {}", key)),
+        }
+    }
+
+    notes
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(|n| {
+            parse_note(n, duration, octave, bpm, volume)
+                .with_context(|| format!("Invalid note {}", n))
+        })
+        .collect()
+}
+
+fn parse_note(
+    note: &str,
+    duration: u32,
+    octave: u32,
+    bpm: u32,
+    volume: u8,
+) -> anyhow::Result<Note> {
+    let note = note.to_ascii_lowercase();
+    let mut rest = note.as_str();
+
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let duration = match &rest[..digits] {
+        "" => duration,
+        d => check_duration(d.parse()?)?,
+    };
+    rest = &rest[digits..];
+
+    // semitones above C
+    let mut semitone = match rest.chars().next() {
+        Some('c') => Some(0),
+        Some('d') => Some(2),
+        Some('e') => Some(4),
+        Some('f') => Some(5),
+        Some('g') => Some(7),
+        Some('a') => Some(9),
+        Some('b' | 'h') => Some(11),
+        Some('p') => None,
+        _ => return Err(anyhow!("Expected a note")),
+    };
+    rest = &rest[1..];
+
+    if let Some(r) = rest.strip_prefix('#') {
+        semitone = semitone.map(|s| s + 1);
+        rest = r;
+    }
+    // the dot can come before or after the octave
+    let mut dotted = false;
+    if let Some(r) = rest.strip_prefix('.') {
+        dotted = true;
+        rest = r;
+    }
+    let octave = match rest.chars().next() {
+        Some(c @ '0'..='9') => {
+            rest = &rest[1..];
+            check_octave(c as u32 - '0' as u32)?
+        }
+        _ => octave,
+    };
+    if let Some(r) = rest.strip_prefix('.') {
+        dotted = true;
+        rest = r;
+    }
+    if !rest.is_empty() {
+        return Err(anyhow!("Unexpected {}", rest));
+    }
+
+    // a whole note is four beats
+    let mut ms = 4 * 60_000 / (bpm * duration);
+    if dotted {
+        ms = ms * 3 / 2;
+    }
+    let duration = ms.try_into().map_err(|_| anyhow!("Too long"))?;
+
+    Ok(match semitone {
+        Some(semitone) => {
+            // A4 is 440Hz
+            let from_a4 = semitone as f64 - 9. + 12. * (octave as f64 - 4.);
+            Note {
+                freq: (440. * 2f64.powf(from_a4 / 12.)).round() as u16,
+                duration,
+                volume,
+            }
+        }
+        None => Note {
+            freq: 0,
+            duration,
+            volume: 0,
+        },
+    })
+}
+
+fn check_duration(duration: u32) -> anyhow::Result<u32> {
+    match duration {
+        1 | 2 | 4 | 8 | 16 | 32 => Ok(duration),
+        d => Err(anyhow!("Invalid duration {}", d)),
+    }
+}
+
+fn check_tempo(bpm: u32) -> anyhow::Result<u32> {
+    match bpm {
+        // the shortest notes are 8ms long at this tempo already
+        1..=MAX_TEMPO => Ok(bpm),
+        b => Err(anyhow!(
+            "Invalid tempo {}, it has to be 1 to {}",
+            b,
+            MAX_TEMPO
+        )),
+    }
+}
+
+fn check_octave(octave: u32) -> anyhow::Result<u32> {
+    match octave {
+        // the buzzer can't go much lower or higher
+        1..=8 => Ok(octave),
+        o => Err(anyhow!("Invalid octave {}", o)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(freq: u16, duration: u16, volume: u8) -> Note {
+        Note {
+            freq,
+            duration,
+            volume,
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        // a whole note is four beats
+        assert_eq!(
+            parse("a:d=4,o=5,b=120:a", 100).unwrap(),
+            [note(880, 500, 100)]
+        );
+        assert_eq!(
+            parse("a:d=2,o=4,b=60:a", 100).unwrap(),
+            [note(440, 2000, 100)]
+        );
+        // d=4, o=6 and b=63 if there are none
+        assert_eq!(parse("a::a", 100).unwrap(), [note(1760, 952, 100)]);
+    }
+
+    #[test]
+    fn notes() {
+        let notes = parse("x:d=4,o=5,b=120:8c6,32e,1g4", 50).unwrap();
+        assert_eq!(
+            notes,
+            [note(1047, 250, 50), note(659, 62, 50), note(392, 2000, 50)]
+        );
+        // h is b in german notation
+        assert_eq!(
+            parse("x:d=4,o=5,b=120:b,h", 50).unwrap(),
+            [note(988, 500, 50); 2]
+        );
+    }
+
+    #[test]
+    fn sharps() {
+        assert_eq!(
+            parse("x:d=4,o=4,b=120:a#,c#5", 50).unwrap(),
+            [note(466, 500, 50), note(554, 500, 50)]
+        );
+    }
+
+    #[test]
+    fn dots() {
+        // the dot can come before or after the octave
+        let dotted = [note(1047, 750, 50)];
+        assert_eq!(parse("x:d=4,o=5,b=120:c.6", 50).unwrap(), dotted);
+        assert_eq!(parse("x:d=4,o=5,b=120:c6.", 50).unwrap(), dotted);
+        assert_eq!(
+            parse("x:d=4,o=5,b=120:8c.", 50).unwrap(),
+            [note(523, 375, 50)]
+        );
+    }
+
+    #[test]
+    fn rests() {
+        assert_eq!(
+            parse("x:d=4,o=5,b=120:p,2p.", 50).unwrap(),
+            [note(0, 500, 0), note(0, 1500, 0)]
+        );
+    }
+
+    #[test]
+    fn jingles() {
+        for (name, rtttl) in JINGLES {
+            let notes = parse(rtttl, 100).unwrap_or_else(|e| panic!("{}: {:?}", name, e));
+            assert!(!notes.is_empty(), "{} has no notes", name);
+            assert_eq!(jingle(name), Some(*rtttl));
+        }
+        assert_eq!(jingle("nope"), None);
+    }
+
+    #[test]
+    fn malformed() {
+        for rtttl in [
+            "",
+            "x",
+            "x:d=4",
+            "x:q=1:c",
+            "x:d:c",
+            "x:d=x:c",
+            "x:d=3:c",
+            "x:o=0:c",
+            "x:o=9:c",
+            "x:b=0:c",
+            "x:b=901:c",
+            "x:b=134217728,d=32:c",
+            "x::3c",
+            "x::x",
+            "x::c9",
+            "x::c#5x",
+        ] {
+            assert!(parse(rtttl, 100).is_err(), "{:?} parsed", rtttl);
+        }
+    }
+}